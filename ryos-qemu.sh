@@ -11,17 +11,21 @@ usage()
   echo "  -e, --exec <BINARY>      Run BINARY in QEMU"
   echo "  -d, --dump               Generate a device tree dump"
   echo "  -h, --hard-drive <FILE>  Use FILE as the virtual hard drive"
+  echo "  -c, --cpu <MODEL>        Emulate MODEL, e.g. rv64,svpbmt=on,svnapot=on"
+  echo "  -a, --aia                Replace the PLIC with an APLIC and IMSICs"
   echo
   exit 1
 }
 
 DUMP=0
+AIA=0
 HARD_DRIVE=hdd.dsk
+CPU_MODEL=rv64
 declare -a QEMU_ARGS
 declare -a POSITIONAL
 
-SHORT=-deh:
-LONG=dump,exec:,hard-drive
+SHORT=-deh:c:a
+LONG=dump,exec:,hard-drive,cpu:,aia
 
 OPTIONS=$(getopt --options ${SHORT} \
                  --longoptions ${LONG} \
@@ -45,6 +49,11 @@ while [[ $# -gt 0 ]]; do
     -h|--hard-drive)
       shift
       HARD_DRIVE="$1";;
+    -c|--cpu)
+      shift
+      CPU_MODEL="$1";;
+    -a|--aia)
+      AIA=1;;
     --)
       end_of_options=1;;
     *)
@@ -70,9 +79,12 @@ if [[ ! -f $HARD_DRIVE ]]; then
 fi
 
 MACH="virt"
+if [[ $AIA -eq 1 ]]; then
+  MACH+=",aia=aplic-imsic"
+fi
 CPUS=4
 MEM="128M"
-QEMU_FLAGS="-machine ${MACH} -smp ${CPUS} -m ${MEM} -nographic -bios none"
+QEMU_FLAGS="-machine ${MACH} -cpu ${CPU_MODEL} -smp ${CPUS} -m ${MEM} -nographic -bios none"
 QEMU_FLAGS+=" -global virtio-mmio.force-legacy=false"
 QEMU_FLAGS+=" -drive if=none,format=raw,file=${HARD_DRIVE},id=x0"
 QEMU_FLAGS+=" -device virtio-blk-device,scsi=off,drive=x0"
@@ -83,6 +95,8 @@ if [[ $DUMP -eq 1 ]]; then
 fi
 
 if [[ -n $EXEC ]]; then
+  # embed the symbol table used to symbolize backtraces
+  "$(dirname "$0")/scripts/ksyms.sh" "$EXEC" || echo "Warning: kernel symbol table not embedded"
   QEMU_FLAGS+=" -kernel $EXEC"
 fi
 
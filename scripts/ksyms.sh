@@ -0,0 +1,97 @@
+#!/bin/bash
+#
+# Post-link step that embeds a symbol table into the .ksyms section of a ryos kernel image,
+# so that backtraces can be symbolized in the kernel (see src/ksyms.rs).
+#
+# The table is little-endian and laid out as follows:
+#
+#   magic  u32  "KSYM"
+#   count  u32  number of entries
+#   strtab u32  offset of the string table from the start of the table
+#   (pad)  u32
+#   count entries sorted by address, each made up of:
+#     addr     u64  symbol address
+#     name_off u32  offset of the (mangled) name in the string table
+#     name_len u32  length of the name
+#   string table
+#
+# The section is reserved with a fixed size in src/asm/ksyms.s so that filling it in doesn't
+# move anything else in the image.
+
+set -e
+
+NM=${NM:-rust-nm}
+OBJCOPY=${OBJCOPY:-rust-objcopy}
+SIZE=${SIZE:-rust-size}
+
+if [[ $# -ne 1 ]]; then
+  echo "Usage: $0 <KERNEL_ELF>"
+  exit 1
+fi
+
+ELF="$1"
+TABLE=$(mktemp)
+trap 'rm -f "$TABLE"' EXIT
+
+CAPACITY=$($SIZE -A "$ELF" | awk '$1 == ".ksyms" { print $2 }')
+if [[ -z $CAPACITY ]]; then
+  echo "$0: $ELF has no .ksyms section" >&2
+  exit 1
+fi
+
+# keep only text symbols, sorted by address, and drop duplicate addresses
+$NM --defined-only "$ELF" \
+  | awk '$2 == "t" || $2 == "T" { print $1, $3 }' \
+  | sort -u -k1,1 \
+  | LC_ALL=C awk '
+    function le(value, bytes,    out, i) {
+      out = ""
+      for (i = 0; i < bytes; i++) {
+        out = out sprintf("%02x", value % 256)
+        value = int(value / 256)
+      }
+      return out
+    }
+    # hex digits of a 64-bit address, which is too big to do arithmetic on exactly
+    function le64(hex,    out, i) {
+      hex = sprintf("%16s", hex)
+      gsub(/ /, "0", hex)
+      out = ""
+      for (i = 15; i >= 1; i -= 2)
+        out = out substr(hex, i, 2)
+      return out
+    }
+    BEGIN {
+      n = 0
+      for (i = 1; i < 256; i++)
+        ord[sprintf("%c", i)] = i
+    }
+    {
+      addr[n] = $1
+      name[n] = $2
+      n++
+    }
+    END {
+      header_size = 16
+      entry_size = 16
+      strtab = header_size + n * entry_size
+      printf "%s%s%s%s", "4b53594d", le(n, 4), le(strtab, 4), le(0, 4)
+      offset = 0
+      for (i = 0; i < n; i++) {
+        printf "%s%s%s", le64(addr[i]), le(offset, 4), le(length(name[i]), 4)
+        offset += length(name[i])
+      }
+      for (i = 0; i < n; i++)
+        for (j = 1; j <= length(name[i]); j++)
+          printf "%02x", ord[substr(name[i], j, 1)]
+    }' \
+  | xxd -r -p > "$TABLE"
+
+USED=$(stat -c %s "$TABLE")
+if [[ $USED -gt $CAPACITY ]]; then
+  echo "$0: symbol table needs $USED bytes but .ksyms only has $CAPACITY" >&2
+  exit 1
+fi
+truncate -s "$CAPACITY" "$TABLE"
+
+$OBJCOPY --update-section .ksyms="$TABLE" "$ELF"
@@ -0,0 +1,192 @@
+/// RISC-V Advanced Interrupt Architecture: APLIC and IMSIC
+/// https://github.com/riscv/riscv-aia
+///
+/// Only MSI delivery is supported, as on the QEMU virt machine with aia=aplic-imsic. Wired
+/// interrupt sources are connected to the APLIC, which forwards each one as an MSI to a single
+/// hart. Every hart has an S-level IMSIC interrupt file of its own, where the identity of an
+/// MSI stays pending until it is claimed through the stopei CSR. Source n is always forwarded
+/// as identity n, so IRQ numbers mean the same as with the PLIC.
+///
+/// There is no M-mode firmware, so the kernel also configures the root (M-level) APLIC domain,
+/// which only delegates every source to the S-level domain.
+use core::arch::asm;
+
+use crate::{
+    fdt::{Fdt, Node},
+    kmem::{phys_to_virt, PAGE_SIZE},
+    mmio::MMIODevice,
+};
+
+// APLIC registers
+const DOMAINCFG: u64 = 0x0000;
+const DOMAINCFG_IE: u32 = 1 << 8; // interrupt enable
+const DOMAINCFG_DM_MSI: u32 = 1 << 2; // delivery mode
+const SOURCECFG1: u64 = 0x0004; // sourcecfg[n] is at SOURCECFG1 + 4 * (n - 1)
+const SOURCECFG_D: u32 = 1 << 10; // delegate to the child domain in the low bits
+const SOURCECFG_SM_LEVEL1: u32 = 6; // active when high
+const MMSIADDRCFGH: u64 = 0x1bc4;
+const SMSIADDRCFG: u64 = 0x1bc8;
+const SMSIADDRCFGH: u64 = 0x1bcc;
+const MSIADDRCFGH_LHXW_SHIFT: u32 = 12; // number of hart index bits
+const MSIADDRCFGH_LHXS_SHIFT: u32 = 20; // log2 of the pages between interrupt files
+const SETIENUM: u64 = 0x1edc;
+const SETIPNUM_LE: u64 = 0x2000;
+const TARGET1: u64 = 0x3004; // target[n] is at TARGET1 + 4 * (n - 1)
+const TARGET_HART_SHIFT: u32 = 18;
+
+// IMSIC registers, accessed indirectly through siselect and sireg
+const EIDELIVERY: u64 = 0x70;
+const EITHRESHOLD: u64 = 0x72;
+const EIE0: u64 = 0xc0; // only the even numbered eie registers exist on RV64
+
+// interrupts-extended cell of interrupt files that raise supervisor external interrupts
+const IRQ_S_EXT: u32 = 9;
+
+pub struct Aia {
+    aplic_m: Option<(u64, u64)>, // physical base and size of the root domain, if visible
+    aplic_s: (u64, u64),
+    num_sources: u32,
+    imsic: (u64, u64), // S-level interrupt files of all harts
+    imsic_stride: u64, // distance between the interrupt files of consecutive harts
+    hart_index_bits: u32,
+    num_ids: u32,
+}
+
+impl Aia {
+    /// Find an S-level IMSIC and the APLIC domain that forwards to it
+    pub fn probe(fdt: &Fdt) -> Option<Aia> {
+        let imsic = fdt
+            .nodes()
+            .find(|node| node.is_compatible("riscv,imsics") && is_supervisor_level(node))?;
+        let aplic_s = fdt.nodes().find(|node| {
+            node.is_compatible("riscv,aplic")
+                && node.property("msi-parent").is_some()
+                && node.property("riscv,children").is_none()
+        })?;
+        let aplic_m = fdt.nodes().find(|node| {
+            node.is_compatible("riscv,aplic") && node.property("riscv,children").is_some()
+        });
+
+        let num_files = imsic.property_u32s("interrupts-extended").count() as u32 / 2;
+        let guest_index_bits = imsic.property_u32("riscv,guest-index-bits").unwrap_or(0);
+        Some(Aia {
+            aplic_m: aplic_m.and_then(|node| node.reg(0)),
+            aplic_s: aplic_s.reg(0)?,
+            num_sources: aplic_s.property_u32("riscv,num-sources")?,
+            imsic: imsic.reg(0)?,
+            imsic_stride: PAGE_SIZE << guest_index_bits,
+            hart_index_bits: imsic
+                .property_u32("riscv,hart-index-bits")
+                .unwrap_or(num_files.next_power_of_two().trailing_zeros()),
+            num_ids: imsic.property_u32("riscv,num-ids")?,
+        })
+    }
+
+    pub fn mmio_regions(&self) -> [Option<(u64, u64)>; 3] {
+        [self.aplic_m, Some(self.aplic_s), Some(self.imsic)]
+    }
+
+    /// Configure the APLIC domains. Every source stays inactive until it is enabled.
+    pub fn init(&self) {
+        let guest_index_bits = (self.imsic_stride / PAGE_SIZE).trailing_zeros();
+        unsafe {
+            if let Some((base, _)) = self.aplic_m {
+                let root = MMIODevice::<u32>::new(phys_to_virt(base));
+                root.reg_w(DOMAINCFG).write(DOMAINCFG_DM_MSI);
+                for irq in 1..=self.num_sources {
+                    root.reg_w(sourcecfg(irq)).write(SOURCECFG_D);
+                }
+                // the hart index width is shared by both privilege levels
+                root.reg_w(MMSIADDRCFGH)
+                    .write(self.hart_index_bits << MSIADDRCFGH_LHXW_SHIFT);
+                let ppn = self.imsic.0 / PAGE_SIZE;
+                root.reg_w(SMSIADDRCFG).write(ppn as u32);
+                root.reg_w(SMSIADDRCFGH)
+                    .write((guest_index_bits << MSIADDRCFGH_LHXS_SHIFT) | (ppn >> 32) as u32);
+            }
+
+            let aplic = self.aplic();
+            aplic.reg_w(DOMAINCFG).write(DOMAINCFG_DM_MSI);
+            for irq in 1..=self.num_sources {
+                aplic.reg_w(sourcecfg(irq)).write(0);
+            }
+            aplic
+                .reg_w(DOMAINCFG)
+                .write(DOMAINCFG_IE | DOMAINCFG_DM_MSI);
+        }
+    }
+
+    /// Accept every interrupt identity in the current hart's interrupt file. Whether a source
+    /// reaches a hart at all is decided by its APLIC target.
+    pub fn init_hart(&self) {
+        unsafe {
+            imsic_write(EIDELIVERY, 1);
+            imsic_write(EITHRESHOLD, 0);
+            for i in 0..=self.num_ids as u64 / 64 {
+                imsic_write(EIE0 + 2 * i, u64::MAX);
+            }
+        }
+    }
+
+    /// Forward source `irq` to hart `hartid`. A source has a single target, so enabling it on
+    /// another hart moves it there.
+    pub fn enable(&self, hartid: usize, irq: u32) {
+        if irq > self.num_sources {
+            // an identity without a wired source, only reachable through MSIs
+            assert!(irq <= self.num_ids);
+            return;
+        }
+        let aplic = self.aplic();
+        unsafe {
+            aplic.reg_w(sourcecfg(irq)).write(SOURCECFG_SM_LEVEL1);
+            aplic
+                .reg_w(TARGET1 + 4 * (irq as u64 - 1))
+                .write(((hartid as u32) << TARGET_HART_SHIFT) | irq);
+            aplic.reg_w(SETIENUM).write(irq);
+        }
+    }
+
+    /// Claim the highest priority (lowest numbered) pending identity of the current hart
+    pub fn claim(&self) -> u32 {
+        let topei: u64;
+        unsafe {
+            // reading and writing stopei in one instruction claims what was read
+            asm!("csrrw {}, 0x15c, zero", out(reg) topei);
+        }
+        (topei >> 16) as u32
+    }
+
+    pub fn complete(&self, irq: u32) {
+        if irq == 0 || irq > self.num_sources {
+            return;
+        }
+        // In MSI mode, a level-triggered source only sends another MSI once its input goes
+        // low and high again. If it is still high, make it pending again so it isn't lost.
+        unsafe { self.aplic().reg_w(SETIPNUM_LE).write(irq) }
+    }
+
+    /// Physical address that devices write an identity to in order to raise it on `hartid`
+    pub fn msi_address(&self, hartid: usize) -> u64 {
+        self.imsic.0 + hartid as u64 * self.imsic_stride
+    }
+
+    fn aplic(&self) -> MMIODevice<u32> {
+        MMIODevice::new(phys_to_virt(self.aplic_s.0))
+    }
+}
+
+const fn sourcecfg(irq: u32) -> u64 {
+    SOURCECFG1 + 4 * (irq as u64 - 1)
+}
+
+/// Whether an IMSIC node describes the supervisor-level interrupt files
+fn is_supervisor_level(node: &Node) -> bool {
+    // interrupts-extended is a list of (cpu interrupt controller phandle, interrupt) pairs
+    let mut irqs = node.property_u32s("interrupts-extended").skip(1).step_by(2);
+    irqs.next() == Some(IRQ_S_EXT)
+}
+
+/// Write a register of the current hart's S-level interrupt file
+unsafe fn imsic_write(reg: u64, value: u64) {
+    asm!("csrw 0x150, {}", "csrw 0x151, {}", in(reg) reg, in(reg) value);
+}
@@ -4,5 +4,10 @@ use core::arch::global_asm;
 // everything together without any extra toolchain steps
 
 global_asm!(include_str!("asm/boot.s"));
+global_asm!(include_str!("asm/fp.s"));
+global_asm!(include_str!("asm/ksyms.s"));
 global_asm!(include_str!("asm/mem.s"));
+global_asm!(include_str!("asm/signal.s"));
+global_asm!(include_str!("asm/switch.s"));
 global_asm!(include_str!("asm/trap.s"));
+global_asm!(include_str!("asm/vector.s"));
@@ -3,8 +3,22 @@
 .global _start
 .global kinit
 .global kernel_vec
+.global kernel_trampoline
+
+# Must match PHYS_OFFSET in kmem.rs and __phys_offset in virt.ld
+.set PHYS_OFFSET, 0xffffffc000000000
+
+# Must match CLINT_BASE in kmem.rs
+.set CLINT_BASE, 0x2000000
+.set MIE_MSIE, 1 << 3
+
+# Everything up to kernel_trampoline runs in M-mode at the physical address the kernel was
+# loaded at, even though it is linked in the higher half. This works because code is built
+# with the medany code model, so symbols are addressed relative to pc.
 _start:
 
+  # QEMU leaves the address of the device tree blob in a1
+  mv s1, a1
 
   # Initialize global pointer
   # https://sourceware.org/binutils/docs-2.31/as/RISC_002dV_002dDirectives.html
@@ -15,13 +29,15 @@ _start:
 
 	# Initialize stack pointer to bottom of the hart's stack
 	la sp, __stack_start
-	la a0, __hart_stack_size
+	# __hart_stack_size is an absolute symbol, so it can't be addressed relative to pc
+	lui a0, %hi(__hart_stack_size)
+	addi a0, a0, %lo(__hart_stack_size)
 	csrr a1, mhartid
 	addi a1, a1, 1
 	mul a0, a0, a1
 	add sp, sp, a0
 
-  # busy wait on all harts except 0
+  # the other harts wait until hart 0 has initialized the kernel
   csrr t0, mhartid
   bnez t0, 3f
 
@@ -35,8 +51,73 @@ _start:
 	bltu	a0, a1, 1b
 2:
 
-  call kinit  
+  mv a0, s1
+  call kinit
 
+  # Wait for hart 0 to set this hart's msip bit (see cpu::start_others). Machine interrupts are
+  # off, so the interrupt isn't taken, but it still wakes up wfi.
 3:
+  li t0, MIE_MSIE
+  csrw mie, t0
+4:
   wfi
-  j 3b
+  csrr t0, mip
+  andi t0, t0, MIE_MSIE
+  beqz t0, 4b
+
+  # clear this hart's msip bit, then set it up like hart 0
+  csrr t0, mhartid
+  slli t0, t0, 2
+  li t1, CLINT_BASE
+  add t0, t0, t1
+  sw zero, 0(t0)
+  mv a0, s1
+  call kinit
+
+# kinit "returns" here in S-mode with paging still disabled. Turn on paging using
+# boot_page_table, then move pc, sp and gp into the higher half and enter main.
+kernel_trampoline:
+  la t0, boot_page_table
+  srli t0, t0, 12
+  li t1, 8 << 60  # Sv39
+  or t0, t0, t1
+  sfence.vma
+  csrw satp, t0
+  sfence.vma
+
+  # we are still executing from the identity mapping
+  li t1, PHYS_OFFSET
+  add sp, sp, t1
+  .option push
+  .option norelax
+  la gp, __global_pointer$
+  .option pop
+  add gp, gp, t1
+
+  # set interrupt vector
+  la t0, kernel_vec
+  add t0, t0, t1
+  csrw stvec, t0
+
+  # hart 0 initializes the kernel, the others only themselves
+  la t0, main
+  beqz tp, 1f
+  la t0, secondary_main
+1:
+  add t0, t0, t1
+  jr t0
+
+# Sv39 page table used until mmu::init() builds the real one. It uses 1GiB pages to identity
+# map the RAM that kernel_trampoline runs from, and to direct map the first 3GiB of the
+# physical address space (MMIO devices and RAM) at PHYS_OFFSET.
+.section .data
+.balign 4096
+boot_page_table:
+  .dword 0
+  .dword 0
+  .dword (0x80000 << 10) | 0xcf  # 0x8000_0000 -> 0x8000_0000, VRWXAD
+  .zero 8 * (256 - 3)
+  .dword (0x00000 << 10) | 0xc7  # PHYS_OFFSET + 0x0000_0000 -> 0x0000_0000, VRWAD
+  .dword (0x40000 << 10) | 0xc7  # PHYS_OFFSET + 0x4000_0000 -> 0x4000_0000, VRWAD
+  .dword (0x80000 << 10) | 0xcf  # PHYS_OFFSET + 0x8000_0000 -> 0x8000_0000, VRWXAD
+  .zero 8 * (512 - 259)
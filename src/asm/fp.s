@@ -0,0 +1,36 @@
+.altmacro
+.set FP_REG_SIZE, 8
+.macro save_freg i
+  fsd f\i, ((\i)*FP_REG_SIZE)(a0)
+.endm
+.macro load_freg i
+  fld f\i, ((\i)*FP_REG_SIZE)(a0)
+.endm
+
+# fp_save(state: *mut FpState)
+# Store f0-f31 and fcsr into the FpState at a0. sstatus.FS must not be Off.
+.global fp_save
+.align 4
+fp_save:
+.set i, 0
+.rept 32
+  save_freg %i
+.set i, i+1
+.endr
+  frcsr t0
+  sd t0, (32*FP_REG_SIZE)(a0)
+  ret
+
+# fp_restore(state: *const FpState)
+# Load f0-f31 and fcsr from the FpState at a0. sstatus.FS must not be Off.
+.global fp_restore
+.align 4
+fp_restore:
+.set i, 0
+.rept 32
+  load_freg %i
+.set i, i+1
+.endr
+  ld t0, (32*FP_REG_SIZE)(a0)
+  fscsr t0
+  ret
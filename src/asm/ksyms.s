@@ -0,0 +1,7 @@
+# Space for the kernel symbol table (see ksyms.rs). It is filled in after linking by
+# scripts/ksyms.sh, which ryos-qemu.sh runs before booting the kernel.
+.section .ksyms, "a"
+.balign 8
+.global KSYMS
+KSYMS:
+  .zero 256 * 1024
@@ -31,6 +31,9 @@ STACK_START: .dword __stack_start
 .global STACK_END
 STACK_END: .dword __stack_end
 
+.global HART_STACK_SIZE
+HART_STACK_SIZE: .dword __hart_stack_size
+
 .global HEAP_START
 HEAP_START: .dword __heap_start
 
@@ -0,0 +1,11 @@
+# sigreturn_trampoline
+# Where signal handlers return to (see signal.rs). The page is mapped into every process, so
+# the trampoline gets it to itself rather than sharing it with the rest of the kernel.
+.pushsection .text.sigreturn, "ax"
+.balign 4096
+.global sigreturn_trampoline
+sigreturn_trampoline:
+  li a7, 139 # rt_sigreturn
+  ecall
+.balign 4096
+.popsection
@@ -8,12 +8,72 @@
   ld x\i, ((\i)*REG_SIZE)(sp)
 .endm
 
+# Must match KSTACK_REGION in kmem.rs, STACK_SLOT in kthread.rs and EMERGENCY_STACK_SIZE in
+# trap.rs
+.set KSTACK_REGION_START, 0xfffffff000000000
+.set KSTACK_REGION_SIZE, 0x40000000
+.set KSTACK_SLOT, 5 * 4096
+.set PAGE_SIZE, 4096
+.set EMERGENCY_STACK_SIZE, 16384
+.set SCAUSE_STORE_PAGE_FAULT, 15
+
+# Point sscratch at the top of this hart's emergency stack, using t0-t2
+.macro set_emergency_stack
+  la t0, EMERGENCY_STACKS
+  addi t1, tp, 1
+  li t2, EMERGENCY_STACK_SIZE
+  mul t1, t1, t2
+  add t0, t0, t1
+  csrw sscratch, t0
+.endm
+
+# Trap vector while running in the kernel. sscratch holds the top of the hart's emergency
+# stack. A kernel thread that overflows its stack faults on the guard page below it, and saving
+# registers below its sp would fault again, so that trap saves them on the emergency stack.
 .global kernel_vec
 .global kernel_trap
 .align 4
 kernel_vec:
+  csrrw sp, sscratch, sp
+  sd t0, -8(sp)
+  sd t1, -16(sp)
+
+  # is it a store to the guard page of a kernel thread stack?
+  csrr t0, scause
+  li t1, SCAUSE_STORE_PAGE_FAULT
+  bne t0, t1, 1f
+  csrr t0, stval
+  li t1, KSTACK_REGION_START
+  sub t0, t0, t1
+  li t1, KSTACK_REGION_SIZE
+  bgeu t0, t1, 1f
+  li t1, KSTACK_SLOT
+  remu t0, t0, t1
+  li t1, PAGE_SIZE
+  bgeu t0, t1, 1f
+
+  # it is: stay on the emergency stack, below the two registers saved at its top
+  ld t0, -8(sp)
+  ld t1, -16(sp)
+  addi sp, sp, -16-256
+.set i, 0
+.rept 32
+  save_reg %i # write x0-x31
+.set i, i+1
+.endr
+  # keep the overflowed sp in the frame, as if the frame had been pushed onto it
+  csrr t0, sscratch
+  addi t0, t0, -256
+  sd t0, (2*REG_SIZE)(sp)
+  addi t0, sp, 16+256
+  csrw sscratch, t0
+  j 2f
 
-  # push all general purpose registers to the stack
+  # it isn't: go back to the interrupted stack and push all general purpose registers to it
+1:
+  ld t0, -8(sp)
+  ld t1, -16(sp)
+  csrrw sp, sscratch, sp
   addi sp, sp, -256
 .set i, 0
 .rept 32
@@ -21,15 +81,151 @@ kernel_vec:
 .set i, i+1
 .endr
 
-  # handle trap in trap.rs
+  # handle trap in trap.rs, passing it the saved registers
+2:
+  mv a0, sp
   call kernel_trap
 
-  # pop all general purpose registers from the stack
-.set i, 0
-.rept 32
-  load_reg %i # read x0-x31
+  # pop all general purpose registers from the stack, with sp last
+  load_reg 1
+.set i, 3
+.rept 29
+  load_reg %i # read x3-x31
 .set i, i+1
 .endr
+  load_reg 2
   addi sp, sp, 256
 
   sret
+
+
+# Offsets into proc::TrapFrame
+.set TF_KERNEL_SP, 8
+.set TF_EPC, 16
+.set TF_KERNEL_HARTID, 24
+.set TF_REGS, 32
+.macro save_user_reg i
+  sd x\i, (TF_REGS+(\i)*REG_SIZE)(t6)
+.endm
+.macro load_user_reg i
+  ld x\i, (TF_REGS+(\i)*REG_SIZE)(t6)
+.endm
+
+# Trap vector while running in user mode. sscratch holds the process' TrapFrame. The kernel
+# is mapped in every user page table, so there is no need to switch satp.
+.global user_vec
+.global user_trap
+.align 4
+user_vec:
+  csrrw t6, sscratch, t6
+
+  # save x1-x30, then the user's t6 (x31) which is now in sscratch
+.set i, 1
+.rept 30
+  save_user_reg %i
+.set i, i+1
+.endr
+  csrr t0, sscratch
+  sd t0, (TF_REGS+31*REG_SIZE)(t6)
+  csrr t0, sepc
+  sd t0, TF_EPC(t6)
+
+  # switch to the kernel's stack, hart id and global pointer
+  ld sp, TF_KERNEL_SP(t6)
+  ld tp, TF_KERNEL_HARTID(t6)
+  .option push
+  .option norelax
+  la gp, __global_pointer$
+  .option pop
+
+  # traps from now on happen in the kernel
+  la t0, kernel_vec
+  csrw stvec, t0
+  set_emergency_stack
+
+  # handle trap in trap.rs, which returns to user mode through user_ret
+  mv a0, t6
+  call user_trap
+1:
+  j 1b
+
+# user_ret(frame: &TrapFrame) -> !
+# Return to user mode at frame.epc with the registers in frame. sstatus must already be set up
+# for sret, with interrupts disabled.
+.global user_ret
+.align 4
+user_ret:
+  csrw sscratch, a0
+  la t0, user_vec
+  csrw stvec, t0
+  ld t0, TF_EPC(a0)
+  csrw sepc, t0
+
+  mv t6, a0
+.set i, 1
+.rept 31
+  load_user_reg %i # read x1-x31, with t6 (x31) last
+.set i, i+1
+.endr
+  sret
+
+# Must match CLINT_BASE in kmem.rs
+.set CLINT_BASE, 0x2000000
+.set CLINT_MTIME, CLINT_BASE + 0xbff8
+.set MCAUSE_MSI, (1 << 63) | 3
+.set MCAUSE_MTI, (1 << 63) | 7
+
+# Offsets into timer::MachineScratch
+.set MS_MTIMECMP, 16
+.set MS_INTERVAL, 24
+.set MS_TICK, 32
+
+# Machine mode trap vector. Everything is delegated to supervisor mode except machine software
+# interrupts, which are raised through the CLINT by ipi.rs, and machine timer interrupts. Both
+# are forwarded to supervisor mode as supervisor software interrupts. mscratch points to the
+# hart's timer::MachineScratch, which has room for saving registers.
+.global machine_vec
+.align 4
+machine_vec:
+  csrrw t0, mscratch, t0
+  sd t1, 0(t0)
+  sd t2, 8(t0)
+
+  csrr t1, mcause
+  li t2, MCAUSE_MSI
+  beq t1, t2, 1f
+  li t2, MCAUSE_MTI
+  beq t1, t2, 2f
+
+  # nothing else should trap into machine mode
+3:
+  wfi
+  j 3b
+
+  # clear this hart's msip bit
+1:
+  csrr t1, mhartid
+  slli t1, t1, 2
+  li t2, CLINT_BASE
+  add t1, t1, t2
+  sw zero, 0(t1)
+  j 4f
+
+  # set mtimecmp for the next tick, which also clears MTIP, and flag the tick
+2:
+  li t1, CLINT_MTIME
+  ld t1, 0(t1)
+  ld t2, MS_INTERVAL(t0)
+  add t1, t1, t2
+  ld t2, MS_MTIMECMP(t0)
+  sd t1, 0(t2)
+  li t1, 1
+  sd t1, MS_TICK(t0)
+
+  # raise SSIP instead
+4:
+  csrsi mip, 1 << 1
+  ld t1, 0(t0)
+  ld t2, 8(t0)
+  csrrw t0, mscratch, t0
+  mret
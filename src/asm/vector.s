@@ -0,0 +1,43 @@
+# Whole vector register loads and stores, encoded by hand so that the assembler doesn't need to
+# know about the V extension. Both use a0 (x10) as the base address.
+.altmacro
+.macro vs1r_a0 i
+  .word (1 << 25) | (8 << 20) | (10 << 15) | ((\i) << 7) | 0x27  # vs1r.v v\i, (a0)
+.endm
+.macro vl1re8_a0 i
+  .word (1 << 25) | (8 << 20) | (10 << 15) | ((\i) << 7) | 0x07  # vl1re8.v v\i, (a0)
+.endm
+
+# vector_save(area: *mut u8, vlenb: u64)
+# Store v0-v31 one after another, vlenb bytes each. sstatus.VS must not be Off.
+.global vector_save
+.align 4
+vector_save:
+.set i, 0
+.rept 32
+  vs1r_a0 %i
+  add a0, a0, a1
+.set i, i+1
+.endr
+  ret
+
+# vector_restore(area: *const u8, vlenb: u64)
+# Load v0-v31 as stored by vector_save. sstatus.VS must not be Off.
+.global vector_restore
+.align 4
+vector_restore:
+.set i, 0
+.rept 32
+  vl1re8_a0 %i
+  add a0, a0, a1
+.set i, i+1
+.endr
+  ret
+
+# vector_setvl(vl: u64, vtype: u64)
+# Restore vl and vtype. sstatus.VS must not be Off.
+.global vector_setvl
+.align 4
+vector_setvl:
+  .word (1 << 31) | (11 << 20) | (10 << 15) | (7 << 12) | 0x57  # vsetvl zero, a0, a1
+  ret
@@ -1,14 +1,159 @@
-use core::mem::MaybeUninit;
+use core::{
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    cpustat::HartStats,
+    fdt,
+    fp::FpState,
+    ipi,
+    kthread::{Context, KThread},
+    lockdep::HartLocks,
+    sched::RunQueue,
+    trap::KernelFrame,
+    vector::VectorState,
+};
 
 #[macro_export]
 macro_rules! cpu {
     () => {
-        crate::cpu::CPUS.assume_init_mut()[crate::csr_read!(tp) as usize]
+        crate::cpu::CPUS.assume_init_mut()[crate::reg_read!(tp) as usize]
     };
 }
 
 pub struct CPU {
     pub current_proc: usize,
+    pub kernel_frame: *mut KernelFrame, // registers saved by kernel_vec while handling a trap
+    pub fp_owner: *const FpState,       // process whose FP registers were last loaded
+    pub vector_owner: *const VectorState, // process whose vector registers were last loaded
+    pub stats: HartStats,
+    pub current_thread: *mut KThread, // kernel thread running on this hart
+    pub idle_context: Context,        // where the idle loop left off to run a kernel thread
+    pub lockdep: HartLocks,
+    pub run_queue: RunQueue,
+    pub need_resched: bool, // the running process should be preempted (see sched.rs)
+    pub after_sleep: Option<(fn(usize), usize)>, // see sched::after_sleep
+}
+
+/// Number of harts the kernel has room for
+pub const MAX_HARTS: usize = 4;
+
+pub static mut CPUS: MaybeUninit<[CPU; MAX_HARTS]> = MaybeUninit::zeroed();
+
+/// Bitmask of the harts that have finished initializing, bit n standing for hart n
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Mark the current hart as ready to take part in cross-hart calls
+pub fn set_online() {
+    ONLINE.fetch_or(1 << unsafe { crate::reg_read!(tp) }, Ordering::Release);
+}
+
+pub fn online() -> u64 {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Wake up the other harts listed in the device tree, which wait in boot.s until hart 0 has
+/// initialized the kernel
+pub fn start_others() {
+    let me = unsafe { crate::reg_read!(tp) } as usize;
+    let Some(fdt) = fdt::get() else {
+        debug!("No device tree, only running on hart {}", me);
+        return;
+    };
+    let harts = fdt
+        .nodes()
+        .filter(|node| node.base_name() == "cpu")
+        .filter(|node| {
+            node.property_str("status")
+                .is_none_or(|status| status == "okay")
+        })
+        .filter_map(|node| node.property_u32("reg"));
+    // everything hart 0 set up must be visible before the others start using it
+    unsafe { core::arch::asm!("fence") };
+    for hartid in harts.map(|hartid| hartid as usize) {
+        if hartid == me {
+            continue;
+        }
+        if hartid >= MAX_HARTS {
+            debug!(
+                "Not starting hart {}, there is only room for {}",
+                hartid, MAX_HARTS
+            );
+            continue;
+        }
+        ipi::kick(hartid);
+    }
+}
+
+/// Optional ISA extensions that the kernel knows how to take advantage of
+#[derive(Clone, Copy, Debug)]
+pub enum Extension {
+    Svpbmt,  // page-based memory types
+    Svnapot, // NAPOT translation contiguity (64KiB pages)
+    V,       // vectors
 }
 
-pub static mut CPUS: MaybeUninit<[CPU; 4]> = MaybeUninit::zeroed();
+impl Extension {
+    const ALL: [Extension; 3] = [Extension::Svpbmt, Extension::Svnapot, Extension::V];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Extension::Svpbmt => "svpbmt",
+            Extension::Svnapot => "svnapot",
+            Extension::V => "v",
+        }
+    }
+
+    fn bit(&self) -> u64 {
+        1 << *self as u64
+    }
+}
+
+static mut EXTENSIONS: u64 = 0;
+
+/// Set by kinit (in M-mode) if menvcfg.PBMTE could be enabled. S-mode cannot read menvcfg.
+pub static mut PBMTE_ENABLED: bool = false;
+
+/// Detect supported ISA extensions from the `riscv,isa` string (or the newer
+/// `riscv,isa-extensions` list) of the boot hart in the device tree.
+pub fn init() {
+    let Some(cpu) = fdt::get().and_then(|fdt| fdt.find_node("cpu")) else {
+        debug!("No cpu node in device tree, assuming no ISA extensions");
+        return;
+    };
+    let mut found = 0;
+    for ext in Extension::ALL {
+        let mut isa = cpu.property_str("riscv,isa").unwrap_or("").split('_');
+        // the first part holds the single-letter extensions, e.g. "rv64imafdcv"
+        let base = isa.next().unwrap_or("");
+        let in_isa_string = match ext.name().as_bytes() {
+            [letter] => base.bytes().skip(4).any(|b| b.eq_ignore_ascii_case(letter)),
+            _ => isa.any(|name| name.eq_ignore_ascii_case(ext.name())),
+        };
+        let in_isa_list = cpu
+            .property_strings("riscv,isa-extensions")
+            .any(|name| name.eq_ignore_ascii_case(ext.name()));
+        if in_isa_string || in_isa_list {
+            found |= ext.bit();
+        }
+    }
+
+    // Svpbmt is useless unless M-mode managed to turn it on for us
+    if unsafe { !PBMTE_ENABLED } {
+        found &= !Extension::Svpbmt.bit();
+    }
+
+    unsafe {
+        EXTENSIONS = found;
+    }
+    for ext in Extension::ALL {
+        if has_extension(ext) {
+            debug!("Detected ISA extension {}", ext.name());
+        }
+    }
+}
+
+pub fn has_extension(ext: Extension) -> bool {
+    unsafe { EXTENSIONS & ext.bit() != 0 }
+}
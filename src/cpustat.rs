@@ -0,0 +1,90 @@
+/// CPU time accounting
+///
+/// A hart is always doing one of the things in `Mode`. On every change (trap entry and exit,
+/// and around wfi in the idle loop) the time spent since the last change, in ticks of the
+/// `time` CSR, is charged to the mode being left.
+use crate::{cpu, csr_read, fdt};
+
+/// QEMU's virt machine, in case the device tree doesn't say
+const DEFAULT_TIMEBASE_HZ: u64 = 10_000_000;
+
+static mut TIMEBASE_HZ: u64 = DEFAULT_TIMEBASE_HZ;
+
+/// What a hart is spending its time on. CPUS starts out zeroed, so harts boot in System.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Mode {
+    System, // kernel code on behalf of a process or itself
+    User,
+    Interrupt, // interrupt handlers, whatever was interrupted
+    Idle,      // waiting for an interrupt with nothing to run
+}
+
+const NUM_MODES: usize = core::mem::variant_count::<Mode>();
+
+/// Per hart accounting state, kept in its CPU
+pub struct HartStats {
+    ticks: [u64; NUM_MODES],
+    mode: Mode,
+    since: u64, // time of the last change of mode
+}
+
+/// Time spent by a hart in each mode, as returned by the cpu_stats syscall
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct CpuStats {
+    pub idle: u64,
+    pub user: u64,
+    pub system: u64,
+    pub interrupt: u64,
+    pub timebase_hz: u64, // ticks per second
+}
+
+/// Read the frequency of the time CSR from the device tree
+pub fn init() {
+    let timebase = fdt::get()
+        .and_then(|fdt| fdt.find_node("cpus"))
+        .and_then(|cpus| cpus.property_u32("timebase-frequency"));
+    match timebase {
+        Some(hz) => unsafe { TIMEBASE_HZ = hz as u64 },
+        None => debug!("No timebase-frequency, assuming {} Hz", DEFAULT_TIMEBASE_HZ),
+    }
+}
+
+/// Ticks of the time CSR per second
+pub fn timebase_hz() -> u64 {
+    unsafe { TIMEBASE_HZ }
+}
+
+pub fn now() -> u64 {
+    unsafe { csr_read!(time) }
+}
+
+/// Charge the time since the last change to the current mode and switch to `mode`. Returns
+/// the previous mode, so that an interrupt handler can go back to it.
+pub fn switch(mode: Mode) -> Mode {
+    let stats = unsafe { &mut cpu!().stats };
+    let now = now();
+    stats.ticks[stats.mode as usize] += now.wrapping_sub(stats.since);
+    stats.since = now;
+    core::mem::replace(&mut stats.mode, mode)
+}
+
+/// What this hart is doing
+pub fn mode() -> Mode {
+    unsafe { cpu!().stats.mode }
+}
+
+/// Time spent by hart `hartid` in each mode so far, including the current one
+pub fn get(hartid: usize) -> CpuStats {
+    let stats = unsafe { &crate::cpu::CPUS.assume_init_ref()[hartid].stats };
+    let mut ticks = stats.ticks;
+    ticks[stats.mode as usize] += now().wrapping_sub(stats.since);
+    CpuStats {
+        idle: ticks[Mode::Idle as usize],
+        user: ticks[Mode::User as usize],
+        system: ticks[Mode::System as usize],
+        interrupt: ticks[Mode::Interrupt as usize],
+        timebase_hz: timebase_hz(),
+    }
+}
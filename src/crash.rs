@@ -0,0 +1,83 @@
+/// Crash reports for the panic handler
+///
+/// Prints the hart id, the registers saved by kernel_vec (if the panic happened while handling
+/// a trap), and a backtrace found by following the frame pointer chain. The kernel is built
+/// with -Cforce-frame-pointers=yes, so every function stores its return address and the
+/// caller's frame pointer just below its own frame pointer:
+///
+///   fp - 8:  return address
+///   fp - 16: caller's fp
+use core::ops::Range;
+
+use crate::{
+    cpu, csr_read, kmem::hart_stack, ksyms::Symbolized, kthread, println_sync, reg_read,
+    scause::Trap, trap::KernelFrame,
+};
+
+const MAX_FRAMES: usize = 32;
+
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Print everything we know about the state of this hart
+pub fn report() {
+    unsafe {
+        let hartid = reg_read!(tp);
+        println_sync!("hart {}", hartid);
+
+        let frame = cpu!().kernel_frame;
+        if let Some(frame) = frame.as_ref() {
+            let scause = csr_read!(scause);
+            match Trap::decode(scause) {
+                Ok(trap) => println_sync!("scause  0x{:016x} ({})", scause, trap),
+                Err(unknown) => println_sync!("scause  0x{:016x} ({})", scause, unknown),
+            }
+            println_sync!("sepc    {}", Symbolized(csr_read!(sepc)));
+            println_sync!("stval   0x{:016x}", csr_read!(stval));
+            println_sync!("sstatus 0x{:016x}", csr_read!(sstatus));
+            dump_registers(frame);
+        }
+
+        println_sync!("backtrace:");
+        let stack = match kthread::current() {
+            Some(thread) => thread.stack.clone(),
+            None => hart_stack(hartid),
+        };
+        backtrace(reg_read!(s0), stack);
+    }
+}
+
+fn dump_registers(frame: &KernelFrame) {
+    for row in 0..8 {
+        for col in 0..4 {
+            let i = row * 4 + col;
+            let value = if i == 2 { frame.sp() } else { frame.regs[i] };
+            crate::print_sync!("{:>4} 0x{:016x}  ", REG_NAMES[i], value);
+        }
+        println_sync!();
+    }
+}
+
+/// Walk the frame pointer chain starting at `fp`, refusing to read outside of `stack`
+fn backtrace(mut fp: u64, stack: Range<u64>) {
+    for depth in 0..MAX_FRAMES {
+        // the saved ra and fp must lie within the stack, and frames only get older going up
+        if fp % 8 != 0 || fp < stack.start + 16 || fp > stack.end {
+            return;
+        }
+        let ra = unsafe { *((fp - 8) as *const u64) };
+        let prev_fp = unsafe { *((fp - 16) as *const u64) };
+        if ra == 0 {
+            return;
+        }
+        println_sync!("  #{:<2} {}", depth, Symbolized(ra));
+        if prev_fp <= fp {
+            return;
+        }
+        fp = prev_fp;
+    }
+    println_sync!("  ...");
+}
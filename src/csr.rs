@@ -75,6 +75,10 @@ pub const MSTATUS_MPP_M: u64 = 3;
 pub const MSTATUS_MPP_S: u64 = 1;
 pub const MSTATUS_MPP_U: u64 = 0;
 
+// 3.1.9 Machine Interrupt Registers
+pub const MIE_MSIE: u64 = 1 << 3;
+pub const MIE_MTIE: u64 = 1 << 7;
+
 // 3.7.1 Physical Memory Protection CSRs
 pub const PMPCFG_A: u64 = 0b11 << 3;
 pub const PMPCFG_A_OFF: u64 = 0;
@@ -85,16 +89,32 @@ pub const PMPCFG_X: u64 = 1 << 2;
 pub const PMPCFG_W: u64 = 1 << 1;
 pub const PMPCFG_R: u64 = 1 << 0;
 
+// 3.1.18 Machine Environment Configuration Register
+pub const MENVCFG_PBMTE: u64 = 1 << 62;
+
 // SUPERVISOR
 
 // 5.1.1 Supervisor Status Register
+pub const SSTATUS_SUM: u64 = 1 << 18;
+pub const SSTATUS_FS: u64 = 0b11 << 13;
+pub const SSTATUS_FS_OFF: u64 = 0;
+pub const SSTATUS_FS_INITIAL: u64 = 1;
+pub const SSTATUS_FS_CLEAN: u64 = 2;
+pub const SSTATUS_FS_DIRTY: u64 = 3;
+pub const SSTATUS_VS: u64 = 0b11 << 9;
+pub const SSTATUS_VS_OFF: u64 = 0;
+pub const SSTATUS_VS_INITIAL: u64 = 1;
+pub const SSTATUS_VS_CLEAN: u64 = 2;
+pub const SSTATUS_VS_DIRTY: u64 = 3;
 pub const SSTATUS_SPP: u64 = 1 << 8;
+pub const SSTATUS_SPIE: u64 = 1 << 5;
 pub const SSTATUS_SIE: u64 = 1 << 1;
 
 // 5.1.3 Supervisor Interrupt Registers
 pub const SIE_SEIE: u64 = 1 << 9;
 pub const SIE_STIE: u64 = 1 << 5;
 pub const SIE_SSIE: u64 = 1 << 1;
+pub const SIP_SSIP: u64 = 1 << 1;
 
 // 5.1.11 Supervisor Address Translation and Protection Register
 pub const SATP_MODE: u64 = 0b1111 << 60;
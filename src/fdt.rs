@@ -0,0 +1,291 @@
+/// Minimal read-only parser for the flattened device tree passed to us by QEMU
+/// https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+///
+/// Only what the kernel needs to discover hardware is supported: walking nodes, reading
+/// properties, and looking up nodes by name or `compatible` string.
+use core::str::from_utf8;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+// byte offsets of fields in the FDT header
+const HEADER_MAGIC: usize = 0x00;
+const HEADER_TOTALSIZE: usize = 0x04;
+const HEADER_OFF_DT_STRUCT: usize = 0x08;
+const HEADER_OFF_DT_STRINGS: usize = 0x0c;
+const HEADER_SIZE_DT_STRINGS: usize = 0x20;
+const HEADER_SIZE_DT_STRUCT: usize = 0x24;
+
+static mut FDT: Option<Fdt> = None;
+
+/// Validate the device tree blob at `addr` and make it available through `get()`.
+/// `addr` is the value QEMU leaves in a1 at reset.
+pub fn init(addr: u64) {
+    unsafe {
+        FDT = Fdt::new(addr as *const u8);
+        match &FDT {
+            Some(fdt) => debug!(
+                "Found device tree at 0x{:x} ({} bytes)",
+                addr,
+                fdt.total_size()
+            ),
+            None => debug!("No valid device tree at 0x{:x}", addr),
+        }
+    }
+}
+
+/// Get the device tree found by `init()`, if any.
+pub fn get() -> Option<&'static Fdt> {
+    unsafe { FDT.as_ref() }
+}
+
+pub struct Fdt {
+    base: *const u8,
+}
+
+impl Fdt {
+    fn new(base: *const u8) -> Option<Self> {
+        if base.is_null() || base as u64 % 4 != 0 {
+            return None;
+        }
+        let fdt = Self { base };
+        if fdt.header(HEADER_MAGIC) != FDT_MAGIC {
+            return None;
+        }
+        Some(fdt)
+    }
+
+    fn header(&self, offset: usize) -> u32 {
+        unsafe { be32(self.base.add(offset)) }
+    }
+
+    /// Address of the blob in memory
+    pub fn addr(&self) -> u64 {
+        self.base as u64
+    }
+
+    /// Size of the blob in bytes, including all of its blocks
+    pub fn total_size(&self) -> u64 {
+        self.header(HEADER_TOTALSIZE) as u64
+    }
+
+    fn structs(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self.base.add(self.header(HEADER_OFF_DT_STRUCT) as usize),
+                self.header(HEADER_SIZE_DT_STRUCT) as usize,
+            )
+        }
+    }
+
+    fn strings(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self.base.add(self.header(HEADER_OFF_DT_STRINGS) as usize),
+                self.header(HEADER_SIZE_DT_STRINGS) as usize,
+            )
+        }
+    }
+
+    fn string_at(&self, offset: usize) -> &str {
+        cstr(&self.strings()[offset..])
+    }
+
+    /// Iterate over every node in the tree in depth-first order.
+    pub fn nodes(&self) -> Nodes<'_> {
+        Nodes {
+            fdt: self,
+            offset: 0,
+            depth: 0,
+        }
+    }
+
+    /// Find the first node whose name (ignoring the unit address) is `name`
+    pub fn find_node(&self, name: &str) -> Option<Node<'_>> {
+        self.nodes().find(|node| node.base_name() == name)
+    }
+
+    /// Find the first node listing `compatible` in its `compatible` property
+    pub fn find_compatible(&self, compatible: &str) -> Option<Node<'_>> {
+        self.nodes().find(|node| node.is_compatible(compatible))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: &'a Fdt,
+    pub name: &'a str,
+    pub depth: usize,
+    props: usize, // offset of the first token after FDT_BEGIN_NODE and the node name
+}
+
+impl<'a> Node<'a> {
+    /// Node name without the "@unit-address" suffix
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap()
+    }
+
+    /// Iterate over the properties of this node (not including those of its children)
+    pub fn properties(&self) -> Properties<'a> {
+        Properties {
+            fdt: self.fdt,
+            offset: self.props,
+        }
+    }
+
+    /// Get the raw value of the property called `name`
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties()
+            .find(|prop| prop.name == name)
+            .map(|prop| prop.value)
+    }
+
+    /// Iterate over a property holding a list of NUL-terminated strings
+    pub fn property_strings(&self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.property(name)
+            .unwrap_or(&[])
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .map(|s| from_utf8(s).unwrap_or(""))
+    }
+
+    /// Get a property holding a single string
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        self.property(name).map(cstr)
+    }
+
+    /// Get a property holding a single 32-bit cell
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        match self.property(name) {
+            Some(value) if value.len() >= 4 => Some(unsafe { be32(value.as_ptr()) }),
+            _ => None,
+        }
+    }
+
+    /// Iterate over a property holding a list of 32-bit cells
+    pub fn property_u32s(&self, name: &str) -> impl Iterator<Item = u32> + 'a {
+        self.property(name)
+            .unwrap_or(&[])
+            .chunks_exact(4)
+            .map(|cell| unsafe { be32(cell.as_ptr()) })
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property_strings("compatible").any(|c| c == compatible)
+    }
+
+    /// Get the `index`th (address, size) pair of the `reg` property. The QEMU virt machine
+    /// uses #address-cells = <2> and #size-cells = <2> for everything under /soc.
+    pub fn reg(&self, index: usize) -> Option<(u64, u64)> {
+        let reg = self.property("reg")?;
+        let entry = reg.get(index * 16..(index + 1) * 16)?;
+        unsafe { Some((be64(entry.as_ptr()), be64(entry.as_ptr().add(8)))) }
+    }
+}
+
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+pub struct Properties<'a> {
+    fdt: &'a Fdt,
+    offset: usize,
+}
+
+impl<'a> Iterator for Properties<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs();
+        loop {
+            match token(structs, self.offset)? {
+                FDT_NOP => self.offset += 4,
+                FDT_PROP => {
+                    let len = token(structs, self.offset + 4)? as usize;
+                    let name_offset = token(structs, self.offset + 8)? as usize;
+                    let value_start = self.offset + 12;
+                    let value = structs.get(value_start..value_start + len)?;
+                    self.offset = align4(value_start + len);
+                    return Some(Property {
+                        name: self.fdt.string_at(name_offset),
+                        value,
+                    });
+                }
+                _ => return None, // properties always precede child nodes
+            }
+        }
+    }
+}
+
+pub struct Nodes<'a> {
+    fdt: &'a Fdt,
+    offset: usize,
+    depth: usize,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs();
+        loop {
+            match token(structs, self.offset)? {
+                FDT_BEGIN_NODE => {
+                    let name_bytes = &structs[self.offset + 4..];
+                    let name_len = name_bytes.iter().position(|&b| b == 0)?;
+                    let name = from_utf8(&name_bytes[..name_len]).unwrap_or("");
+                    let props = align4(self.offset + 4 + name_len + 1);
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        depth: self.depth,
+                        props,
+                    };
+                    self.depth += 1;
+                    self.offset = props;
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                    self.offset += 4;
+                }
+                FDT_PROP => {
+                    let len = token(structs, self.offset + 4)? as usize;
+                    self.offset = align4(self.offset + 12 + len);
+                }
+                FDT_NOP => self.offset += 4,
+                _ => return None, // FDT_END, or a malformed tree
+            }
+        }
+    }
+}
+
+fn token(structs: &[u8], offset: usize) -> Option<u32> {
+    if offset + 4 > structs.len() {
+        None
+    } else {
+        Some(unsafe { be32(structs.as_ptr().add(offset)) })
+    }
+}
+
+/// Read a NUL-terminated string from the start of `bytes`
+fn cstr(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+const fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+unsafe fn be32(ptr: *const u8) -> u32 {
+    u32::from_be_bytes(*(ptr as *const [u8; 4]))
+}
+
+unsafe fn be64(ptr: *const u8) -> u64 {
+    ((be32(ptr) as u64) << 32) | be32(ptr.add(4)) as u64
+}
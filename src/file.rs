@@ -0,0 +1,324 @@
+/// Open files and file descriptors
+///
+/// An open file, made by `open`, holds the offset and flags that every descriptor referring to
+/// it shares. Open files live in FILES and are reference counted: `dup` and copying a
+/// descriptor table take a reference, and closing a descriptor drops one, so a file is only
+/// really closed once nothing refers to it.
+///
+/// Each process has an FdTable mapping its descriptors to open files, shared by its threads.
+/// A child made with fork, or spawned by another process, gets a copy of its parent's table,
+/// sharing the open files. Processes spawned by the kernel start with the console on fds 0, 1
+/// and 2.
+///
+/// Dropping the last reference to the end of a pipe wakes up whoever waits on the other end,
+/// which takes RUN_LOCK, so it is never done with an FdTable locked: a table hands the
+/// references it lets go of back to the caller, which drops them with `put` once it has
+/// unlocked the table.
+///
+/// There's no filesystem, so only a few devices can be opened: /dev/console, /dev/null and
+/// /dev/zero. The ends of a pipe are open files as well.
+use crate::{
+    pipe::{self, End},
+    spinlock::Spinlock,
+    syscall::{self, EBADF, EINVAL, EMFILE, ENFILE, ENOENT, ESPIPE},
+    uart,
+};
+
+/// Open files in the whole system
+pub const MAX_FILES: usize = 128;
+
+/// Descriptors per process
+pub const MAX_FDS: usize = 32;
+
+// open flags, as in Linux. Others are accepted and ignored.
+pub const O_ACCMODE: u32 = 0o3;
+pub const O_RDONLY: u32 = 0o0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_APPEND: u32 = 0o2000;
+pub const O_NONBLOCK: u32 = 0o4000;
+pub const O_CLOEXEC: u32 = 0o2000000;
+
+// lseek whence
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// Index in FILES of the console, which is always open for fds 0-2 of new processes
+const CONSOLE: usize = 0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Console,
+    Null,             // reads return end of file, writes are thrown away
+    Zero,             // reads return zeroes, writes are thrown away
+    Pipe(usize, End), // index in PIPES
+}
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    refs: usize, // 0 for a free entry
+    kind: Kind,
+    flags: u32,
+    offset: u64,
+}
+
+static FILES: Spinlock<[OpenFile; MAX_FILES]> = Spinlock::new({
+    let free = OpenFile {
+        refs: 0,
+        kind: Kind::Null,
+        flags: 0,
+        offset: 0,
+    };
+    let mut files = [free; MAX_FILES];
+    // never closed
+    files[CONSOLE] = OpenFile {
+        refs: 1,
+        kind: Kind::Console,
+        flags: O_RDWR,
+        offset: 0,
+    };
+    files
+});
+
+/// Descriptors of a process, each the index in FILES of an open file
+pub struct FdTable {
+    fds: [Option<usize>; MAX_FDS],
+}
+
+impl Kind {
+    fn seekable(&self) -> bool {
+        !matches!(self, Kind::Console | Kind::Pipe(..))
+    }
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        Self {
+            fds: [None; MAX_FDS],
+        }
+    }
+
+    /// A table with the console on fds 0, 1 and 2
+    pub fn console() -> Self {
+        let mut table = Self::new();
+        for fd in 0..3 {
+            table.fds[fd] = Some(get(CONSOLE));
+        }
+        table
+    }
+
+    /// A copy of the table, sharing its open files
+    pub fn copy(&self) -> Self {
+        Self {
+            fds: self.fds.map(|file| file.map(get)),
+        }
+    }
+
+    /// Give `first` and `second` the two lowest free descriptors, taking over the caller's
+    /// references to them. The caller keeps them if there isn't room for both.
+    pub fn install_pair(&mut self, first: usize, second: usize) -> Result<(u64, u64), i64> {
+        if self.fds.iter().filter(|fd| fd.is_none()).count() < 2 {
+            return Err(EMFILE);
+        }
+        Ok((self.install(first)?, self.install(second)?))
+    }
+
+    /// The open file that `fd` refers to
+    pub fn file(&self, fd: u64) -> Result<usize, i64> {
+        let fd = usize::try_from(fd).map_err(|_| EBADF)?;
+        self.fds.get(fd).copied().flatten().ok_or(EBADF)
+    }
+
+    /// Take a reference to the open file that `fd` refers to, which keeps it open even if
+    /// `fd` is closed. Dropped with `put`.
+    pub fn hold(&self, fd: u64) -> Result<usize, i64> {
+        self.file(fd).map(get)
+    }
+
+    /// Give `file` the lowest free descriptor, taking over the caller's reference to it. The
+    /// caller keeps it if there's no free descriptor.
+    pub fn install(&mut self, file: usize) -> Result<u64, i64> {
+        let fd = self.fds.iter().position(Option::is_none).ok_or(EMFILE)?;
+        self.fds[fd] = Some(file);
+        Ok(fd as u64)
+    }
+
+    /// Free `fd`, returning the reference to its file
+    pub fn close(&mut self, fd: u64) -> Result<usize, i64> {
+        let file = self.file(fd)?;
+        self.fds[fd as usize] = None;
+        Ok(file)
+    }
+
+    /// Move every descriptor to the table returned, leaving this one empty
+    pub fn take(&mut self) -> Self {
+        core::mem::replace(self, Self::new())
+    }
+
+    /// Close every descriptor of a table that isn't locked
+    pub fn close_all(mut self) {
+        for file in self.fds.iter_mut().filter_map(Option::take) {
+            put(file);
+        }
+    }
+
+    /// Refer to the file of `fd` with the lowest free descriptor as well
+    pub fn dup(&mut self, fd: u64) -> Result<u64, i64> {
+        let file = self.file(fd)?;
+        self.install(get(file))
+    }
+
+    /// Refer to the file of `old` with `new` as well, returning the reference to whatever
+    /// `new` referred to before
+    pub fn dup_to(&mut self, old: u64, new: u64) -> Result<Option<usize>, i64> {
+        let file = self.file(old)?;
+        if new as usize >= MAX_FDS {
+            return Err(EBADF);
+        }
+        Ok(self.fds[new as usize].replace(get(file)))
+    }
+}
+
+/// Take another reference to `file`
+pub fn get(file: usize) -> usize {
+    FILES.lock()[file].refs += 1;
+    file
+}
+
+/// Drop a reference to `file`, closing it if it was the last
+pub fn put(file: usize) {
+    let mut files = FILES.lock();
+    assert!(files[file].refs > 0, "file {} closed twice", file);
+    files[file].refs -= 1;
+    let closed = files[file].refs == 0;
+    let kind = files[file].kind;
+    drop(files);
+    if let (true, Kind::Pipe(index, end)) = (closed, kind) {
+        // wakes up whoever waits for the other end, so not with FILES locked
+        pipe::close(index, end);
+    }
+}
+
+/// Take a free entry in FILES for a new open file
+fn alloc(files: &mut [OpenFile; MAX_FILES], kind: Kind, flags: u32) -> Result<usize, i64> {
+    let file = files.iter().position(|file| file.refs == 0).ok_or(ENFILE)?;
+    files[file] = OpenFile {
+        refs: 1,
+        kind,
+        flags,
+        offset: 0,
+    };
+    Ok(file)
+}
+
+/// Open the file at `path`, returning a reference to it for FdTable::install
+pub fn open(path: &[u8], flags: u32) -> Result<usize, i64> {
+    let kind = match path {
+        b"/dev/console" | b"/dev/tty" => Kind::Console,
+        b"/dev/null" => Kind::Null,
+        b"/dev/zero" => Kind::Zero,
+        _ => return Err(ENOENT),
+    };
+    if flags & O_ACCMODE == O_ACCMODE {
+        return Err(EINVAL);
+    }
+    alloc(&mut FILES.lock(), kind, flags)
+}
+
+/// Make a pipe, returning references to open files for its read end and its write end. Only
+/// O_NONBLOCK matters in `flags`.
+pub fn pipe(flags: u32) -> Result<(usize, usize), i64> {
+    let index = pipe::create().ok_or(ENFILE)?;
+    let mut files = FILES.lock();
+    let read_end = alloc(&mut files, Kind::Pipe(index, End::Read), flags | O_RDONLY);
+    let write_end = alloc(&mut files, Kind::Pipe(index, End::Write), flags | O_WRONLY);
+    match (read_end, write_end) {
+        (Ok(read_end), Ok(write_end)) => Ok((read_end, write_end)),
+        (read_end, write_end) => {
+            drop(files);
+            for file in [read_end, write_end].into_iter().flatten() {
+                put(file);
+            }
+            // an end that didn't get an open file was never counted as closed
+            if read_end.is_err() {
+                pipe::close(index, End::Read);
+            }
+            if write_end.is_err() {
+                pipe::close(index, End::Write);
+            }
+            Err(ENFILE)
+        }
+    }
+}
+
+fn readable(flags: u32) -> bool {
+    flags & O_ACCMODE != O_WRONLY
+}
+
+fn writeable(flags: u32) -> bool {
+    flags & O_ACCMODE != O_RDONLY
+}
+
+/// Read from `file` at its offset into `buf`, which may put the process to sleep
+pub fn read(file: usize, buf: &mut [u8]) -> syscall::Result {
+    let OpenFile { kind, flags, .. } = FILES.lock()[file];
+    if !readable(flags) {
+        return Err(EBADF);
+    }
+    // the lock can't be held while the process sleeps
+    let n = match kind {
+        Kind::Console => uart::read_syscall(buf)?,
+        Kind::Null => 0,
+        Kind::Zero => {
+            buf.fill(0);
+            buf.len() as u64
+        }
+        Kind::Pipe(index, _) => pipe::read(index, buf, flags & O_NONBLOCK != 0)?,
+    };
+    if kind.seekable() {
+        FILES.lock()[file].offset += n;
+    }
+    Ok(n)
+}
+
+/// Write `buf` to `file` at its offset, which may put the process to sleep unless the file or
+/// `nonblock` says not to
+pub fn write(file: usize, buf: &[u8], nonblock: bool) -> syscall::Result {
+    let OpenFile { kind, flags, .. } = FILES.lock()[file];
+    if !writeable(flags) {
+        return Err(EBADF);
+    }
+    let n = match kind {
+        Kind::Console => {
+            buf.iter().for_each(|&byte| uart::put(byte));
+            buf.len() as u64
+        }
+        Kind::Null | Kind::Zero => buf.len() as u64,
+        Kind::Pipe(index, _) => pipe::write(index, buf, nonblock || flags & O_NONBLOCK != 0)?,
+    };
+    // devices have no end for O_APPEND to go to
+    if kind.seekable() && flags & O_APPEND == 0 {
+        FILES.lock()[file].offset += n;
+    }
+    Ok(n)
+}
+
+/// Move the offset of `file`, returning the new one. Devices are empty, so SEEK_END is
+/// relative to 0.
+pub fn lseek(file: usize, offset: i64, whence: u64) -> syscall::Result {
+    let mut files = FILES.lock();
+    let file = &mut files[file];
+    if !file.kind.seekable() {
+        return Err(ESPIPE);
+    }
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => file.offset as i64,
+        SEEK_END => 0,
+        _ => return Err(EINVAL),
+    };
+    let offset = base.checked_add(offset).filter(|&offset| offset >= 0);
+    file.offset = offset.ok_or(EINVAL)? as u64;
+    Ok(file.offset)
+}
@@ -0,0 +1,129 @@
+/// Lazy floating-point context switching
+///
+/// sstatus.FS tracks the state of the F and D extension registers: Off (every FP instruction
+/// traps), Initial, Clean, or Dirty (modified since they were last saved or restored). The
+/// kernel doesn't use floating point itself, so FS is kept Off while it runs. For processes:
+///
+/// - A process starts with FS Off. Its first FP instruction raises an illegal instruction
+///   exception, after which `handle_first_use` gives it zeroed registers and retries.
+/// - On a trap from user mode, the registers are only saved if FS is Dirty.
+/// - Before returning to user mode, the registers are only restored if they were last loaded
+///   for another process, or on another hart.
+use crate::{
+    cpu,
+    csr::{SSTATUS_FS, SSTATUS_FS_CLEAN, SSTATUS_FS_DIRTY, SSTATUS_FS_INITIAL, SSTATUS_FS_OFF},
+    csr_read, csr_read_field, csr_write, csr_write_field, reg_read,
+};
+
+extern "C" {
+    fn fp_save(state: *mut FpState); // defined in fp.s
+    fn fp_restore(state: *const FpState);
+}
+
+const NO_HART: u64 = u64::MAX;
+
+/// Floating-point state of a process. The layout of `regs` and `fcsr` must match fp.s.
+#[repr(C)]
+pub struct FpState {
+    pub regs: [u64; 32],
+    pub fcsr: u64,
+    status: u64, // value of sstatus.FS to return to user mode with
+    hart: u64,   // hart that the registers were last loaded on, or NO_HART
+}
+
+impl FpState {
+    pub const fn new() -> Self {
+        Self {
+            regs: [0; 32],
+            fcsr: 0,
+            status: SSTATUS_FS_OFF,
+            hart: NO_HART,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.status != SSTATUS_FS_OFF
+    }
+
+    /// Change register `f{i}` of a process that has trapped. The hart is given the new value
+    /// on the way back to user mode.
+    pub fn set_reg(&mut self, i: usize, value: u64) {
+        self.regs[i] = value;
+        self.hart = NO_HART;
+    }
+}
+
+/// Save the registers of the process that just trapped if it modified them, and turn floating
+/// point off for the kernel
+pub fn save_user(state: &mut FpState) {
+    unsafe {
+        match csr_read_field!(sstatus, SSTATUS_FS) {
+            SSTATUS_FS_DIRTY => {
+                fp_save(state);
+                state.status = SSTATUS_FS_CLEAN;
+            }
+            SSTATUS_FS_OFF => {}
+            fs => state.status = fs,
+        }
+        csr_write_field!(sstatus, SSTATUS_FS, SSTATUS_FS_OFF);
+    }
+}
+
+/// Make sure the hart holds the registers of the process about to run, and set sstatus.FS
+/// for it
+pub fn restore_user(state: &mut FpState) {
+    if !state.enabled() {
+        unsafe {
+            csr_write_field!(sstatus, SSTATUS_FS, SSTATUS_FS_OFF);
+        }
+        return;
+    }
+    unsafe {
+        let hartid = reg_read!(tp);
+        let loaded = cpu!().fp_owner == state as *const FpState && state.hart == hartid;
+        if !loaded {
+            // loading needs FP on, and leaves FS Dirty
+            csr_write_field!(sstatus, SSTATUS_FS, SSTATUS_FS_CLEAN);
+            fp_restore(state);
+            cpu!().fp_owner = state;
+            state.hart = hartid;
+        }
+        csr_write_field!(sstatus, SSTATUS_FS, state.status);
+    }
+}
+
+/// Handle an illegal instruction exception from user mode, where `inst` is the instruction
+/// (from stval). Returns true if it was caused by the process using floating point for the
+/// first time, in which case it can be retried.
+pub fn handle_first_use(state: &mut FpState, inst: u64) -> bool {
+    if state.enabled() || !is_fp_instruction(inst as u32) {
+        return false;
+    }
+    state.regs = [0; 32];
+    state.fcsr = 0;
+    state.status = SSTATUS_FS_INITIAL;
+    state.hart = NO_HART;
+    true
+}
+
+/// Whether `inst` needs sstatus.FS to be on
+fn is_fp_instruction(inst: u32) -> bool {
+    if inst & 0x3 != 0x3 {
+        // compressed: only C.FLD and C.FSD, and C.FLDSP and C.FSDSP, on RV64
+        let quadrant = inst & 0x3;
+        let funct3 = (inst >> 13) & 0x7;
+        return matches!((quadrant, funct3), (0 | 2, 1 | 5));
+    }
+    let opcode = inst & 0x7f;
+    let width = (inst >> 12) & 0x7;
+    let csr = inst >> 20;
+    match opcode {
+        0x43 | 0x47 | 0x4b | 0x4f | 0x53 => true, // fused multiply-adds and OP-FP
+        // LOAD-FP and STORE-FP with the width of a scalar floating point type, the others
+        // being vector loads and stores
+        0x07 | 0x27 => matches!(width, 1..=4),
+        // csr instructions (but not ecall, ebreak, etc.) accessing fflags, frm or fcsr
+        0x73 => width != 0 && width != 4 && matches!(csr, 0x001..=0x003),
+        _ => false,
+    }
+}
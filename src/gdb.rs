@@ -0,0 +1,700 @@
+/// GDB remote serial protocol stub
+/// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+///
+/// A hart enters the stub when it executes ebreak, in the kernel or in a process, or when
+/// BREAK_KEY arrives on the UART. The stub then polls the UART for packets until gdb resumes
+/// the hart, so gdb can attach with `target remote` to QEMU's serial port. Only the hart that
+/// stopped is halted; a hart that stops while another one is in the stub waits its turn.
+///
+/// UART interrupts are turned off for receiving while a hart is in the stub, since they go to
+/// the boot hart, which would otherwise take gdb's packets for console input.
+///
+/// BREAK_KEY is also Ctrl-C for the process reading the console, which gets SIGINT for it
+/// while gdb isn't attached (see uart.rs). Booting with `gdb` on the kernel command line
+/// reserves it for the stub, so that gdb can attach at any time.
+///
+/// Memory is accessed through the page table in satp, which maps the kernel as well as the
+/// current process. Breakpoints are written over instructions through the direct map, where
+/// kernel text is made writeable for the duration of the write. There is no single-stepping in
+/// supervisor mode, so a step puts temporary breakpoints on every instruction that can execute
+/// next, and continues.
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    cpu,
+    csr::SATP_PPN,
+    csr_read, csr_read_field, csr_write, fdt, ipi,
+    kmem::{phys_to_virt, PAGE_SIZE},
+    misaligned::bits,
+    mmu::{PageTable, PTE_W},
+    println_sync,
+    proc::TrapFrame,
+    spinlock::Spinlock,
+    trap::KernelFrame,
+    uart,
+};
+
+/// Byte that stops the hart taking UART interrupts, which gdb sends when Ctrl-C is pressed
+pub const BREAK_KEY: u8 = 0x03;
+
+pub const SIGINT: u8 = 2;
+pub const SIGTRAP: u8 = 5;
+
+const MAX_PACKET: usize = 2048;
+const MAX_BREAKPOINTS: usize = 32;
+const NUM_REGS: usize = 33; // x0-x31 and the pc, in gdb's numbering
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u32 = 0x9002;
+
+/// Registers of the stopped hart
+pub struct Registers {
+    pub x: [u64; 32],
+    pub pc: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    len: usize,      // 2 for c.ebreak, 4 for ebreak
+    saved: [u8; 4],  // instruction bytes that the breakpoint replaced
+    temporary: bool, // placed by a step, and removed when the hart stops again
+}
+
+struct Stub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    packet: Packet,
+    reply: Packet,
+}
+
+static STUB: Spinlock<Stub> = Spinlock::new(Stub {
+    breakpoints: [None; MAX_BREAKPOINTS],
+    packet: Packet::new(),
+    reply: Packet::new(),
+});
+
+/// gdb is connected, and expects a stop reply when a hart stops. Kept out of STUB so that the
+/// UART interrupt handler can check it while a hart is in the stub.
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// What the hart does once a command has been handled
+enum Resume {
+    No,
+    Continue,
+    Step,
+    Detach,
+}
+
+/// Stop in the stub after a trap taken in the kernel. The stack pointer can't be changed,
+/// since the trap frame lives on the stack.
+pub fn enter_kernel(frame: &mut KernelFrame, signal: u8) {
+    let mut regs = Registers {
+        x: frame.regs,
+        pc: unsafe { csr_read!(sepc) },
+    };
+    regs.x[0] = 0;
+    regs.x[2] = frame.sp();
+    enter(&mut regs, signal);
+
+    let sp = frame.regs[2];
+    frame.regs = regs.x;
+    frame.regs[2] = sp;
+    unsafe {
+        csr_write!(sepc, regs.pc);
+    }
+}
+
+/// Stop in the stub after a trap taken in user mode
+pub fn enter_user(frame: &mut TrapFrame, signal: u8) {
+    let mut regs = Registers {
+        x: frame.regs,
+        pc: frame.epc,
+    };
+    regs.x[0] = 0;
+    enter(&mut regs, signal);
+    frame.regs = regs.x;
+    frame.epc = regs.pc;
+}
+
+/// Set by `gdb` on the kernel command line
+static mut RESERVED: bool = false;
+
+/// Read from the kernel command line whether BREAK_KEY is reserved for the stub
+pub fn init() {
+    let reserved = fdt::get()
+        .and_then(|fdt| fdt.find_node("chosen"))
+        .and_then(|chosen| chosen.property_str("bootargs"))
+        .is_some_and(|args| args.split_whitespace().any(|arg| arg == "gdb"));
+    unsafe {
+        RESERVED = reserved;
+    }
+    if reserved {
+        debug!("BREAK_KEY is reserved for gdb");
+    }
+}
+
+/// Whether gdb is connected to the stub
+pub fn attached() -> bool {
+    ATTACHED.load(Ordering::Relaxed)
+}
+
+/// Whether BREAK_KEY should stop in the stub rather than interrupt a process: gdb is attached,
+/// or the key is reserved for it
+pub fn wants_break_key() -> bool {
+    let reserved = unsafe { RESERVED };
+    reserved || attached()
+}
+
+/// Stop wherever the hart was when BREAK_KEY arrived
+pub fn break_in() {
+    unsafe {
+        let frame = cpu!().kernel_frame;
+        if frame.is_null() {
+            // the interrupt was taken in user mode
+            enter_user(crate::proc!().frame.assume_init_mut(), SIGINT);
+        } else {
+            enter_kernel(&mut *frame, SIGINT);
+        }
+    }
+}
+
+/// Talk to gdb until it resumes the hart
+pub fn enter(regs: &mut Registers, signal: u8) {
+    let mut stub_guard = STUB.lock();
+    let stub = &mut *stub_guard;
+    let pt = current_table();
+    uart::set_rx_interrupts(false);
+
+    stub.remove_temporary(pt);
+    let mut stop_pc = regs.pc;
+
+    if attached() {
+        stub.reply.stop(signal);
+        send(&stub.reply);
+    } else {
+        println_sync!("Waiting for gdb at 0x{:x}", stop_pc);
+    }
+
+    loop {
+        receive(&mut stub.packet);
+        ATTACHED.store(true, Ordering::Relaxed);
+        stub.reply.clear();
+        let packet = stub.packet;
+        let resume = stub.handle(pt, regs, packet.as_bytes(), signal);
+        if let Resume::No = resume {
+            send(&stub.reply);
+            continue;
+        }
+
+        // an ebreak compiled into the code, rather than one of ours, is skipped when resuming
+        let skip = match stub.find(regs.pc) {
+            Some(_) => 0,
+            None if regs.pc == stop_pc => ebreak_len(pt, regs.pc),
+            None => 0,
+        };
+        regs.pc += skip;
+        match resume {
+            Resume::Step if skip != 0 => {
+                // stepping over an ebreak is done by skipping it
+                stop_pc = regs.pc;
+                stub.reply.stop(SIGTRAP);
+                send(&stub.reply);
+                continue;
+            }
+            Resume::Step => {
+                for addr in next_pcs(pt, regs).into_iter().flatten() {
+                    stub.insert(pt, addr, 2, true);
+                }
+            }
+            Resume::Detach => {
+                stub.remove_all(pt);
+                ATTACHED.store(false, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        uart::set_rx_interrupts(true);
+        // Breakpoints may have been written over code that other harts run. The lock goes
+        // first, since they may be waiting for it with interrupts off.
+        drop(stub_guard);
+        ipi::call_all(fence_i, 0);
+        return;
+    }
+}
+
+impl Stub {
+    /// Carry out the command in `packet`, leaving the reply (if any) in self.reply
+    fn handle(
+        &mut self,
+        pt: &mut PageTable,
+        regs: &mut Registers,
+        packet: &[u8],
+        signal: u8,
+    ) -> Resume {
+        let Some((&command, args)) = packet.split_first() else {
+            return Resume::No;
+        };
+        let reply = &mut self.reply;
+        match command {
+            b'?' => reply.stop(signal),
+            b'g' => {
+                for n in 0..NUM_REGS {
+                    reply.push_u64(*register(regs, n).unwrap());
+                }
+            }
+            b'G' => {
+                if args.len() < NUM_REGS * 16 {
+                    reply.error(1);
+                } else {
+                    for n in 0..NUM_REGS {
+                        let value = parse_u64(&args[n * 16..(n + 1) * 16]);
+                        *register(regs, n).unwrap() = value.unwrap_or(0);
+                    }
+                    regs.x[0] = 0;
+                    reply.ok();
+                }
+            }
+            b'p' => match parse_hex(args).and_then(|n| register(regs, n as usize)) {
+                Some(value) => reply.push_u64(*value),
+                None => reply.error(1),
+            },
+            b'P' => {
+                let reg = split(args, b'=').and_then(|(n, value)| {
+                    Some((register(regs, parse_hex(n)? as usize)?, parse_u64(value)?))
+                });
+                match reg {
+                    Some((reg, value)) => {
+                        *reg = value;
+                        regs.x[0] = 0;
+                        reply.ok();
+                    }
+                    None => reply.error(1),
+                }
+            }
+            b'm' => match parse_range(args) {
+                Some((addr, len)) => {
+                    let len = len.min(MAX_PACKET as u64 / 2);
+                    for i in 0..len {
+                        match read_byte(pt, addr + i) {
+                            Some(byte) => reply.push_byte(byte),
+                            None if i == 0 => reply.error(14), // EFAULT
+                            None => break,
+                        }
+                    }
+                }
+                None => reply.error(1),
+            },
+            b'M' => {
+                let write = split(args, b':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    (data.len() as u64 == 2 * len).then_some((addr, data))
+                });
+                match write {
+                    Some((addr, data)) => {
+                        let written = data.chunks(2).enumerate().all(|(i, hex)| {
+                            parse_hex(hex).is_some_and(|b| write_byte(pt, addr + i as u64, b as u8))
+                        });
+                        if written {
+                            reply.ok();
+                        } else {
+                            reply.error(14);
+                        }
+                    }
+                    None => reply.error(1),
+                }
+            }
+            b'Z' | b'z' => {
+                // only software breakpoints (type 0) are supported
+                let Some(rest) = args.strip_prefix(b"0,") else {
+                    return Resume::No;
+                };
+                let done = match parse_range(rest) {
+                    Some((addr, len @ (2 | 4))) if command == b'Z' => {
+                        self.insert(pt, addr, len as usize, false)
+                    }
+                    Some((addr, _)) => self.remove(pt, addr),
+                    None => false,
+                };
+                if done {
+                    self.reply.ok();
+                } else {
+                    self.reply.error(1);
+                }
+            }
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    regs.pc = addr;
+                }
+                return match command {
+                    b'c' => Resume::Continue,
+                    _ => Resume::Step,
+                };
+            }
+            b'D' => {
+                reply.ok();
+                send(reply);
+                return Resume::Detach;
+            }
+            b'k' => return Resume::Detach,
+            b'H' => reply.ok(),
+            b'q' if args.starts_with(b"Supported") => {
+                let _ =
+                    core::fmt::Write::write_fmt(reply, format_args!("PacketSize={:x}", MAX_PACKET));
+            }
+            b'q' if args == b"Attached" => reply.push(b'1'),
+            _ => {} // an empty reply means the command isn't supported
+        }
+        Resume::No
+    }
+
+    fn find(&self, addr: u64) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .position(|bp| bp.is_some_and(|bp| bp.addr == addr))
+    }
+
+    /// Replace the instruction at `addr` with a breakpoint of `len` bytes
+    fn insert(&mut self, pt: &mut PageTable, addr: u64, len: usize, temporary: bool) -> bool {
+        if self.find(addr).is_some() {
+            return true;
+        }
+        let Some(slot) = self.breakpoints.iter_mut().find(|bp| bp.is_none()) else {
+            return false;
+        };
+        let mut saved = [0u8; 4];
+        for (i, byte) in saved[..len].iter_mut().enumerate() {
+            match read_byte(pt, addr + i as u64) {
+                Some(b) => *byte = b,
+                None => return false,
+            }
+        }
+        let ebreak = if len == 2 { C_EBREAK } else { EBREAK };
+        for (i, &byte) in ebreak.to_le_bytes()[..len].iter().enumerate() {
+            if !write_byte(pt, addr + i as u64, byte) {
+                // put back what was already overwritten
+                for j in 0..i {
+                    write_byte(pt, addr + j as u64, saved[j]);
+                }
+                return false;
+            }
+        }
+        *slot = Some(Breakpoint {
+            addr,
+            len,
+            saved,
+            temporary,
+        });
+        true
+    }
+
+    fn remove(&mut self, pt: &mut PageTable, addr: u64) -> bool {
+        let Some(i) = self.find(addr) else {
+            return false;
+        };
+        let bp = self.breakpoints[i].take().unwrap();
+        (0..bp.len).all(|j| write_byte(pt, bp.addr + j as u64, bp.saved[j]))
+    }
+
+    fn remove_temporary(&mut self, pt: &mut PageTable) {
+        for i in 0..MAX_BREAKPOINTS {
+            if let Some(bp) = self.breakpoints[i].filter(|bp| bp.temporary) {
+                self.remove(pt, bp.addr);
+            }
+        }
+    }
+
+    fn remove_all(&mut self, pt: &mut PageTable) {
+        for i in 0..MAX_BREAKPOINTS {
+            if let Some(bp) = self.breakpoints[i] {
+                self.remove(pt, bp.addr);
+            }
+        }
+    }
+}
+
+/// gdb register `n`
+fn register(regs: &mut Registers, n: usize) -> Option<&mut u64> {
+    match n {
+        0..=31 => Some(&mut regs.x[n]),
+        32 => Some(&mut regs.pc),
+        _ => None,
+    }
+}
+
+/// Addresses of the instructions that can execute after the one at the pc
+fn next_pcs(pt: &PageTable, regs: &Registers) -> [Option<u64>; 2] {
+    let pc = regs.pc;
+    let Some(inst) = fetch(pt, pc) else {
+        return [None, None];
+    };
+    let x = |r: u32| regs.x[r as usize];
+
+    if inst & 0b11 == 0b11 {
+        let next = pc + 4;
+        let i_imm = (inst as i32 >> 20) as i64 as u64;
+        let j_imm = ((inst as i32 >> 31) << 20) as u32
+            | bits(inst, 19, 12) << 12
+            | bits(inst, 20, 20) << 11
+            | bits(inst, 30, 21) << 1;
+        let b_imm = ((inst as i32 >> 31) << 12) as u32
+            | bits(inst, 7, 7) << 11
+            | bits(inst, 30, 25) << 5
+            | bits(inst, 11, 8) << 1;
+        match inst & 0x7f {
+            0x6f => [Some(pc.wrapping_add(j_imm as i32 as u64)), None], // jal
+            0x67 => [Some(x(bits(inst, 19, 15)).wrapping_add(i_imm) & !1), None], // jalr
+            0x63 => [Some(next), Some(pc.wrapping_add(b_imm as i32 as u64))], // branches
+            _ => [Some(next), None],
+        }
+    } else {
+        let next = pc + 2;
+        let rs1 = bits(inst, 11, 7);
+        let cj_imm = bits(inst, 12, 12) << 11
+            | bits(inst, 11, 11) << 4
+            | bits(inst, 10, 9) << 8
+            | bits(inst, 8, 8) << 10
+            | bits(inst, 7, 7) << 6
+            | bits(inst, 6, 6) << 7
+            | bits(inst, 5, 3) << 1
+            | bits(inst, 2, 2) << 5;
+        let cb_imm = bits(inst, 12, 12) << 8
+            | bits(inst, 11, 10) << 3
+            | bits(inst, 6, 5) << 6
+            | bits(inst, 4, 3) << 1
+            | bits(inst, 2, 2) << 5;
+        // sign extend the 12 and 9 bit offsets
+        let cj_imm = ((cj_imm << 20) as i32 >> 20) as i64 as u64;
+        let cb_imm = ((cb_imm << 23) as i32 >> 23) as i64 as u64;
+        match (inst & 0b11, bits(inst, 15, 13)) {
+            (0b01, 0b101) => [Some(pc.wrapping_add(cj_imm)), None], // c.j
+            (0b01, 0b110 | 0b111) => [Some(next), Some(pc.wrapping_add(cb_imm))], // c.beqz, c.bnez
+            // c.jr and c.jalr
+            (0b10, 0b100) if bits(inst, 6, 2) == 0 && rs1 != 0 => [Some(x(rs1) & !1), None],
+            _ => [Some(next), None],
+        }
+    }
+}
+
+/// Fetch the (possibly compressed) instruction at `addr`
+fn fetch(pt: &PageTable, addr: u64) -> Option<u32> {
+    let half = |addr| {
+        Some(u16::from_le_bytes([
+            read_byte(pt, addr)?,
+            read_byte(pt, addr + 1)?,
+        ]))
+    };
+    let low = half(addr)? as u32;
+    if low & 0b11 != 0b11 {
+        return Some(low);
+    }
+    Some(low | (half(addr + 2)? as u32) << 16)
+}
+
+/// Length of the instruction at `addr` if it is an ebreak, or 0
+fn ebreak_len(pt: &PageTable, addr: u64) -> u64 {
+    match fetch(pt, addr) {
+        Some(EBREAK) => 4,
+        Some(C_EBREAK) => 2,
+        _ => 0,
+    }
+}
+
+fn current_table() -> &'static mut PageTable {
+    unsafe {
+        let root = csr_read_field!(satp, SATP_PPN) * PAGE_SIZE;
+        &mut *(phys_to_virt(root) as *mut PageTable)
+    }
+}
+
+fn read_byte(pt: &PageTable, vaddr: u64) -> Option<u8> {
+    let paddr = pt.lookup(vaddr)?;
+    Some(unsafe { (phys_to_virt(paddr) as *const u8).read_volatile() })
+}
+
+fn write_byte(pt: &mut PageTable, vaddr: u64, byte: u8) -> bool {
+    let Some(paddr) = pt.lookup(vaddr) else {
+        return false;
+    };
+    // the kernel image is mapped in the direct map with the permissions of its sections
+    let alias = phys_to_virt(paddr);
+    let Some((pte, _)) = pt.lookup_pte_mut(alias) else {
+        return false;
+    };
+    let permissions = pte.permissions();
+    unsafe {
+        if permissions & PTE_W == 0 {
+            pte.set_permissions(permissions | PTE_W);
+            asm!("sfence.vma {}, zero", in(reg) alias);
+        }
+        (alias as *mut u8).write_volatile(byte);
+        if permissions & PTE_W == 0 {
+            pte.set_permissions(permissions);
+            asm!("sfence.vma {}, zero", in(reg) alias);
+        }
+    }
+    true
+}
+
+fn getc() -> u8 {
+    loop {
+        if let Some(byte) = uart::get() {
+            return byte;
+        }
+        // other harts may be waiting for this one, such as to flush its TLB
+        ipi::handle();
+    }
+}
+
+/// Make instructions written as breakpoints visible to the hart's instruction fetches
+fn fence_i(_: usize) {
+    unsafe {
+        asm!("fence.i");
+    }
+}
+
+/// Wait for a packet with a valid checksum, acknowledging it
+fn receive(packet: &mut Packet) {
+    loop {
+        // skip acks and anything else between packets
+        while getc() != b'$' {}
+        packet.clear();
+        let mut sum = 0u8;
+        loop {
+            match getc() {
+                b'#' => break,
+                byte => {
+                    sum = sum.wrapping_add(byte);
+                    packet.push(byte);
+                }
+            }
+        }
+        let checksum = parse_hex(&[getc(), getc()]);
+        if checksum == Some(sum as u64) {
+            uart::put_sync(b'+');
+            return;
+        }
+        uart::put_sync(b'-');
+    }
+}
+
+/// Send a packet, until gdb acknowledges it
+fn send(packet: &Packet) {
+    let sum = packet
+        .as_bytes()
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    loop {
+        uart::put_sync(b'$');
+        packet
+            .as_bytes()
+            .iter()
+            .for_each(|&byte| uart::put_sync(byte));
+        uart::put_sync(b'#');
+        uart::put_sync(HEX[sum as usize >> 4]);
+        uart::put_sync(HEX[sum as usize & 0xf]);
+        loop {
+            match getc() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+#[derive(Clone, Copy)]
+struct Packet {
+    buf: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Packet {
+    const fn new() -> Self {
+        Self {
+            buf: [0; MAX_PACKET],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Append `byte`, dropping it if the packet is full
+    fn push(&mut self, byte: u8) {
+        if self.len < MAX_PACKET {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        self.push(HEX[byte as usize >> 4]);
+        self.push(HEX[byte as usize & 0xf]);
+    }
+
+    /// Append a register value, which gdb expects in target (little endian) byte order
+    fn push_u64(&mut self, value: u64) {
+        value
+            .to_le_bytes()
+            .into_iter()
+            .for_each(|b| self.push_byte(b));
+    }
+
+    fn ok(&mut self) {
+        self.buf[..2].copy_from_slice(b"OK");
+        self.len = 2;
+    }
+
+    fn error(&mut self, errno: u8) {
+        self.clear();
+        self.push(b'E');
+        self.push_byte(errno);
+    }
+
+    fn stop(&mut self, signal: u8) {
+        self.clear();
+        self.push(b'S');
+        self.push_byte(signal);
+    }
+}
+
+impl core::fmt::Write for Packet {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        s.bytes().for_each(|b| self.push(b));
+        Ok(())
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter().try_fold(0, |value, &c| {
+        Some(value << 4 | (c as char).to_digit(16)? as u64)
+    })
+}
+
+/// Parse a register value in target byte order
+fn parse_u64(s: &[u8]) -> Option<u64> {
+    Some(parse_hex(s)?.swap_bytes() >> (64 - 4 * s.len() as u32))
+}
+
+fn split(s: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let i = s.iter().position(|&c| c == separator)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+/// Parse "addr,length"
+fn parse_range(s: &[u8]) -> Option<(u64, u64)> {
+    let (addr, len) = split(s, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
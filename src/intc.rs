@@ -0,0 +1,111 @@
+/// Interrupt controller selection
+///
+/// Both the PLIC and the AIA (APLIC and IMSIC) raise supervisor external interrupts that are
+/// claimed and completed by IRQ number, so the rest of the kernel doesn't need to know which
+/// one it is running on. `init` picks the AIA if the device tree describes one, and otherwise
+/// the PLIC at PLIC_BASE.
+use crate::{
+    aia::Aia,
+    fdt,
+    kmem::{phys_to_virt, PLIC_BASE},
+    plic::{self, PlicPrivilege},
+};
+
+pub enum Controller {
+    Plic,
+    Aia(Aia),
+}
+
+static mut CONTROLLER: Controller = Controller::Plic;
+
+fn controller() -> &'static Controller {
+    unsafe { &CONTROLLER }
+}
+
+pub fn init() {
+    if let Some(aia) = fdt::get().and_then(Aia::probe) {
+        unsafe {
+            CONTROLLER = Controller::Aia(aia);
+        }
+    }
+    if let Controller::Aia(aia) = controller() {
+        aia.init();
+    }
+    debug!("Using {} interrupt controller", name());
+}
+
+pub fn name() -> &'static str {
+    match controller() {
+        Controller::Plic => "PLIC",
+        Controller::Aia(_) => "AIA",
+    }
+}
+
+/// Let the current hart take any interrupt that is enabled for it
+pub fn init_hart() {
+    match controller() {
+        Controller::Plic => plic::set_threshold(PlicPrivilege::Supervisor, 0),
+        Controller::Aia(aia) => aia.init_hart(),
+    }
+}
+
+/// The AIA has no per-source priorities: lower identities always win
+pub fn set_priority(irq: u32, priority: u32) {
+    match controller() {
+        Controller::Plic => plic::set_priority(irq, priority),
+        Controller::Aia(_) => {}
+    }
+}
+
+/// Route `irq` to hart `hartid`. With the AIA, only the last hart an IRQ is enabled on
+/// receives it.
+pub fn enable(hartid: usize, irq: u32) {
+    match controller() {
+        Controller::Plic => plic::enable_on(hartid, PlicPrivilege::Supervisor, irq),
+        Controller::Aia(aia) => aia.enable(hartid, irq),
+    }
+}
+
+/// Claim the next pending IRQ of the current hart, or 0 if there is none
+pub fn claim() -> u32 {
+    match controller() {
+        Controller::Plic => plic::claim(PlicPrivilege::Supervisor),
+        Controller::Aia(aia) => aia.claim(),
+    }
+}
+
+pub fn complete(irq: u32) {
+    match controller() {
+        Controller::Plic => plic::complete(PlicPrivilege::Supervisor, irq),
+        Controller::Aia(aia) => aia.complete(irq),
+    }
+}
+
+/// Physical (base, size) of the registers that need to be mapped
+pub fn mmio_regions() -> impl Iterator<Item = (u64, u64)> {
+    let regions = match controller() {
+        Controller::Plic => [Some((PLIC_BASE, 0x40_0000)), None, None],
+        Controller::Aia(aia) => aia.mmio_regions(),
+    };
+    regions.into_iter().flatten()
+}
+
+/// Physical address that a device writes an IRQ number to in order to raise it on `hartid`.
+/// Only the AIA supports MSIs.
+pub fn msi_address(hartid: usize) -> Option<u64> {
+    match controller() {
+        Controller::Plic => None,
+        Controller::Aia(aia) => Some(aia.msi_address(hartid)),
+    }
+}
+
+/// Raise `irq` on `hartid` by writing to its MSI address, as a device would
+pub fn send_msi(hartid: usize, irq: u32) -> bool {
+    match msi_address(hartid) {
+        Some(addr) => {
+            unsafe { (phys_to_virt(addr) as *mut u32).write_volatile(irq) }
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,121 @@
+/// Inter-processor interrupts and cross-hart function calls
+///
+/// A hart is interrupted by setting its msip bit in the CLINT. That raises a machine software
+/// interrupt, which machine_vec (see trap.s) turns into a supervisor software interrupt. Each
+/// hart has a queue of messages sent to it, which `handle_intr` runs when the interrupt
+/// arrives.
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu::{self, MAX_HARTS},
+    csr::SIP_SSIP,
+    csr_clear_bits, csr_write,
+    kmem::{phys_to_virt, CLINT_BASE},
+    reg_read,
+    spinlock::Spinlock,
+    util::CircularBuffer,
+};
+
+const QUEUE_SIZE: usize = 16;
+
+pub type CallFn = fn(usize);
+
+/// Request to run `func(arg)`, after which `pending` is decremented
+#[derive(Clone, Copy)]
+struct Message {
+    func: CallFn,
+    arg: usize,
+    pending: *const AtomicUsize,
+}
+
+// the sender waits for `pending` to reach 0, so it outlives the message
+unsafe impl Send for Message {}
+
+type Queue = Spinlock<CircularBuffer<Message, QUEUE_SIZE>>;
+
+const EMPTY_QUEUE: Queue = Spinlock::new(CircularBuffer::new());
+static QUEUES: [Queue; MAX_HARTS] = [EMPTY_QUEUE; MAX_HARTS];
+
+/// Run `func(arg)` on hart `hartid` and wait for it to finish
+pub fn call(hartid: usize, func: CallFn, arg: usize) {
+    call_many(1 << hartid, func, arg);
+}
+
+/// Run `func(arg)` on every online hart in the bitmask `harts` (including the current one, if
+/// it is set) and wait for all of them to finish
+pub fn call_many(harts: u64, func: CallFn, arg: usize) {
+    let me = unsafe { reg_read!(tp) } as usize;
+    let harts = harts & cpu::online();
+    let others = harts & !(1 << me);
+
+    let pending = AtomicUsize::new(others.count_ones() as usize);
+    let message = Message {
+        func,
+        arg,
+        pending: &pending,
+    };
+    for hartid in 0..MAX_HARTS {
+        if others & (1 << hartid) != 0 {
+            send(hartid, message);
+        }
+    }
+    if harts & (1 << me) != 0 {
+        func(arg);
+    }
+    while pending.load(Ordering::Acquire) != 0 {
+        // the other harts may be waiting on us in turn
+        handle();
+        spin_loop();
+    }
+}
+
+/// Run `func(arg)` on every online hart, including the current one
+pub fn call_all(func: CallFn, arg: usize) {
+    call_many(u64::MAX, func, arg);
+}
+
+fn send(hartid: usize, message: Message) {
+    while QUEUES[hartid].lock().write(message).is_none() {
+        // the queue is full, and its owner may be busy sending to us
+        handle();
+        spin_loop();
+    }
+    raise(hartid);
+}
+
+/// Interrupt hart `hartid` without sending it anything, such as to get it out of wfi
+pub fn kick(hartid: usize) {
+    raise(hartid);
+}
+
+/// Set the msip bit of hart `hartid`
+fn raise(hartid: usize) {
+    let msip = phys_to_virt(CLINT_BASE + 4 * hartid as u64) as *mut u32;
+    unsafe { msip.write_volatile(1) }
+}
+
+/// Handle a supervisor software interrupt
+pub fn handle_intr() {
+    unsafe {
+        // clear it first, so that a message queued while handling raises it again
+        csr_clear_bits!(sip, SIP_SSIP);
+    }
+    handle();
+}
+
+/// Run every message queued for the current hart, for code that polls with interrupts off
+pub fn handle() {
+    let me = unsafe { reg_read!(tp) } as usize;
+    loop {
+        // don't hold the lock while running the function, which may send messages itself
+        let message = QUEUES[me].lock().read();
+        let Some(message) = message else {
+            return;
+        };
+        (message.func)(message.arg);
+        unsafe { (*message.pending).fetch_sub(1, Ordering::Release) };
+    }
+}
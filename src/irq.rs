@@ -0,0 +1,135 @@
+/// Routing of external interrupt sources to driver handlers
+///
+/// Drivers claim an IRQ with `register`, which also sets its priority and enables it on the
+/// chosen harts. IRQs registered before `init` has picked an interrupt controller are
+/// programmed once it has. `handle_external` is called by the trap handler on a supervisor
+/// external interrupt and dispatches every pending IRQ to its handler.
+use crate::{cpu::MAX_HARTS, intc, reg_read};
+
+/// Number of interrupt sources the PLIC (or APLIC) can have, including the reserved source 0
+pub const NUM_IRQS: usize = 1024;
+
+/// Priorities above the threshold set by `init_hart` can interrupt a hart
+pub const MAX_PRIORITY: u32 = 7;
+
+/// Called with the IRQ that fired
+pub type Handler = fn(u32);
+
+#[derive(Debug)]
+pub enum RegisterError {
+    InvalidIrq,
+    InvalidPriority,
+    InvalidTarget,
+    AlreadyRegistered,
+}
+
+#[derive(Clone, Copy)]
+struct Route {
+    handler: Handler,
+    priority: u32,
+    harts: u64,
+}
+
+static mut ROUTES: [Option<Route>; NUM_IRQS] = [None; NUM_IRQS];
+
+static mut INITIALIZED: bool = false;
+
+/// Number of times each IRQ has been handled on each hart. A hart only ever updates its own
+/// column.
+static mut COUNTS: [[u64; MAX_HARTS]; NUM_IRQS] = [[0; MAX_HARTS]; NUM_IRQS];
+
+/// Pick an interrupt controller and program the IRQs registered so far
+pub fn init() {
+    intc::init();
+    unsafe {
+        INITIALIZED = true;
+        for irq in 1..NUM_IRQS as u32 {
+            if let Some(route) = ROUTES[irq as usize] {
+                program(irq, route);
+            }
+        }
+    }
+    init_hart();
+}
+
+/// Let any enabled IRQ interrupt the current hart
+pub fn init_hart() {
+    intc::init_hart();
+}
+
+/// Route `irq` to `handler` with the given priority (1 to MAX_PRIORITY). `harts` is a bitmask
+/// of the harts that may take the interrupt, bit n standing for hart n.
+pub fn register(
+    irq: u32,
+    handler: Handler,
+    priority: u32,
+    harts: u64,
+) -> Result<(), RegisterError> {
+    if irq == 0 || irq as usize >= NUM_IRQS {
+        return Err(RegisterError::InvalidIrq);
+    }
+    if priority == 0 || priority > MAX_PRIORITY {
+        return Err(RegisterError::InvalidPriority);
+    }
+    if harts == 0 || harts >> MAX_HARTS != 0 {
+        return Err(RegisterError::InvalidTarget);
+    }
+    let route = Route {
+        handler,
+        priority,
+        harts,
+    };
+    unsafe {
+        if ROUTES[irq as usize].is_some() {
+            return Err(RegisterError::AlreadyRegistered);
+        }
+        ROUTES[irq as usize] = Some(route);
+        if INITIALIZED {
+            program(irq, route);
+        }
+    }
+    Ok(())
+}
+
+fn program(irq: u32, route: Route) {
+    intc::set_priority(irq, route.priority);
+    for hartid in 0..MAX_HARTS {
+        if route.harts & (1 << hartid) != 0 {
+            intc::enable(hartid, irq);
+        }
+    }
+}
+
+/// Handle external interrupts until there are none left
+pub fn handle_external() {
+    loop {
+        let irq = intc::claim();
+        if irq == 0 {
+            // no pending interrupts
+            return;
+        }
+        dispatch(irq);
+        intc::complete(irq);
+    }
+}
+
+fn dispatch(irq: u32) {
+    unsafe {
+        let hartid = reg_read!(tp) as usize;
+        COUNTS[irq as usize][hartid] += 1;
+        match ROUTES[irq as usize] {
+            Some(route) => (route.handler)(irq),
+            None => debug!("Unexpected PLIC IRQ {}", irq),
+        }
+    }
+}
+
+/// Number of times `irq` has been handled on hart `hartid`
+pub fn count(irq: u32, hartid: usize) -> u64 {
+    unsafe { COUNTS[irq as usize][hartid] }
+}
+
+/// Number of times `irq` has been handled across all harts
+pub fn total(irq: u32) -> u64 {
+    (0..MAX_HARTS).map(|hartid| count(irq, hartid)).sum()
+}
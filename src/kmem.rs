@@ -2,7 +2,9 @@
 ///
 /// The kernel heap is divided into 4K pages and managed by a free list composed of the pages
 /// themselves. Pages can be allocated and freed one at a time using `kalloc()` and `kfree()`.
-use core::ptr::null_mut;
+use core::{ops::Range, ptr::null_mut};
+
+use crate::fdt;
 
 // expose memory layout constants defined in mem.s
 extern "C" {
@@ -16,11 +18,13 @@ extern "C" {
     pub static BSS_END: u64;
     pub static STACK_START: u64;
     pub static STACK_END: u64;
+    pub static HART_STACK_SIZE: u64;
     pub static HEAP_START: u64;
     pub static HEAP_END: u64;
 }
 
 // more memory layout constants, found in the .dts file generated by `./qemu-ryos.sh -d`
+// these are physical addresses, so use phys_to_virt() to access them
 pub const CLINT_BASE: u64 = 0x0200_0000;
 pub const PLIC_BASE: u64 = 0x0C00_0000;
 pub const UART_BASE: u64 = 0x1000_0000;
@@ -35,6 +39,34 @@ pub const VIRTIO_BASES: [u64; 8] = [
     0x1000_1000,
 ];
 
+/// Start of the direct map. All of physical memory is mapped linearly at `PHYS_OFFSET + paddr`,
+/// and the kernel image itself is linked to run inside it. Must match boot.s and virt.ld.
+pub const PHYS_OFFSET: u64 = 0xffff_ffc0_0000_0000;
+
+/// Part of the upper half, outside the direct map, where kernel thread stacks are mapped (see
+/// kthread.rs). It is covered by a single root page table entry.
+pub const KSTACK_REGION: Range<u64> = 0xffff_fff0_0000_0000..0xffff_fff0_4000_0000;
+
+/// Get the virtual address of physical address `paddr` in the direct map
+pub const fn phys_to_virt(paddr: u64) -> u64 {
+    paddr + PHYS_OFFSET
+}
+
+/// Get the physical address backing `vaddr`, which must lie in the direct map. This includes
+/// anything in the kernel image and every page handed out by `kalloc()`.
+pub const fn virt_to_phys(vaddr: u64) -> u64 {
+    assert!(vaddr >= PHYS_OFFSET);
+    vaddr - PHYS_OFFSET
+}
+
+/// The boot stack belonging to hart `hartid` (see boot.s)
+pub fn hart_stack(hartid: u64) -> Range<u64> {
+    unsafe {
+        let start = STACK_START + hartid * HART_STACK_SIZE;
+        start..start + HART_STACK_SIZE
+    }
+}
+
 pub const PAGE_SIZE: u64 = 4096;
 pub const PAGE_OFFSET_MASK: u64 = PAGE_SIZE - 1;
 pub const PAGE_NUMBER_MASK: u64 = !PAGE_OFFSET_MASK;
@@ -78,16 +110,30 @@ static mut INITIALIZED: bool = false;
 pub fn init() {
     unsafe {
         FREE_LIST = null_mut();
+
+        // QEMU places the device tree blob at the end of RAM, which overlaps the heap
+        let reserved = match fdt::get() {
+            Some(fdt) => page_floor!(fdt.addr())..page_ceil!(fdt.addr() + fdt.total_size()),
+            None => 0..0,
+        };
+
         let mut ptr = page_ceil!(HEAP_START) as *mut FreePage;
         let heap_end: *mut FreePage = HEAP_END as *mut FreePage;
         let mut num_heap_pages: u32 = 0;
+        let mut num_reserved_pages: u32 = 0;
         while ptr.byte_add(PAGE_SIZE as usize) <= heap_end {
-            (*ptr).next = FREE_LIST;
-            FREE_LIST = ptr;
+            if reserved.contains(&(ptr as u64)) {
+                num_reserved_pages += 1;
+            } else {
+                (*ptr).next = FREE_LIST;
+                FREE_LIST = ptr;
+                num_heap_pages += 1;
+            }
             ptr = ptr.byte_add(PAGE_SIZE as usize);
-            num_heap_pages += 1;
         }
-        assert!(num_heap_pages == ((HEAP_END - HEAP_START) / PAGE_SIZE) as u32);
+        assert!(
+            num_heap_pages + num_reserved_pages == ((HEAP_END - HEAP_START) / PAGE_SIZE) as u32
+        );
 
         debug!("Initializing page allocator");
         debug!("  text: 0x{:x}..0x{:x}", TEXT_START, TEXT_END);
@@ -97,6 +143,12 @@ pub fn init() {
         debug!(" stack: 0x{:x}..0x{:x}", STACK_START, STACK_END);
         debug!("  heap: 0x{:x}..0x{:x}", HEAP_START, HEAP_END);
         debug!("        ({} pages)", num_heap_pages);
+        if num_reserved_pages > 0 {
+            debug!(
+                "        ({} pages reserved for device tree at 0x{:x})",
+                num_reserved_pages, reserved.start
+            );
+        }
         INITIALIZED = true;
     }
 }
@@ -0,0 +1,195 @@
+/// Kernel symbol table, used to print addresses as `function+offset`
+///
+/// The table lives in the .ksyms section and is filled in after linking by scripts/ksyms.sh,
+/// which documents the format. If that step was skipped, the section is all zeros and every
+/// lookup fails, so addresses are printed without symbols.
+use core::fmt::{self, Write};
+
+use crate::kmem::{TEXT_END, TEXT_START};
+
+extern "C" {
+    static KSYMS: u8; // defined in ksyms.s
+}
+
+const KSYMS_MAGIC: u32 = 0x4d59_534b; // "KSYM" in little-endian
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+struct Table {
+    base: *const u8,
+    count: usize,
+    strtab: usize,
+}
+
+impl Table {
+    fn get() -> Option<Self> {
+        let base = unsafe { &KSYMS as *const u8 };
+        if read_u32(base, 0) != KSYMS_MAGIC {
+            return None;
+        }
+        Some(Table {
+            base,
+            count: read_u32(base, 4) as usize,
+            strtab: read_u32(base, 8) as usize,
+        })
+    }
+
+    fn addr(&self, index: usize) -> u64 {
+        let entry = HEADER_SIZE + index * ENTRY_SIZE;
+        read_u32(self.base, entry) as u64 | (read_u32(self.base, entry + 4) as u64) << 32
+    }
+
+    fn name(&self, index: usize) -> &'static str {
+        let entry = HEADER_SIZE + index * ENTRY_SIZE;
+        let offset = read_u32(self.base, entry + 8) as usize;
+        let len = read_u32(self.base, entry + 12) as usize;
+        unsafe {
+            let bytes = core::slice::from_raw_parts(self.base.add(self.strtab + offset), len);
+            core::str::from_utf8(bytes).unwrap_or("?")
+        }
+    }
+}
+
+fn read_u32(base: *const u8, offset: usize) -> u32 {
+    unsafe { (base.add(offset) as *const u32).read() }
+}
+
+/// Find the function containing `addr`. Returns its mangled name and the offset of `addr`
+/// from the start of the function.
+pub fn lookup(addr: u64) -> Option<(&'static str, u64)> {
+    if unsafe { !(TEXT_START..TEXT_END).contains(&addr) } {
+        return None;
+    }
+    let table = Table::get()?;
+
+    // binary search for the last symbol at or below addr
+    let (mut lo, mut hi) = (0, table.count);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if table.addr(mid) <= addr {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    if lo == 0 {
+        return None;
+    }
+    let index = lo - 1;
+    Some((table.name(index), addr - table.addr(index)))
+}
+
+/// Displays an address followed by `function+0xoffset` if the function is known
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:016x}", self.0)?;
+        if let Some((name, offset)) = lookup(self.0) {
+            write!(f, " {}+0x{:x}", Demangle(name), offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Displays a symbol name mangled with rustc's legacy scheme, e.g.
+/// `_ZN4ryos4trap11kernel_trap17h0123456789abcdefE` as `ryos::trap::kernel_trap`.
+/// Anything else is displayed as is.
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(path) = self.0.strip_prefix("_ZN") else {
+            return f.write_str(self.0);
+        };
+        // check the whole path before writing anything, in case it is malformed
+        if Components(path).any(|ident| ident.is_none()) {
+            return f.write_str(self.0);
+        }
+        for (i, ident) in Components(path).map(Option::unwrap).enumerate() {
+            if i > 0 {
+                f.write_str("::")?;
+            }
+            write_ident(f, ident)?;
+        }
+        Ok(())
+    }
+}
+
+/// Iterates over the length-prefixed identifiers of a mangled path, leaving out the trailing
+/// hash. Yields `None` if the path is malformed.
+struct Components<'a>(&'a str);
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Option<&'a str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.starts_with('E') || self.0.is_empty() {
+            return None;
+        }
+        let digits = self.0.bytes().take_while(u8::is_ascii_digit).count();
+        let ident = self.0[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|len| self.0.get(digits..digits + len));
+        let Some(ident) = ident else {
+            self.0 = "";
+            return Some(None);
+        };
+        self.0 = &self.0[digits + ident.len()..];
+        if self.0 == "E" && is_hash(ident) {
+            return None;
+        }
+        Some(Some(ident))
+    }
+}
+
+/// rustc appends "h" followed by a 16 digit hex hash to every symbol
+fn is_hash(ident: &str) -> bool {
+    ident.len() == 17 && ident.starts_with('h') && ident[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Write an identifier, expanding the escapes rustc uses for characters that aren't allowed in
+/// symbol names
+fn write_ident(f: &mut fmt::Formatter, ident: &str) -> fmt::Result {
+    // identifiers starting with '$' get an extra '_' in front
+    let mut rest = match ident.strip_prefix("_$") {
+        Some(_) => &ident[1..],
+        None => ident,
+    };
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = r;
+        } else if let Some((code, r)) = rest.strip_prefix('$').and_then(|r| r.split_once('$')) {
+            match unescape(code) {
+                Some(c) => f.write_char(c)?,
+                None => write!(f, "${}$", code)?,
+            }
+            rest = r;
+        } else {
+            let c = rest.chars().next().unwrap();
+            f.write_char(c)?;
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Ok(())
+}
+
+fn unescape(code: &str) -> Option<char> {
+    match code {
+        "SP" => Some('@'),
+        "BP" => Some('*'),
+        "RF" => Some('&'),
+        "LT" => Some('<'),
+        "GT" => Some('>'),
+        "LP" => Some('('),
+        "RP" => Some(')'),
+        "C" => Some(','),
+        _ => {
+            // arbitrary characters are escaped as $u<hex>$
+            let hex = code.strip_prefix('u')?;
+            char::from_u32(u32::from_str_radix(hex, 16).ok()?)
+        }
+    }
+}
@@ -0,0 +1,241 @@
+/// Kernel threads
+///
+/// A kernel thread runs a function in supervisor mode on a stack of its own, for deferred work
+/// that shouldn't happen in a trap handler. Threads are picked by the idle loop of every hart
+/// along with user processes (see sched.rs), and the idle loop switches to a thread by swapping
+/// callee-saved registers with `switch_context`. Threads aren't preempted: one runs until its
+/// function returns, or it calls `yield_now` or `sleep`.
+///
+/// Stacks are mapped in KSTACK_REGION, each with an unmapped guard page below it, so that
+/// overflowing one faults instead of silently corrupting the memory below. kernel_vec handles
+/// that fault on the hart's emergency stack, since there's no room left on the thread's.
+use core::{mem::MaybeUninit, ops::Range, ptr::null_mut};
+
+use crate::{
+    cpu,
+    csr::SSTATUS_SIE,
+    csr_set_bits, csr_write,
+    kmem::{kalloc, kfree, virt_to_phys, KSTACK_REGION, PAGE_SIZE},
+    lockdep::{self, HeldLocks},
+    mmu::{self, PTE_R, PTE_W},
+    sched::{self, Entity, Task, RUN_LOCK},
+    spinlock::interrupts_off,
+};
+
+extern "C" {
+    fn switch_context(old: *mut Context, new: *const Context); // defined in switch.s
+}
+
+pub const MAX_THREADS: usize = 32;
+
+/// Pages of a kernel thread stack, not counting the guard page
+pub const STACK_PAGES: usize = 4;
+
+/// Room taken up in KSTACK_REGION by each thread: the guard page and the stack above it
+const STACK_SLOT: u64 = (STACK_PAGES as u64 + 1) * PAGE_SIZE;
+
+/// Registers preserved across a call to switch_context. The layout must match switch.s.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Context {
+    ra: u64,
+    sp: u64,
+    s: [u64; 12],
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ThreadState {
+    Free, // THREADS starts out zeroed
+    Runnable,
+    Running,
+    Sleeping, // waiting to be woken up, see wait.rs
+    Dead,     // finished, waiting for the idle loop to free its stack
+}
+
+pub struct KThread {
+    pub id: usize, // index in THREADS
+    pub state: ThreadState,
+    pub sched: Entity,
+    pub stack: Range<u64>,
+    context: Context,
+    func: Option<fn(usize)>,
+    arg: usize,
+    pages: [*mut u8; STACK_PAGES],
+    held_locks: HeldLocks, // sleeping locks held while switched out
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    TooManyThreads,
+    OutOfMemory,
+}
+
+static mut THREADS: MaybeUninit<[KThread; MAX_THREADS]> = MaybeUninit::zeroed();
+
+fn threads() -> &'static mut [KThread; MAX_THREADS] {
+    unsafe { THREADS.assume_init_mut() }
+}
+
+/// Create a kernel thread that calls `func(arg)`. It may start running on any hart as soon as
+/// this returns. Returns the thread's index.
+pub fn spawn(func: fn(usize), arg: usize) -> Result<usize, SpawnError> {
+    // allocated up front, since unmapping a partly built stack would call other harts to flush
+    // their TLBs, which they can't do while waiting for RUN_LOCK
+    let mut pages = [null_mut(); STACK_PAGES];
+    for i in 0..STACK_PAGES {
+        pages[i] = kalloc();
+        if pages[i].is_null() {
+            pages[..i].iter().for_each(|&page| kfree(page));
+            return Err(SpawnError::OutOfMemory);
+        }
+    }
+
+    let table = mmu::kernel_table();
+    let guard = RUN_LOCK.lock();
+    let Some(index) = threads()
+        .iter()
+        .position(|thread| thread.state == ThreadState::Free)
+    else {
+        drop(guard);
+        pages.iter().for_each(|&page| kfree(page));
+        return Err(SpawnError::TooManyThreads);
+    };
+    let thread = &mut threads()[index];
+
+    // the guard page at the bottom of the slot is left unmapped
+    let bottom = KSTACK_REGION.start + index as u64 * STACK_SLOT + PAGE_SIZE;
+    for (i, &page) in pages.iter().enumerate() {
+        let vaddr = bottom + i as u64 * PAGE_SIZE;
+        table.map(vaddr, virt_to_phys(page as u64), PTE_R | PTE_W, 0);
+    }
+    thread.pages = pages;
+
+    thread.stack = bottom..bottom + STACK_PAGES as u64 * PAGE_SIZE;
+    thread.context = Context {
+        ra: thread_start as u64,
+        sp: thread.stack.end,
+        s: [0; 12],
+    };
+    thread.id = index;
+    thread.sched = Entity::new();
+    thread.held_locks = HeldLocks::new();
+    thread.func = Some(func);
+    thread.arg = arg;
+    thread.state = ThreadState::Runnable;
+    sched::enqueue(Task::Thread(index));
+    Ok(index)
+}
+
+/// The thread running on this hart, if any
+pub fn current() -> Option<&'static mut KThread> {
+    unsafe { cpu!().current_thread.as_mut() }
+}
+
+/// Thread `index`, whose state may only be changed with RUN_LOCK held
+pub fn get(index: usize) -> &'static mut KThread {
+    &mut threads()[index]
+}
+
+/// Make thread `index` runnable if it is asleep, returning whether it was. Must be called
+/// with RUN_LOCK held.
+pub fn wake(index: usize) -> bool {
+    let thread = &mut threads()[index];
+    let sleeping = thread.state == ThreadState::Sleeping;
+    if sleeping {
+        thread.state = ThreadState::Runnable;
+    }
+    sleeping
+}
+
+/// Switch from the idle loop to thread `index`, which must have been claimed, until it yields,
+/// sleeps or exits. Interrupts must be off.
+pub fn run(index: usize) {
+    let thread = &mut threads()[index];
+    unsafe {
+        cpu!().current_thread = thread;
+        switch_context(&mut cpu!().idle_context, &thread.context);
+        cpu!().current_thread = null_mut();
+    }
+
+    // The thread switched back holding RUN_LOCK. Now that this hart is off its stack, another
+    // hart may pick it up again, or its stack can be freed.
+    sched::switched_out(Task::Thread(index));
+    if thread.state == ThreadState::Running {
+        thread.state = ThreadState::Runnable;
+        sched::enqueue(Task::Thread(index));
+    }
+    let dead = thread.state == ThreadState::Dead;
+    unsafe {
+        RUN_LOCK.force_unlock();
+    }
+    if dead {
+        thread.free();
+        let _guard = RUN_LOCK.lock();
+        thread.state = ThreadState::Free;
+    }
+}
+
+/// Go back to the idle loop, which releases RUN_LOCK. Returns when the thread is run again.
+fn switch_to_idle(thread: &mut KThread, interrupts_were_enabled: bool) {
+    lockdep::switch_out(&mut thread.held_locks);
+    unsafe {
+        switch_context(&mut thread.context, &cpu!().idle_context);
+    }
+    lockdep::switch_in(&mut thread.held_locks);
+    unsafe {
+        if interrupts_were_enabled {
+            csr_set_bits!(sstatus, SSTATUS_SIE);
+        }
+    }
+}
+
+/// Let other threads and processes run. Does nothing outside of a kernel thread.
+pub fn yield_now() {
+    let Some(thread) = current() else {
+        return;
+    };
+    let interrupts_were_enabled = interrupts_off();
+    core::mem::forget(RUN_LOCK.lock());
+    switch_to_idle(thread, interrupts_were_enabled);
+}
+
+/// Sleep until `wake` is called, calling `release` once nothing can wake the thread before its
+/// hart is done with it. `release` must leave interrupts off, and returns whether they should
+/// be turned back on once the thread is woken. Panics outside of a kernel thread.
+pub fn sleep(release: impl FnOnce() -> bool) {
+    let thread = current().expect("sleep outside of a kernel thread");
+    let interrupts_were_enabled = interrupts_off();
+    core::mem::forget(RUN_LOCK.lock());
+    thread.state = ThreadState::Sleeping;
+    let interrupts_were_enabled = release() || interrupts_were_enabled;
+    switch_to_idle(thread, interrupts_were_enabled);
+}
+
+/// End the current thread
+pub fn exit() -> ! {
+    let thread = current().expect("exit outside of a kernel thread");
+    interrupts_off();
+    core::mem::forget(RUN_LOCK.lock());
+    thread.state = ThreadState::Dead;
+    switch_to_idle(thread, false);
+    unreachable!("dead kernel thread was resumed");
+}
+
+/// Where a new thread starts, on its own stack
+extern "C" fn thread_start() -> ! {
+    let thread = current().unwrap();
+    // the idle loop switched here with interrupts off
+    unsafe {
+        csr_set_bits!(sstatus, SSTATUS_SIE);
+    }
+    (thread.func.unwrap())(thread.arg);
+    exit()
+}
+
+impl KThread {
+    fn free(&mut self) {
+        mmu::kernel_table().unmap(self.stack.start, self.stack.end - self.stack.start);
+        self.pages.iter().for_each(|&page| kfree(page));
+        self.func = None;
+    }
+}
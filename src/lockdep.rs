@@ -0,0 +1,420 @@
+/// Lock dependency checker for debug builds
+///
+/// Every lock belongs to a class, the place in the source where it was created, so that all
+/// the locks made by one `Spinlock::new` call (such as the one inside every WaitQueue) are
+/// checked as one. Each hart keeps a stack of the locks it holds, and taking a lock records
+/// that its class comes after the class of every lock already held. A deadlock is possible
+/// once two classes have been taken in both orders, which shows up as a cycle in those
+/// dependencies. The checker reports that as soon as the order that closes the cycle is first
+/// used, even if the two paths never ran at the same time.
+///
+/// It also reports taking a sleeping lock (see sync.rs) in an interrupt handler, and going to
+/// sleep while holding a spinlock other than RUN_LOCK, which is handed over to the idle loop.
+/// Spinlocks turn interrupts off while held, so any of them can be taken by an interrupt
+/// handler. Semaphores aren't tracked since they have no owner to release them.
+///
+/// Reports are printed with the acquisition sites involved, after which the checker turns
+/// itself off, since its view of which locks are held can't be trusted anymore.
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    cpu,
+    cpustat::{self, Mode},
+    csr::SSTATUS_SIE,
+    csr_set_bits, csr_write, println_sync,
+    sched::RUN_LOCK,
+    spinlock::interrupts_off,
+};
+
+const ENABLED: bool = cfg!(debug_assertions);
+
+const MAX_CLASSES: usize = 128;
+const MAX_DEPENDENCIES: usize = 512;
+const MAX_HELD: usize = 16;
+
+/// Where a lock was created or taken
+pub type Site = &'static Location<'static>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Spin, // zeroed HeldLocks hold spinlocks
+    Sleeping,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    lock: usize, // address, to tell apart locks of the same class
+    class: u8,
+    kind: Kind,
+    site: Option<Site>,
+}
+
+/// Locks held by a hart, or by a kernel thread while it isn't running
+#[derive(Clone, Copy)]
+pub struct HeldLocks {
+    held: [Held; MAX_HELD],
+    depth: usize,
+}
+
+/// Per hart state, kept in its CPU
+pub struct HartLocks {
+    held: HeldLocks,
+    busy: bool, // checking a lock, so locks taken while reporting aren't checked
+}
+
+/// Class `to` was taken while holding class `from`, first at `site`
+#[derive(Clone, Copy)]
+struct Dependency {
+    from: u8,
+    to: u8,
+    site: Option<Site>,
+}
+
+struct Graph {
+    classes: [Option<Site>; MAX_CLASSES],
+    class_count: usize,
+    after: [u128; MAX_CLASSES], // bit n of after[m]: class n has been taken holding class m
+    dependencies: [Dependency; MAX_DEPENDENCIES],
+    dependency_count: usize,
+}
+
+static OFF: AtomicBool = AtomicBool::new(false);
+
+/// Not a Spinlock, which would be checked itself
+static GRAPH_LOCK: AtomicBool = AtomicBool::new(false);
+
+static mut GRAPH: Graph = Graph {
+    classes: [None; MAX_CLASSES],
+    class_count: 0,
+    after: [0; MAX_CLASSES],
+    dependencies: [Dependency {
+        from: 0,
+        to: 0,
+        site: None,
+    }; MAX_DEPENDENCIES],
+    dependency_count: 0,
+};
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        Self {
+            held: [Held {
+                lock: 0,
+                class: 0,
+                kind: Kind::Spin,
+                site: None,
+            }; MAX_HELD],
+            depth: 0,
+        }
+    }
+
+    fn held(&self) -> &[Held] {
+        &self.held[..self.depth]
+    }
+
+    fn push(&mut self, held: Held) -> bool {
+        if self.depth == MAX_HELD {
+            return false;
+        }
+        self.held[self.depth] = held;
+        self.depth += 1;
+        true
+    }
+
+    fn remove(&mut self, index: usize) -> Held {
+        let held = self.held[index];
+        self.held.copy_within(index + 1..self.depth, index);
+        self.depth -= 1;
+        held
+    }
+}
+
+impl Graph {
+    fn class(&mut self, site: Site) -> Option<u8> {
+        let classes = &self.classes[..self.class_count];
+        if let Some(class) = classes.iter().position(|&class| class == Some(site)) {
+            return Some(class as u8);
+        }
+        if self.class_count == MAX_CLASSES {
+            return None;
+        }
+        self.classes[self.class_count] = Some(site);
+        self.class_count += 1;
+        Some(self.class_count as u8 - 1)
+    }
+
+    fn site(&self, class: u8) -> Site {
+        self.classes[class as usize].unwrap()
+    }
+
+    /// Record that `to` was taken holding `from`. Returns false if there's no room.
+    fn add(&mut self, from: u8, to: u8, site: Site) -> bool {
+        if self.dependency_count == MAX_DEPENDENCIES {
+            return false;
+        }
+        self.after[from as usize] |= 1 << to;
+        self.dependencies[self.dependency_count] = Dependency {
+            from,
+            to,
+            site: Some(site),
+        };
+        self.dependency_count += 1;
+        true
+    }
+
+    fn dependency(&self, from: u8, to: u8) -> &Dependency {
+        self.dependencies[..self.dependency_count]
+            .iter()
+            .find(|dependency| dependency.from == from && dependency.to == to)
+            .unwrap()
+    }
+
+    /// Classes on a path of dependencies from `from` to `to`, found breadth first. Returns the
+    /// number of classes on it, the last one being `to`.
+    fn path(&self, from: u8, to: u8, path: &mut [u8; MAX_CLASSES]) -> Option<usize> {
+        let mut previous = [0u8; MAX_CLASSES];
+        let mut queue = [0u8; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        let mut seen: u128 = 1 << from;
+        queue[0] = from;
+        while head < tail {
+            let class = queue[head];
+            head += 1;
+            if class == to {
+                // walk back to `from`, then put the path the right way around
+                let mut len = 0;
+                let mut at = to;
+                while at != from {
+                    path[len] = at;
+                    len += 1;
+                    at = previous[at as usize];
+                }
+                path[..len].reverse();
+                return Some(len);
+            }
+            let mut next = self.after[class as usize] & !seen;
+            seen |= next;
+            while next != 0 {
+                let after = next.trailing_zeros() as u8;
+                next &= next - 1;
+                previous[after as usize] = class;
+                queue[tail] = after;
+                tail += 1;
+            }
+        }
+        None
+    }
+}
+
+fn hart() -> &'static mut HartLocks {
+    unsafe { &mut cpu!().lockdep }
+}
+
+/// Run `f` on the dependency graph, unless a check is already running on this hart
+fn check(f: impl FnOnce(&mut HartLocks, &mut Graph)) {
+    if !ENABLED || OFF.load(Ordering::Relaxed) {
+        return;
+    }
+    let interrupts_were_enabled = interrupts_off();
+    let hart = hart();
+    if !hart.busy {
+        hart.busy = true;
+        while GRAPH_LOCK
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        f(hart, unsafe { &mut *core::ptr::addr_of_mut!(GRAPH) });
+        GRAPH_LOCK.store(false, Ordering::Release);
+        hart.busy = false;
+    }
+    if interrupts_were_enabled {
+        unsafe {
+            csr_set_bits!(sstatus, SSTATUS_SIE);
+        }
+    }
+}
+
+fn turn_off(reason: &str) {
+    println_sync!("lockdep: {}, turning off", reason);
+    OFF.store(true, Ordering::Relaxed);
+}
+
+fn describe(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Spin => "spinlock",
+        Kind::Sleeping => "sleeping lock",
+    }
+}
+
+fn print_held(graph: &Graph, held: &HeldLocks) {
+    println_sync!("locks held by hart {}:", unsafe { crate::reg_read!(tp) });
+    for held in held.held() {
+        println_sync!(
+            "  {} {} taken at {}",
+            describe(held.kind),
+            graph.site(held.class),
+            held.site.unwrap()
+        );
+    }
+}
+
+/// Print the dependencies from `from` around to `to`, which close a cycle when `to` is taken
+/// holding `from` at `site`
+fn report_cycle(graph: &Graph, hart: &HartLocks, from: u8, to: u8, site: Site) {
+    println_sync!("lockdep: possible deadlock");
+    println_sync!("  {} taken at {}", graph.site(to), site);
+    println_sync!("  while holding {}", graph.site(from));
+    println_sync!("but the opposite order has been seen before:");
+    let mut path = [0; MAX_CLASSES];
+    let len = graph.path(to, from, &mut path).unwrap();
+    let mut previous = to;
+    for &class in &path[..len] {
+        let dependency = graph.dependency(previous, class);
+        println_sync!(
+            "  {} taken at {}",
+            graph.site(class),
+            dependency.site.unwrap()
+        );
+        println_sync!("  while holding {}", graph.site(previous));
+        previous = class;
+    }
+    print_held(graph, &hart.held);
+}
+
+/// Check taking the lock at address `lock` of class `class` at `site` against the locks held
+/// by this hart, then count it as held
+pub fn acquire(lock: usize, class: Site, kind: Kind, site: Site) {
+    check(|hart, graph| {
+        let Some(to) = graph.class(class) else {
+            return turn_off("too many lock classes");
+        };
+
+        if kind == Kind::Sleeping && cpustat::mode() == Mode::Interrupt {
+            println_sync!("lockdep: sleeping lock {} taken at {}", class, site);
+            println_sync!("in an interrupt handler");
+            print_held(graph, &hart.held);
+            return turn_off("lock taken in interrupt context");
+        }
+
+        for held in hart.held.held() {
+            if held.lock == lock {
+                println_sync!("lockdep: {} {} taken at {}", describe(kind), class, site);
+                println_sync!("while already held, taken at {}", held.site.unwrap());
+                print_held(graph, &hart.held);
+                return turn_off("recursive locking");
+            }
+            // other locks of the same class, such as one per device, can be nested
+            let from = held.class;
+            if from == to || graph.after[from as usize] & 1 << to != 0 {
+                continue;
+            }
+            let mut path = [0; MAX_CLASSES];
+            if graph.path(to, from, &mut path).is_some() {
+                report_cycle(graph, hart, from, to, site);
+                return turn_off("lock order cycle");
+            }
+            if !graph.add(from, to, site) {
+                return turn_off("too many lock dependencies");
+            }
+        }
+
+        let held = Held {
+            lock,
+            class: to,
+            kind,
+            site: Some(site),
+        };
+        if !hart.held.push(held) {
+            turn_off("too many locks held");
+        }
+    })
+}
+
+/// Count a lock that was taken without waiting for it as held. It can't be part of a
+/// deadlock, so nothing is checked.
+pub fn acquired(lock: usize, class: Site, kind: Kind, site: Site) {
+    check(|hart, graph| {
+        let Some(class) = graph.class(class) else {
+            return turn_off("too many lock classes");
+        };
+        let held = Held {
+            lock,
+            class,
+            kind,
+            site: Some(site),
+        };
+        if !hart.held.push(held) {
+            turn_off("too many locks held");
+        }
+    })
+}
+
+/// Stop counting the lock at address `lock` as held. Locks taken while the checker was busy
+/// aren't counted, so they may not be found.
+pub fn release(lock: usize) {
+    check(|hart, _| {
+        if let Some(index) = hart.held.held().iter().rposition(|held| held.lock == lock) {
+            hart.held.remove(index);
+        }
+    })
+}
+
+/// Check that the task running on this hart can go to sleep
+fn check_sleep(hart: &HartLocks, graph: &Graph) -> bool {
+    let run_lock = &RUN_LOCK as *const _ as usize;
+    let spinning = |held: &&Held| held.kind == Kind::Spin && held.lock != run_lock;
+    if cpustat::mode() == Mode::Interrupt {
+        println_sync!("lockdep: sleeping in an interrupt handler");
+    } else if let Some(held) = hart.held.held().iter().find(spinning) {
+        println_sync!("lockdep: sleeping while holding {}", graph.site(held.class));
+        println_sync!("taken at {}", held.site.unwrap());
+    } else {
+        return true;
+    }
+    print_held(graph, &hart.held);
+    turn_off("sleeping in atomic context");
+    false
+}
+
+/// Check that a process can go to sleep in a syscall. Any sleeping lock it holds is released
+/// before it leaves the syscall.
+pub fn process_sleep() {
+    check(|hart, graph| {
+        check_sleep(hart, graph);
+    });
+}
+
+/// Check that a kernel thread can be switched out, and move the sleeping locks it holds from
+/// this hart to `saved`
+pub fn switch_out(saved: &mut HeldLocks) {
+    check(|hart, graph| {
+        if !check_sleep(hart, graph) {
+            return;
+        }
+        let mut index = 0;
+        while index < hart.held.depth {
+            if hart.held.held[index].kind == Kind::Sleeping {
+                saved.push(hart.held.remove(index));
+            } else {
+                index += 1;
+            }
+        }
+    })
+}
+
+/// Give the locks saved by `switch_out` back to the hart a kernel thread is resumed on
+pub fn switch_in(saved: &mut HeldLocks) {
+    check(|hart, _| {
+        for &held in saved.held() {
+            if !hart.held.push(held) {
+                return turn_off("too many locks held");
+            }
+        }
+        saved.depth = 0;
+    })
+}
@@ -10,13 +10,16 @@
 )]
 
 extern "C" {
-    fn kernel_vec();
+    fn kernel_trampoline();
+    fn machine_vec();
 }
 
 use crate::csr::{
-    MSTATUS_MPP, MSTATUS_MPP_S, PMPCFG_A, PMPCFG_A_TOR, PMPCFG_R, PMPCFG_W, PMPCFG_X, SIE_SEIE,
-    SIE_SSIE, SIE_STIE, SSTATUS_SIE,
+    MENVCFG_PBMTE, MIE_MSIE, MSTATUS_MPP, MSTATUS_MPP_S, PMPCFG_A, PMPCFG_A_TOR, PMPCFG_R,
+    PMPCFG_W, PMPCFG_X, SIE_SEIE, SIE_SSIE, SIE_STIE, SSTATUS_FS, SSTATUS_FS_OFF, SSTATUS_SIE,
+    SSTATUS_VS, SSTATUS_VS_OFF,
 };
+use crate::kmem::phys_to_virt;
 use core::arch::asm;
 
 #[macro_export]
@@ -47,6 +50,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     } else {
         println_sync!("no information available.");
     }
+    crate::crash::report();
     abort();
 }
 
@@ -59,9 +63,12 @@ extern "C" fn abort() -> ! {
     }
 }
 
+/// Physical address of the device tree blob, handed over from kinit to main
+static mut DTB_ADDR: u64 = 0;
+
 /// ENTRY POINT
 #[no_mangle]
-extern "C" fn kinit() {
+extern "C" fn kinit(dtb: u64) {
     unsafe {
         // disable paging until the MMU is initialized
         csr_write!(satp, 0u64);
@@ -69,42 +76,72 @@ extern "C" fn kinit() {
         // allow supervisor interrupts
         csr_set_bits!(sstatus, SSTATUS_SIE);
 
+        // the kernel doesn't use floating point or vectors, see fp.rs and vector.rs
+        csr_write_field!(sstatus, SSTATUS_FS, SSTATUS_FS_OFF);
+        csr_write_field!(sstatus, SSTATUS_VS, SSTATUS_VS_OFF);
+
         // delegate interrupts and exceptions to supervisor
         csr_write!(medeleg, 0xffffu64);
         csr_write!(mideleg, 0xffffu64);
         csr_set_bits!(sie, SIE_SEIE, SIE_STIE, SIE_SSIE);
 
-        // set interrupt vector
-        csr_write!(stvec, kernel_vec as u64);
-
         // allow supervisor to access all memory
         csr_write!(pmpaddr0, (1 << 54) - 1);
         csr_write_field!(pmpcfg0, PMPCFG_A, PMPCFG_A_TOR);
         csr_set_bits!(pmpcfg0, PMPCFG_R, PMPCFG_W, PMPCFG_X);
 
+        // allow supervisor to use page-based memory types (Svpbmt) if the hart has them.
+        // PBMTE is WARL, so it reads back as 0 when unsupported.
+        csr_set_bits!(menvcfg, MENVCFG_PBMTE);
+        crate::cpu::PBMTE_ENABLED = csr_read!(menvcfg) & MENVCFG_PBMTE != 0;
+
+        DTB_ADDR = dtb;
+
         // write mhartid into tp
         let hartid: u64 = csr_read!(mhartid);
         reg_write!(tp, hartid);
 
+        // Machine software and timer interrupts (from the CLINT) can't be delegated, so
+        // machine_vec forwards them to supervisor mode for IPIs and the scheduler tick. Like
+        // mepc below, these are physical addresses, which is what M-mode uses.
+        csr_write!(mtvec, machine_vec as u64);
+        crate::timer::machine_init(hartid as usize);
+        csr_set_bits!(mie, MIE_MSIE);
+
         // switch to supervisor mode upon mret
         csr_write_field!(mstatus, MSTATUS_MPP, MSTATUS_MPP_S);
 
-        // jump to main upon mret
-        csr_write!(mepc, main as u64);
+        // jump to kernel_trampoline upon mret, which enables paging and calls main. We are
+        // running from physical memory, so this is the trampoline's physical address.
+        csr_write!(mepc, kernel_trampoline as u64);
 
         asm!("mret");
     }
 }
 
-fn main() {
+#[no_mangle]
+extern "C" fn main() {
     // Main should initialize all sub-systems and get
     // ready to start scheduling. The last thing this
     // should do is start the timer.
 
+    crate::trap::init();
     crate::uart::init();
+    match unsafe { DTB_ADDR } {
+        0 => debug!("No device tree was passed in a1"),
+        dtb => crate::fdt::init(phys_to_virt(dtb)),
+    }
+    crate::gdb::init();
+    crate::cpu::init();
+    crate::cpustat::init();
+    crate::vector::init();
+    crate::irq::init();
     crate::kmem::init();
     crate::mmu::init();
+    crate::proc::init();
+    crate::cpu::set_online();
     crate::virtio::init();
+    crate::cpu::start_others();
 
     // Now test println! macro!
     debug!("Initialized hart {}", unsafe { reg_read!(tp) });
@@ -114,28 +151,62 @@ fn main() {
     println!("===========");
     println!();
 
-    unsafe {
-        let mtimecmp = 0x0200_4000 as *mut u64;
-        let mtime = 0x0200_bff8 as *const u64;
-        mtimecmp.write_volatile(mtime.read_volatile() + 10_000_000);
-    }
+    crate::timer::init();
+    crate::sched::idle()
+}
 
-    // Test reading from UART
-    loop {}
+/// Where harts other than hart 0 enter the kernel, once hart 0 has initialized it and woken them
+/// up (see boot.s)
+#[no_mangle]
+extern "C" fn secondary_main() {
+    crate::trap::init();
+    crate::mmu::init_hart();
+    crate::irq::init_hart();
+    crate::cpu::set_online();
+    debug!("Initialized hart {}", unsafe { reg_read!(tp) });
+    crate::timer::init();
+    crate::sched::idle()
 }
 
+pub mod aia;
 pub mod asm;
 pub mod cpu;
+pub mod cpustat;
+pub mod crash;
 pub mod csr;
+pub mod fdt;
+pub mod file;
+pub mod fp;
+pub mod gdb;
+pub mod intc;
+pub mod ipi;
+pub mod irq;
 pub mod kmem;
+pub mod ksyms;
+pub mod kthread;
+pub mod lockdep;
+pub mod misaligned;
 pub mod mmio;
 pub mod mmu;
+pub mod pid;
+pub mod pipe;
 pub mod plic;
 pub mod proc;
 pub mod reg;
+pub mod scause;
+pub mod sched;
+pub mod signal;
+pub mod spinlock;
 pub mod string;
+pub mod sync;
+pub mod syscall;
 pub mod term;
+pub mod timer;
+pub mod tlb;
 pub mod trap;
+pub mod uaccess;
 pub mod uart;
 pub mod util;
+pub mod vector;
 pub mod virtio;
+pub mod wait;
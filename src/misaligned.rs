@@ -0,0 +1,196 @@
+/// Emulation of misaligned loads and stores from user mode
+///
+/// Harts may raise an address-misaligned exception instead of performing a misaligned access.
+/// The faulting instruction is fetched from the process, decoded (both the standard and
+/// compressed encodings of integer and floating point loads and stores) and carried out a byte
+/// at a time through the process' page table. AMOs and LR/SC can't be emulated atomically, so
+/// they are left to fault.
+use crate::{
+    mmu::PageTable,
+    proc::TrapFrame,
+    uaccess::{copy_from_user, copy_to_user, Fault},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Load,
+    Store,
+}
+
+#[derive(Clone, Copy)]
+enum Reg {
+    X(usize),
+    F(usize),
+}
+
+/// A decoded load or store
+struct Access {
+    kind: Kind,
+    reg: Reg,    // destination of a load or source of a store
+    base: usize, // x register holding the base address
+    offset: i64,
+    size: usize,
+    signed: bool, // sign extend a load
+    len: u64,     // length of the instruction in bytes
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The instruction couldn't be fetched, or the access itself faulted
+    Fault(Fault),
+    /// The instruction at sepc isn't a load or store of the expected kind
+    Unsupported(u32),
+}
+
+impl From<Fault> for Error {
+    fn from(fault: Fault) -> Self {
+        Error::Fault(fault)
+    }
+}
+
+/// Carry out the misaligned load or store at `frame.epc`, and skip past it
+pub fn emulate(pt: &PageTable, frame: &mut TrapFrame, kind: Kind) -> Result<(), Error> {
+    let inst = fetch(pt, frame.epc)?;
+    let access = match decode(inst) {
+        Some(access) if access.kind == kind => access,
+        _ => return Err(Error::Unsupported(inst)),
+    };
+    let addr = frame.regs[access.base].wrapping_add(access.offset as u64);
+
+    let mut bytes = [0u8; 8];
+    match access.kind {
+        Kind::Load => {
+            copy_from_user(pt, &mut bytes[..access.size], addr)?;
+            let value = extend(u64::from_le_bytes(bytes), access.size, access.signed);
+            match access.reg {
+                Reg::X(0) => {}
+                Reg::X(rd) => frame.regs[rd] = value,
+                // NaN-box single precision values
+                Reg::F(rd) if access.size == 4 => frame.fp.set_reg(rd, value | !0 << 32),
+                Reg::F(rd) => frame.fp.set_reg(rd, value),
+            }
+        }
+        Kind::Store => {
+            let value = match access.reg {
+                Reg::X(rs2) => frame.regs[rs2],
+                Reg::F(rs2) => frame.fp.regs[rs2],
+            };
+            bytes = value.to_le_bytes();
+            copy_to_user(pt, addr, &bytes[..access.size])?;
+        }
+    }
+    frame.epc += access.len;
+    Ok(())
+}
+
+/// Fetch the (possibly compressed) instruction at `epc`
+fn fetch(pt: &PageTable, epc: u64) -> Result<u32, Fault> {
+    let mut low = [0u8; 2];
+    copy_from_user(pt, &mut low, epc)?;
+    let low = u16::from_le_bytes(low) as u32;
+    if low & 0b11 != 0b11 {
+        return Ok(low);
+    }
+    let mut high = [0u8; 2];
+    copy_from_user(pt, &mut high, epc + 2)?;
+    Ok(low | (u16::from_le_bytes(high) as u32) << 16)
+}
+
+fn decode(inst: u32) -> Option<Access> {
+    if inst & 0b11 == 0b11 {
+        decode_standard(inst)
+    } else {
+        decode_compressed(inst)
+    }
+}
+
+fn decode_standard(inst: u32) -> Option<Access> {
+    let funct3 = bits(inst, 14, 12);
+    let rd = bits(inst, 11, 7) as usize;
+    let rs1 = bits(inst, 19, 15) as usize;
+    let rs2 = bits(inst, 24, 20) as usize;
+    let i_imm = (inst as i32 >> 20) as i64;
+    let s_imm = (((inst as i32 >> 25) << 5) | bits(inst, 11, 7) as i32) as i64;
+
+    let (kind, reg, offset, size, signed) = match (inst & 0x7f, funct3) {
+        // LOAD: lb, lh, lw, ld, lbu, lhu, lwu
+        (0x03, 0..=3) => (Kind::Load, Reg::X(rd), i_imm, 1 << funct3, true),
+        (0x03, 4..=6) => (Kind::Load, Reg::X(rd), i_imm, 1 << (funct3 - 4), false),
+        // STORE: sb, sh, sw, sd
+        (0x23, 0..=3) => (Kind::Store, Reg::X(rs2), s_imm, 1 << funct3, false),
+        // LOAD-FP and STORE-FP: flw, fld, fsw, fsd
+        (0x07, 2..=3) => (Kind::Load, Reg::F(rd), i_imm, 1 << funct3, false),
+        (0x27, 2..=3) => (Kind::Store, Reg::F(rs2), s_imm, 1 << funct3, false),
+        _ => return None,
+    };
+    Some(Access {
+        kind,
+        reg,
+        base: rs1,
+        offset,
+        size,
+        signed,
+        len: 4,
+    })
+}
+
+fn decode_compressed(inst: u32) -> Option<Access> {
+    const SP: usize = 2;
+    let funct3 = bits(inst, 15, 13);
+    // registers x8-x15 in the 3-bit fields of quadrant 0
+    let rs1_c = bits(inst, 9, 7) as usize + 8;
+    let rd_c = bits(inst, 4, 2) as usize + 8;
+    // quadrant 2 uses full register numbers
+    let rd = bits(inst, 11, 7) as usize;
+    let rs2 = bits(inst, 6, 2) as usize;
+
+    // zero extended offsets, scaled by the access size
+    let word_off = bits(inst, 12, 10) << 3 | bits(inst, 6, 6) << 2 | bits(inst, 5, 5) << 6;
+    let double_off = bits(inst, 12, 10) << 3 | bits(inst, 6, 5) << 6;
+    let lwsp_off = bits(inst, 12, 12) << 5 | bits(inst, 6, 4) << 2 | bits(inst, 3, 2) << 6;
+    let ldsp_off = bits(inst, 12, 12) << 5 | bits(inst, 6, 5) << 3 | bits(inst, 4, 2) << 6;
+    let swsp_off = bits(inst, 12, 9) << 2 | bits(inst, 8, 7) << 6;
+    let sdsp_off = bits(inst, 12, 10) << 3 | bits(inst, 9, 7) << 6;
+
+    let (kind, reg, base, offset, size, signed) = match (inst & 0b11, funct3) {
+        // c.fld, c.lw, c.ld, c.fsd, c.sw, c.sd
+        (0b00, 0b001) => (Kind::Load, Reg::F(rd_c), rs1_c, double_off, 8, false),
+        (0b00, 0b010) => (Kind::Load, Reg::X(rd_c), rs1_c, word_off, 4, true),
+        (0b00, 0b011) => (Kind::Load, Reg::X(rd_c), rs1_c, double_off, 8, true),
+        (0b00, 0b101) => (Kind::Store, Reg::F(rd_c), rs1_c, double_off, 8, false),
+        (0b00, 0b110) => (Kind::Store, Reg::X(rd_c), rs1_c, word_off, 4, false),
+        (0b00, 0b111) => (Kind::Store, Reg::X(rd_c), rs1_c, double_off, 8, false),
+        // c.fldsp, c.lwsp, c.ldsp, c.fsdsp, c.swsp, c.sdsp
+        (0b10, 0b001) => (Kind::Load, Reg::F(rd), SP, ldsp_off, 8, false),
+        (0b10, 0b010) if rd != 0 => (Kind::Load, Reg::X(rd), SP, lwsp_off, 4, true),
+        (0b10, 0b011) if rd != 0 => (Kind::Load, Reg::X(rd), SP, ldsp_off, 8, true),
+        (0b10, 0b101) => (Kind::Store, Reg::F(rs2), SP, sdsp_off, 8, false),
+        (0b10, 0b110) => (Kind::Store, Reg::X(rs2), SP, swsp_off, 4, false),
+        (0b10, 0b111) => (Kind::Store, Reg::X(rs2), SP, sdsp_off, 8, false),
+        _ => return None,
+    };
+    Some(Access {
+        kind,
+        reg,
+        base,
+        offset: offset as i64,
+        size,
+        signed,
+        len: 2,
+    })
+}
+
+/// Extract `inst[hi:lo]`
+pub const fn bits(inst: u32, hi: u32, lo: u32) -> u32 {
+    (inst >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Extend the low `size` bytes of `value` to 64 bits
+fn extend(value: u64, size: usize, signed: bool) -> u64 {
+    let shift = 64 - 8 * size as u32;
+    if signed {
+        ((value << shift) as i64 >> shift) as u64
+    } else {
+        (value << shift) >> shift
+    }
+}
@@ -1,13 +1,14 @@
+use crate::cpu::{has_extension, Extension};
 use crate::csr::{SATP_MODE, SATP_MODE_SV39, SATP_PPN};
 use crate::kmem::{
-    self, kalloc, kfree, BSS_END, BSS_START, CLINT_BASE, DATA_END, DATA_START, PAGE_SIZE,
-    PLIC_BASE, RODATA_END, RODATA_START, STACK_END, STACK_START, TEXT_END, TEXT_START, UART_BASE,
-    VIRTIO_BASES,
+    self, kalloc, kfree, phys_to_virt, virt_to_phys, BSS_END, BSS_START, CLINT_BASE, DATA_END,
+    DATA_START, HEAP_END, HEAP_START, KSTACK_REGION, PAGE_SIZE, RODATA_END, RODATA_START,
+    STACK_END, STACK_START, TEXT_END, TEXT_START, UART_BASE, VIRTIO_BASES,
 };
-use crate::{csr_write, csr_write_field, page_ceil, page_floor, page_number};
+use crate::{csr_write, csr_write_field, page_ceil, page_floor, page_number, tlb};
 
 /// Sv39 memory management unit.
-use core::{mem::size_of, ptr::null_mut};
+use core::{arch::asm, mem::size_of, ptr::null_mut};
 
 /// Convert physical address to PPN field of PTE
 macro_rules! paddr2pte {
@@ -24,6 +25,7 @@ macro_rules! pte2paddr {
 }
 
 const PAGE_TABLE_SIZE: usize = 512; // number of PTEs in a PageTable
+const USER_ENTRIES: usize = PAGE_TABLE_SIZE / 2; // root PTEs covering the lower half
 static mut PAGE_TABLE: *mut PageTable = null_mut(); // root PageTable
 
 pub struct PageTable {
@@ -49,47 +51,152 @@ pub fn init() {
             return;
         }
 
+        // The kernel image lives inside the direct map, so mapping its sections and then the
+        // heap covers all of RAM. Nothing is mapped in the lower half.
         debug!("adding mappings for kernel memory allocations");
-        (*PAGE_TABLE).map_range(TEXT_START, TEXT_START, TEXT_END, PTE_R | PTE_X);
-        (*PAGE_TABLE).map_range(RODATA_START, RODATA_START, RODATA_END, PTE_R | PTE_X);
-        (*PAGE_TABLE).map_range(DATA_START, DATA_START, DATA_END, PTE_R | PTE_W);
-        (*PAGE_TABLE).map_range(BSS_START, BSS_START, BSS_END, PTE_R | PTE_W);
-        (*PAGE_TABLE).map_range(STACK_START, STACK_START, STACK_END, PTE_R | PTE_W);
-        (*PAGE_TABLE).map(UART_BASE, UART_BASE, PTE_R | PTE_W, 0);
+        (*PAGE_TABLE).map_kernel_range(TEXT_START, TEXT_END, PTE_R | PTE_X);
+        (*PAGE_TABLE).map_kernel_range(RODATA_START, RODATA_END, PTE_R | PTE_X);
+        (*PAGE_TABLE).map_kernel_range(DATA_START, DATA_END, PTE_R | PTE_W);
+        (*PAGE_TABLE).map_kernel_range(BSS_START, BSS_END, PTE_R | PTE_W);
+        (*PAGE_TABLE).map_kernel_range(STACK_START, STACK_END, PTE_R | PTE_W);
+
+        debug!("adding direct map of physical memory");
+        (*PAGE_TABLE).map_kernel_range(HEAP_START, HEAP_END, PTE_R | PTE_W);
+
+        debug!("adding mappings for MMIO devices");
+        (*PAGE_TABLE).map_mmio(phys_to_virt(UART_BASE), UART_BASE, 0x1000);
         for base in VIRTIO_BASES {
-            (*PAGE_TABLE).map_range(base, base, base + 0x1000, PTE_R | PTE_W);
+            (*PAGE_TABLE).map_mmio(phys_to_virt(base), base, 0x1000);
+        }
+        (*PAGE_TABLE).map_mmio(phys_to_virt(CLINT_BASE), CLINT_BASE, 0x1_0000);
+        for (base, len) in crate::intc::mmio_regions() {
+            (*PAGE_TABLE).map_mmio(phys_to_virt(base), base, len);
         }
-        (*PAGE_TABLE).map_range(CLINT_BASE, CLINT_BASE, CLINT_BASE + 0x1_0000, PTE_R | PTE_W);
-        (*PAGE_TABLE).map_range(PLIC_BASE, PLIC_BASE, PLIC_BASE + 0x40_0000, PTE_R | PTE_W);
 
-        // update SATP to enable virtual memory
-        csr_write_field!(satp, SATP_MODE, SATP_MODE_SV39);
-        csr_write_field!(satp, SATP_PPN, page_number!(PAGE_TABLE as u64));
+        // User page tables copy the kernel's root entries when they are created, so the table
+        // that kernel thread stacks get mapped in must exist from the start
+        (*PAGE_TABLE).walk_alloc(KSTACK_REGION.start, 1);
 
         INITIALIZED = true;
     }
+    init_hart();
+}
+
+/// Switch this hart from the boot page table, which also identity maps RAM, to the kernel's
+pub fn init_hart() {
+    unsafe {
+        csr_write_field!(satp, SATP_MODE, SATP_MODE_SV39);
+        csr_write_field!(
+            satp,
+            SATP_PPN,
+            page_number!(virt_to_phys(PAGE_TABLE as u64))
+        );
+        asm!("sfence.vma");
+    }
 }
 
 pub fn initialized() -> bool {
     unsafe { INITIALIZED }
 }
 
+/// The page table of the kernel, whose upper half is shared by every process
+pub fn kernel_table() -> &'static mut PageTable {
+    assert!(initialized());
+    unsafe { &mut *PAGE_TABLE }
+}
+
+/// Allocate an empty page table for a user address space. The upper half is shared with the
+/// kernel page table, so the kernel stays mapped while running on behalf of a process.
+///
+/// Returns null_mut() if no pages are left.
+pub fn create_user_table() -> *mut PageTable {
+    let table = kalloc() as *mut PageTable;
+    if table.is_null() {
+        return table;
+    }
+    unsafe {
+        table.write_bytes(0x00, 1);
+        for i in USER_ENTRIES..PAGE_TABLE_SIZE {
+            (*table).entries[i].entry = (*PAGE_TABLE).entries[i].entry;
+        }
+    }
+    table
+}
+
 /// Two-level Sv39 page table
 impl PageTable {
     /// Upserts a mapping from a virtual address to a physical address.
     ///
     /// Properties of the newly mapped page can be set via `flags`. One of PTE_R, PTE_W, and
     /// PTE_X must be set. The size of the page is controlled via `level`: 0 for 4KiB, 1 for
-    /// 2MiB, and 2 for 1GiB.
+    /// 2MiB, and 2 for 1GiB. A memory type other than PTE_PBMT_PMA may only be requested if
+    /// the hart supports Svpbmt.
     pub fn map(&mut self, vaddr: u64, paddr: u64, flags: u64, level: usize) {
         assert!(
-            (flags & PTE_PBMT == 0) &&  // Svpbmt not implemented
+            (flags & PTE_NAPOT == 0) && // use map_napot() instead
             (flags & PTE_RESERVED == 0) && // reserved for future standard use
             (flags & PTE_PPN == 0) // flags should not specify PPN
         );
+        self.check_pbmt(flags);
         assert!(level == 0 || level == 1 || level == 2); // level is valid
         assert!(flags & PTE_RWX != 0); // flags indicate leaf
 
+        let pte = self.walk_alloc(vaddr, level);
+
+        // check if leaf is already mapped
+        let old_ppn = pte.get_ppn();
+        let new_ppn = paddr2pte!(paddr);
+        if old_ppn != 0 && old_ppn != new_ppn {
+            debug!(
+                "Overwriting vaddr 0x{:x} mapping 0x{:x} -> 0x{:x}",
+                vaddr,
+                pte2paddr!(old_ppn),
+                paddr
+            );
+        }
+
+        // set leaf value
+        pte.set_ppn(new_ppn).set_flags(flags).validate();
+    }
+
+    /// Maps a naturally aligned 64KiB region using a single Svnapot translation.
+    ///
+    /// The region is described by 16 identical level 0 PTEs, which the hart is allowed to
+    /// cache as a single TLB entry. Must only be called if the hart supports Svnapot.
+    pub fn map_napot(&mut self, vaddr: u64, paddr: u64, flags: u64) {
+        assert!(has_extension(Extension::Svnapot));
+        assert!(vaddr % NAPOT_64K_SIZE == 0 && paddr % NAPOT_64K_SIZE == 0);
+        assert!((flags & PTE_RESERVED == 0) && (flags & PTE_PPN == 0));
+        self.check_pbmt(flags);
+        assert!(flags & PTE_RWX != 0);
+
+        // ppn[3:0] of a 64KiB NAPOT PTE is fixed at 0b1000
+        let ppn = paddr2pte!(paddr) | NAPOT_64K_PPN_BITS;
+        for i in 0..NAPOT_64K_PAGES {
+            let pte = self.walk_alloc(vaddr + i * PAGE_SIZE, 0);
+            pte.set_ppn(ppn).set_flags(flags | PTE_NAPOT).validate();
+        }
+    }
+
+    /// Map `[paddr, paddr + len)` to `[vaddr, vaddr + len)` as non-cacheable, strongly-ordered
+    /// I/O memory when Svpbmt is available. Otherwise the platform's PMAs are relied upon.
+    fn map_mmio(&mut self, vaddr: u64, paddr: u64, len: u64) {
+        let mut flags = PTE_R | PTE_W;
+        if has_extension(Extension::Svpbmt) {
+            flags |= PTE_PBMT_IO;
+        }
+        self.map_range(vaddr, paddr, paddr + len, flags);
+    }
+
+    fn check_pbmt(&self, flags: u64) {
+        assert!(flags & PTE_PBMT != PTE_PBMT); // encoding 3 is reserved
+        if flags & PTE_PBMT != PTE_PBMT_PMA {
+            assert!(has_extension(Extension::Svpbmt));
+        }
+    }
+
+    /// Find the PTE for `vaddr` at `level`, allocating intermediate page tables as needed.
+    fn walk_alloc(&mut self, vaddr: u64, level: usize) -> &mut PTE {
         // extract virtual page numbers from vaddr
         let vpn = [
             (vaddr >> 12) & 0x01ff, // vaddr[20:12] (9 bits)
@@ -104,68 +211,200 @@ impl PageTable {
                 unsafe {
                     (page as *mut PageTable).write_bytes(0x00, 1);
                 }
-                pte.set_ppn(paddr2pte!(page)).validate();
+                pte.set_ppn(paddr2pte!(virt_to_phys(page as u64)))
+                    .validate();
             }
-            let entry = pte2paddr!(pte.get_ppn()) as *mut PTE;
+            let entry = phys_to_virt(pte2paddr!(pte.get_ppn())) as *mut PTE;
             pte = unsafe { entry.add(vpn[l] as usize).as_mut().unwrap() };
         }
-
-        // check if leaf is already mapped
-        let old_ppn = pte.get_ppn();
-        let new_ppn = paddr2pte!(paddr);
-        if old_ppn != 0 && old_ppn != new_ppn {
-            debug!(
-                "Overwriting vaddr 0x{:x} mapping 0x{:x} -> 0x{:x}",
-                vaddr,
-                pte2paddr!(old_ppn),
-                paddr
-            );
-        }
-
-        // set leaf value
-        pte.set_ppn(new_ppn).set_flags(flags).validate();
+        pte
     }
 
     /// Add the necessary 4KB page mappings to map the address range `[paddr, paddr + len)` to [vaddr, vaddr + len)
+    ///
+    /// Naturally aligned 2MiB chunks are mapped with megapages. If the hart supports Svnapot,
+    /// naturally aligned 64KiB chunks are mapped with a single NAPOT translation instead of 16
+    /// pages. Both reduce TLB pressure.
     fn map_range(&mut self, mut vaddr: u64, paddr_start: u64, paddr_end: u64, flags: u64) {
         assert!(paddr_end > paddr_start);
-        let paddr_range = page_floor!(paddr_start)..page_ceil!(paddr_end);
-        for paddr in paddr_range.step_by(PAGE_SIZE as usize) {
-            self.map(vaddr, paddr, flags, 0);
-            vaddr += PAGE_SIZE;
+        let napot = has_extension(Extension::Svnapot);
+        let mut paddr = page_floor!(paddr_start);
+        let paddr_end = page_ceil!(paddr_end);
+        while paddr < paddr_end {
+            if vaddr % MEGAPAGE_SIZE == 0
+                && paddr % MEGAPAGE_SIZE == 0
+                && paddr_end - paddr >= MEGAPAGE_SIZE
+            {
+                self.map(vaddr, paddr, flags, 1);
+                vaddr += MEGAPAGE_SIZE;
+                paddr += MEGAPAGE_SIZE;
+            } else if napot
+                && vaddr % NAPOT_64K_SIZE == 0
+                && paddr % NAPOT_64K_SIZE == 0
+                && paddr_end - paddr >= NAPOT_64K_SIZE
+            {
+                self.map_napot(vaddr, paddr, flags);
+                vaddr += NAPOT_64K_SIZE;
+                paddr += NAPOT_64K_SIZE;
+            } else {
+                self.map(vaddr, paddr, flags, 0);
+                vaddr += PAGE_SIZE;
+                paddr += PAGE_SIZE;
+            }
         }
     }
 
+    /// Map the kernel virtual address range `[start, end)` to the physical memory backing it in
+    /// the direct map
+    fn map_kernel_range(&mut self, start: u64, end: u64, flags: u64) {
+        self.map_range(
+            page_floor!(start),
+            virt_to_phys(start),
+            virt_to_phys(end),
+            flags,
+        );
+    }
+
     /// Convert a virtual address to a physical address.
-    fn lookup(&self, vaddr: u64) -> Option<u64> {
+    pub fn lookup(&self, vaddr: u64) -> Option<u64> {
+        self.lookup_pte(vaddr).map(|(_, paddr)| paddr)
+    }
+
+    /// Find the leaf PTE that maps `vaddr`, along with the physical address `vaddr` translates to.
+    pub fn lookup_pte(&self, vaddr: u64) -> Option<(&PTE, u64)> {
+        let (pte, level) = self.find_leaf(vaddr)?;
+        let pte = unsafe { &*pte };
+        let offset_mask = pte.page_size(level) - 1;
+        let page = pte2paddr!(pte.get_ppn()) & !offset_mask;
+        Some((pte, page | (vaddr & offset_mask)))
+    }
+
+    /// Like `lookup_pte`, but the PTE can be modified. The caller is responsible for flushing
+    /// the TLB afterwards.
+    pub fn lookup_pte_mut(&mut self, vaddr: u64) -> Option<(&mut PTE, u64)> {
+        let (pte, level) = self.find_leaf(vaddr)?;
+        let pte = unsafe { &mut *pte };
+        let offset_mask = pte.page_size(level) - 1;
+        let page = pte2paddr!(pte.get_ppn()) & !offset_mask;
+        Some((pte, page | (vaddr & offset_mask)))
+    }
+
+    /// Find the leaf PTE that maps `vaddr` and its level
+    fn find_leaf(&self, vaddr: u64) -> Option<(*mut PTE, usize)> {
         // extract virtual page numbers from vaddr
         let vpn = [
             (vaddr >> 12) & 0x01ff, // vaddr[20:12] (9 bits)
             (vaddr >> 21) & 0x01ff, // vaddr[29:21] (9 bits)
             (vaddr >> 30) & 0x01ff, // vaddr[38:30] (9 bits)
         ];
-        let mut pte = &self.entries[vpn[2] as usize];
+        let mut pte = &self.entries[vpn[2] as usize] as *const PTE as *mut PTE;
         // navigate to new leaf position
         for l in (0..=2).rev() {
-            if !pte.is_valid() {
+            let entry = unsafe { &*pte };
+            if !entry.is_valid() {
                 return None;
             }
-            if pte.is_leaf() {
-                let offset_mask: u64 = !(!0 << (12 + 9 * l));
-                let page = pte2paddr!(pte.get_ppn());
-                return Some(page | (vaddr & offset_mask));
+            if entry.is_leaf() {
+                return Some((pte, l));
+            }
+            if l == 0 {
+                break;
             }
-            let entry = pte2paddr!(pte.get_ppn()) as *mut PTE;
-            pte = unsafe { entry.add(vpn[l - 1] as usize).as_mut().unwrap() };
+            let table = phys_to_virt(pte2paddr!(entry.get_ppn())) as *mut PTE;
+            pte = unsafe { table.add(vpn[l - 1] as usize) };
         }
         None
     }
 
+    /// Remove the mappings of `[vaddr, vaddr + len)` and flush them from every hart's TLB.
+    /// Pages that aren't mapped are skipped.
+    pub fn unmap(&mut self, vaddr: u64, len: u64) {
+        self.update_leaves(vaddr, len, |pte| pte.invalidate());
+        tlb::shootdown(vaddr..vaddr + len);
+    }
+
+    /// Change the permissions (PTE_R, PTE_W, PTE_X and PTE_USER) of the pages mapped in
+    /// `[vaddr, vaddr + len)` to `flags`, and flush the old ones from every hart's TLB
+    pub fn protect(&mut self, vaddr: u64, len: u64, flags: u64) {
+        assert!(flags & !(PTE_RWX | PTE_USER) == 0);
+        assert!(flags & PTE_RWX != 0); // flags indicate leaf
+        self.update_leaves(vaddr, len, |pte| {
+            pte.set_permissions(flags);
+        });
+        tlb::shootdown(vaddr..vaddr + len);
+    }
+
+    /// Call `f` on every leaf PTE mapping part of `[vaddr, vaddr + len)`
+    fn update_leaves(&mut self, vaddr: u64, len: u64, f: impl Fn(&mut PTE)) {
+        assert!(vaddr % PAGE_SIZE == 0 && len % PAGE_SIZE == 0);
+        let end = vaddr + len;
+        let mut addr = vaddr;
+        while addr < end {
+            let Some((pte, level)) = self.find_leaf(addr) else {
+                addr += PAGE_SIZE;
+                continue;
+            };
+            let size = unsafe { (*pte).page_size(level) };
+            if addr % size != 0 || end - addr < size {
+                // only part of a megapage or NAPOT region changes
+                self.split(addr);
+                continue;
+            }
+            if unsafe { (*pte).is_napot() } {
+                // the region's PTEs are next to each other, starting with this one
+                for i in 0..NAPOT_64K_PAGES as usize {
+                    f(unsafe { &mut *pte.add(i) });
+                }
+            } else {
+                f(unsafe { &mut *pte });
+            }
+            addr += size;
+        }
+    }
+
+    /// Replace the megapage, gigapage or NAPOT region that maps `vaddr` with translations of
+    /// the next size down, with the same flags, so that part of it can be changed. The TLB
+    /// still holds the old translation, which is equivalent until the caller changes it.
+    fn split(&mut self, vaddr: u64) {
+        let (pte, level) = self.find_leaf(vaddr).unwrap();
+        let leaf = unsafe { &mut *pte };
+        let flags = leaf.entry & !PTE_PPN;
+        if leaf.is_napot() {
+            // the region's PTEs are next to each other, and each is turned into an ordinary one
+            let first = unsafe { pte.sub(((vaddr / PAGE_SIZE) % NAPOT_64K_PAGES) as usize) };
+            let ppn = leaf.get_ppn() & !NAPOT_64K_PPN_MASK;
+            for i in 0..NAPOT_64K_PAGES {
+                let pte = unsafe { &mut *first.add(i as usize) };
+                pte.entry = (ppn + paddr2pte!(i * PAGE_SIZE)) | (flags & !PTE_NAPOT);
+            }
+            return;
+        }
+        assert!(level > 0);
+        let table = kalloc() as *mut PageTable;
+        assert!(!table.is_null(), "no memory to split a page");
+        let child_size = PAGE_SIZE << (9 * (level - 1));
+        let ppn = leaf.get_ppn();
+        for (i, child) in unsafe { &mut (*table).entries }.iter_mut().enumerate() {
+            child.entry = (ppn + paddr2pte!(i as u64 * child_size)) | flags;
+        }
+        // PTEs that point to a table have no other flags
+        leaf.entry = paddr2pte!(virt_to_phys(table as u64)) | PTE_VALID;
+    }
+
     pub fn free(&mut self) {
-        for i in 0..PAGE_TABLE_SIZE {
+        self.free_entries(0..PAGE_TABLE_SIZE);
+    }
+
+    /// Free a page table created by `create_user_table()`, leaving the kernel's half alone
+    pub fn free_user(&mut self) {
+        self.free_entries(0..USER_ENTRIES);
+    }
+
+    fn free_entries(&mut self, entries: core::ops::Range<usize>) {
+        for i in entries {
             let pte = &self.entries[i];
             if pte.is_valid() && !pte.is_leaf() {
-                let child = pte2paddr!(pte.get_ppn()) as *mut PageTable;
+                let child = phys_to_virt(pte2paddr!(pte.get_ppn())) as *mut PageTable;
                 unsafe {
                     (*child).free();
                 }
@@ -187,9 +426,20 @@ pub const _PTE_RSW: u64 = 0b11 << 8;
 pub const PTE_PPN: u64 = 0xfffffffffff << 10;
 pub const PTE_RESERVED: u64 = 0b111_1111 << 54;
 pub const PTE_PBMT: u64 = 0b11 << 61;
-pub const _PTE_NAPOT: u64 = 1 << 63;
+pub const PTE_PBMT_PMA: u64 = 0 << 61; // use the platform's memory attributes
+pub const PTE_PBMT_NC: u64 = 1 << 61; // non-cacheable, idempotent, weakly-ordered main memory
+pub const PTE_PBMT_IO: u64 = 2 << 61; // non-cacheable, non-idempotent, strongly-ordered I/O
+pub const PTE_NAPOT: u64 = 1 << 63;
 pub const PTE_RWX: u64 = PTE_R | PTE_W | PTE_X;
 
+const MEGAPAGE_SIZE: u64 = 512 * PAGE_SIZE;
+
+// Svnapot only defines a 64KiB contiguous page size (16 level 0 pages)
+const NAPOT_64K_PAGES: u64 = 16;
+const NAPOT_64K_SIZE: u64 = NAPOT_64K_PAGES * PAGE_SIZE;
+const NAPOT_64K_PPN_BITS: u64 = 0b1000 << 10;
+const NAPOT_64K_PPN_MASK: u64 = 0b1111 << 10;
+
 /// Page table entry
 impl PTE {
     pub fn validate(&mut self) {
@@ -204,6 +454,10 @@ impl PTE {
         self.entry & PTE_RWX != 0
     }
 
+    pub fn is_napot(&self) -> bool {
+        self.entry & PTE_NAPOT != 0
+    }
+
     pub fn set_ppn(&mut self, ppn: u64) -> &mut Self {
         assert!(ppn & !PTE_PPN == 0);
         self.entry = (self.entry & !PTE_PPN) | ppn;
@@ -214,8 +468,37 @@ impl PTE {
         self.entry & PTE_PPN
     }
 
+    /// Check whether all of `flags` are set
+    pub fn has_flags(&self, flags: u64) -> bool {
+        self.entry & flags == flags
+    }
+
     pub fn set_flags(&mut self, flags: u64) -> &mut Self {
         self.entry |= flags;
         self
     }
+
+    /// PTE_R, PTE_W, PTE_X and PTE_USER
+    pub fn permissions(&self) -> u64 {
+        self.entry & (PTE_RWX | PTE_USER)
+    }
+
+    /// Replace PTE_R, PTE_W, PTE_X and PTE_USER with those in `flags`
+    pub fn set_permissions(&mut self, flags: u64) -> &mut Self {
+        self.entry = (self.entry & !(PTE_RWX | PTE_USER)) | flags;
+        self
+    }
+
+    pub fn invalidate(&mut self) {
+        self.entry = 0;
+    }
+
+    /// Size of the region mapped by this leaf PTE, found at `level` of the page table
+    fn page_size(&self, level: usize) -> u64 {
+        if self.is_napot() {
+            NAPOT_64K_SIZE
+        } else {
+            PAGE_SIZE << (9 * level)
+        }
+    }
 }
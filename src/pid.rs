@@ -0,0 +1,97 @@
+/// Process ids
+///
+/// Pids are handed out from a bitmap in increasing order, wrapping around at PID_MAX, so a pid
+/// that was freed isn't normally seen again until the rest of the space has been gone through.
+/// On top of that, a freed pid is held back until REUSE_DELAY more pids have been freed, so it
+/// isn't reused straight away even when nearly every pid is taken.
+///
+/// Each pid in use maps to the index of its process in PROCS, for looking processes up by pid.
+/// Pid 0 is never handed out, as it marks a free slot in PROCS.
+use crate::{proc::MAX_PROCS, spinlock::Spinlock, util::CircularBuffer};
+
+pub type Pid = u32;
+
+/// Pids are below this
+pub const PID_MAX: usize = 32768;
+
+/// How many more pids have to be freed after a pid before it can be reused
+const REUSE_DELAY: usize = 64;
+
+const NO_SLOT: u16 = u16::MAX;
+
+const _: () = assert!(MAX_PROCS < NO_SLOT as usize);
+
+struct Pids {
+    used: [u64; PID_MAX / 64], // held back pids count as used
+    slots: [u16; PID_MAX],     // index in PROCS of the process with each pid
+    last: usize,               // pid handed out last, where the search for a free one starts
+    held_back: CircularBuffer<Pid, REUSE_DELAY>,
+}
+
+static PIDS: Spinlock<Pids> = Spinlock::new(Pids {
+    used: {
+        let mut used = [0; PID_MAX / 64];
+        used[0] = 1; // pid 0
+        used
+    },
+    slots: [NO_SLOT; PID_MAX],
+    last: 0,
+    held_back: CircularBuffer::new(),
+});
+
+impl Pids {
+    fn is_used(&self, pid: usize) -> bool {
+        self.used[pid / 64] & 1 << (pid % 64) != 0
+    }
+
+    fn set_used(&mut self, pid: usize, used: bool) {
+        if used {
+            self.used[pid / 64] |= 1 << (pid % 64);
+        } else {
+            self.used[pid / 64] &= !(1 << (pid % 64));
+        }
+    }
+
+    /// The first free pid after `last`, wrapping around
+    fn find_free(&self) -> Option<usize> {
+        (1..=PID_MAX)
+            .map(|i| (self.last + i) % PID_MAX)
+            .find(|&pid| !self.is_used(pid))
+    }
+}
+
+/// Allocate a pid for the process at index `slot` in PROCS. Returns None if every pid is taken
+/// or held back.
+pub fn alloc(slot: usize) -> Option<Pid> {
+    let mut pids = PIDS.lock();
+    let pid = pids.find_free()?;
+    pids.set_used(pid, true);
+    pids.slots[pid] = slot as u16;
+    pids.last = pid;
+    Some(pid as Pid)
+}
+
+/// Give back `pid`, which will be reused after REUSE_DELAY more pids are freed
+pub fn free(pid: Pid) {
+    let mut pids = PIDS.lock();
+    assert!(
+        pids.slots[pid as usize] != NO_SLOT,
+        "pid {} freed twice",
+        pid
+    );
+    pids.slots[pid as usize] = NO_SLOT;
+    if pids.held_back.is_full() {
+        let oldest = pids.held_back.read().unwrap();
+        pids.set_used(oldest as usize, false);
+    }
+    pids.held_back.write(pid);
+}
+
+/// Index in PROCS of the process with pid `pid`
+pub fn lookup(pid: Pid) -> Option<usize> {
+    let pids = PIDS.lock();
+    match pids.slots.get(pid as usize) {
+        Some(&NO_SLOT) | None => None,
+        Some(&slot) => Some(slot as usize),
+    }
+}
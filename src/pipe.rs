@@ -0,0 +1,137 @@
+/// Anonymous pipes
+///
+/// A pipe is a ring buffer with a read end and a write end, each an open file (see file.rs).
+/// Readers sleep while it is empty and writers while it is full, on a wait queue per pipe.
+/// Once every write end is closed, reads of an empty pipe return end of file; once every read
+/// end is closed, writes fail with EPIPE and send the writer SIGPIPE.
+///
+/// The buffer is only touched with the pipe's lock held, which serializes readers and writers:
+/// each read takes a contiguous run of bytes, even with several processes reading one pipe.
+///
+/// A write of up to PIPE_BUF bytes goes into the pipe all at once, so it isn't interleaved
+/// with other writes. A longer write takes whatever room there is and returns a short count,
+/// since a syscall that sleeps starts over once woken (see wait.rs) and can't remember how
+/// much it wrote before.
+use crate::{
+    cpu,
+    signal::{self, SIGPIPE},
+    spinlock::Spinlock,
+    syscall::{self, EAGAIN, EPIPE},
+    util::CircularBuffer,
+    wait::WaitQueue,
+};
+
+pub const MAX_PIPES: usize = 16;
+
+/// Bytes a pipe holds
+pub const PIPE_SIZE: usize = 4096;
+
+/// Writes up to this long are atomic
+pub const PIPE_BUF: usize = 512;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum End {
+    Read,
+    Write,
+}
+
+struct Pipe {
+    buffer: CircularBuffer<u8, PIPE_SIZE>,
+    readers: usize, // open files for each end
+    writers: usize,
+}
+
+struct Slot {
+    pipe: Spinlock<Pipe>, // free while it has neither readers nor writers
+    readable: WaitQueue,  // readers waiting for data
+    writable: WaitQueue,  // writers waiting for room
+}
+
+const FREE_SLOT: Slot = Slot {
+    pipe: Spinlock::new(Pipe {
+        buffer: CircularBuffer::new(),
+        readers: 0,
+        writers: 0,
+    }),
+    readable: WaitQueue::new(),
+    writable: WaitQueue::new(),
+};
+static PIPES: [Slot; MAX_PIPES] = [FREE_SLOT; MAX_PIPES];
+
+/// Make an empty pipe with one reader and one writer. Returns its index in PIPES.
+pub fn create() -> Option<usize> {
+    PIPES.iter().position(|slot| {
+        let mut pipe = slot.pipe.lock();
+        let free = pipe.readers == 0 && pipe.writers == 0;
+        if free {
+            pipe.buffer = CircularBuffer::new();
+            pipe.readers = 1;
+            pipe.writers = 1;
+        }
+        free
+    })
+}
+
+/// An open file for `end` of pipe `index` was closed. Whoever was waiting for the other end
+/// is woken once its last one is gone.
+pub fn close(index: usize, end: End) {
+    let slot = &PIPES[index];
+    let mut pipe = slot.pipe.lock();
+    let (count, waiting) = match end {
+        End::Read => (&mut pipe.readers, &slot.writable),
+        End::Write => (&mut pipe.writers, &slot.readable),
+    };
+    *count -= 1;
+    let last = *count == 0;
+    drop(pipe);
+    if last {
+        waiting.wake_all();
+    }
+}
+
+/// Read from pipe `index` into `buf`, sleeping while it is empty unless `nonblock` is set.
+/// Returns 0 at end of file.
+pub fn read(index: usize, buf: &mut [u8], nonblock: bool) -> syscall::Result {
+    let slot = &PIPES[index];
+    let mut pipe = slot.pipe.lock();
+    let n = pipe.buffer.read_into(buf);
+    if n > 0 {
+        drop(pipe);
+        slot.writable.wake_all();
+        return Ok(n as u64);
+    }
+    if buf.is_empty() || pipe.writers == 0 {
+        return Ok(0);
+    }
+    if nonblock {
+        return Err(EAGAIN);
+    }
+    slot.readable.wait_syscall(pipe)
+}
+
+/// Write `buf` to pipe `index`, sleeping while there isn't room unless `nonblock` is set
+pub fn write(index: usize, buf: &[u8], nonblock: bool) -> syscall::Result {
+    let slot = &PIPES[index];
+    let mut pipe = slot.pipe.lock();
+    if pipe.readers == 0 {
+        drop(pipe);
+        signal::send(unsafe { cpu!().current_proc }, SIGPIPE);
+        return Err(EPIPE);
+    }
+    let free = pipe.buffer.free();
+    let fits = if buf.len() <= PIPE_BUF {
+        free >= buf.len()
+    } else {
+        free > 0
+    };
+    if !fits && nonblock {
+        return Err(EAGAIN);
+    }
+    if !fits {
+        return slot.writable.wait_syscall(pipe);
+    }
+    let n = pipe.buffer.write_from(buf);
+    drop(pipe);
+    slot.readable.wake_all();
+    Ok(n as u64)
+}
@@ -1,13 +1,13 @@
 use core::mem::variant_count;
 
-use crate::kmem::PLIC_BASE;
+use crate::kmem::{phys_to_virt, PLIC_BASE};
 use crate::mmio::{MMIODevice, MMIORegister, RPerm, RWPerm, WPerm};
 use crate::reg_read;
 
 /// Manages the Platform Level Interrupt Controller
 /// https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
 
-const PLIC_MMIO: MMIODevice<u32> = MMIODevice::new(PLIC_BASE);
+const PLIC_MMIO: MMIODevice<u32> = MMIODevice::new(phys_to_virt(PLIC_BASE));
 
 const PRIORITY0: MMIORegister<u32, WPerm> = PLIC_MMIO.reg(0x0000);
 const PENDING0: MMIORegister<u32, RWPerm> = PLIC_MMIO.reg(0x1000);
@@ -23,23 +23,30 @@ pub enum PlicPrivilege {
 }
 
 impl PlicPrivilege {
+    /// Context of this privilege level on the current hart
     fn context(&self) -> usize {
-        unsafe {
-            let context =
-                (reg_read!(tp) as usize * variant_count::<PlicPrivilege>()) + *self as usize;
-            assert!(context < 15872);
-            context
-        }
+        self.context_of(unsafe { reg_read!(tp) } as usize)
+    }
+
+    fn context_of(&self, hartid: usize) -> usize {
+        let context = (hartid * variant_count::<PlicPrivilege>()) + *self as usize;
+        assert!(context < 15872);
+        context
     }
 }
 
 pub fn enable(privilege: PlicPrivilege, irq: u32) {
+    enable_on(unsafe { reg_read!(tp) } as usize, privilege, irq)
+}
+
+/// Enable `irq` for `privilege` on hart `hartid`, which need not be the current hart
+pub fn enable_on(hartid: usize, privilege: PlicPrivilege, irq: u32) {
     assert!(irq != 0 && irq < 1024);
     let (reg, bit) = (irq / 32, irq % 32);
     assert!(reg < 32 && bit < 32);
     unsafe {
         let enable = ENABLE00
-            .byte_add(0x80 * privilege.context())
+            .byte_add(0x80 * privilege.context_of(hartid))
             .add(reg as usize);
         enable.write(enable.read() | (1 << bit))
     }
@@ -65,5 +72,5 @@ pub fn claim(privilege: PlicPrivilege) -> u32 {
 }
 
 pub fn complete(privilege: PlicPrivilege, irq: u32) {
-    unsafe { COMPLETE0.add(0x1000 * privilege.context()).write(irq) }
+    unsafe { COMPLETE0.byte_add(0x1000 * privilege.context()).write(irq) }
 }
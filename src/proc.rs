@@ -1,8 +1,23 @@
-use core::mem::MaybeUninit;
+use core::{arch::asm, mem::MaybeUninit};
 
 use crate::{
-    kmem::{kalloc, kfree, PAGE_SIZE},
-    mmu::{PageTable, PTE_R, PTE_USER, PTE_W, PTE_X},
+    cpu,
+    csr::{SATP_MODE, SATP_MODE_SV39, SATP_PPN},
+    csr_write, csr_write_field, fdt,
+    file::FdTable,
+    fp::FpState,
+    kmem::{kalloc, kfree, virt_to_phys, PAGE_SIZE},
+    mmu::{self, PageTable, PTE_R, PTE_USER, PTE_W, PTE_X},
+    page_number,
+    pid::{self, Pid},
+    sched::{self, Entity, Task, RUN_LOCK},
+    signal::{self, Actions, Signals, SIGCHLD, SIGKILL, SIGRETURN_ADDR, SIG_IGN},
+    spinlock::Spinlock,
+    syscall::{EAGAIN, ECHILD, EDEADLK, EINVAL, ENOMEM, ESRCH},
+    trap,
+    uaccess::copy_to_user,
+    vector::VectorState,
+    wait::WaitQueue,
 };
 
 #[macro_export]
@@ -12,12 +27,16 @@ macro_rules! proc {
     };
 }
 
+/// State of a process saved by user_vec. The layout up to `regs` must match trap.s.
+#[repr(C)]
 pub struct TrapFrame {
     pub kernel_satp: u64,
     pub kernel_sp: u64,
     pub epc: u64,
     pub kernel_hartid: u64,
     pub regs: [u64; 32],
+    pub fp: FpState,
+    pub vector: VectorState,
 }
 
 pub enum ProcessState {
@@ -27,23 +46,65 @@ pub enum ProcessState {
     Dead,
 }
 
+/// A thread of a user process. The first thread of a process is its leader, and its entry
+/// holds what all of the threads share: the page table, open files and signal actions. The
+/// leader's entry stays until the last thread has exited, even if the leader itself exited
+/// earlier, and it is what the parent waits for.
 pub struct Process {
     pub frame: MaybeUninit<TrapFrame>,
-    pub stack: MaybeUninit<[*mut u8; STACK_PAGES as usize]>,
+    pub stack: MaybeUninit<[*mut u8; STACK_PAGES as usize]>, // null if the stack came from user space
+    pub stack_base: u64,
     pub pc: u64,
-    pub pid: u16,
-    pub root: *mut PageTable,
+    pub pid: Pid,             // thread id; 0 for a free slot in PROCS
+    pub tgid: Pid,            // pid of the leader, which is the pid of the process
+    pub leader: usize,        // index of the leader in PROCS
+    pub parent: Pid,          // 0 once the parent has exited, and for threads other than the leader
+    pub root: *mut PageTable, // shared by the threads, owned by the leader
     pub state: ProcessState,
+    pub exit_status: u32,     // wait status once Dead
+    pub clear_child_tid: u64, // user address zeroed when the thread exits, or 0
+    pub sched: Entity,
+    pub signals: Signals,
+    pub actions: Spinlock<Actions>, // leader only
+    pub files: Spinlock<FdTable>,   // leader only
+    pub live_threads: usize,        // leader only: threads that haven't exited
+    pub exiting: bool,              // leader only: exit_group has been called
+    pub misaligned_loads: u64,      // misaligned accesses emulated by the kernel
+    pub misaligned_stores: u64,
 }
 
-pub const NPROC: usize = 64;
-pub static mut PROCS: MaybeUninit<[Process; NPROC]> = MaybeUninit::zeroed();
+/// Room in PROCS. How many processes can exist at once is set by `limit`.
+pub const MAX_PROCS: usize = 256;
+pub static mut PROCS: MaybeUninit<[Process; MAX_PROCS]> = MaybeUninit::zeroed();
+
+/// Process limit unless the kernel command line has `maxprocs=<n>`
+const DEFAULT_LIMIT: usize = 64;
+
+static mut LIMIT: usize = DEFAULT_LIMIT;
+
+/// Held while processes exit or are waited for, which keeps parents and children consistent
+pub static EXIT_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// Parents waiting in wait4 for a child to exit
+static CHILD_EXIT: WaitQueue = WaitQueue::new();
+
+/// Threads waiting in thread_join for another thread to exit
+static THREAD_EXIT: WaitQueue = WaitQueue::new();
 
 pub const STACK_ADDR: u64 = 0x1_0000_0000;
 pub const STACK_PAGES: u64 = 4;
 pub const PROC_STARTING_ADDR: u64 = 0x2000_0000;
 
-static mut NEXT_PID: u16 = 1;
+/// Where the stacks of threads other than the leader go. Each thread gets the slot for its
+/// index in PROCS, with an unmapped guard page at the bottom.
+const THREAD_STACKS: u64 = STACK_ADDR + 0x100_0000;
+const THREAD_STACK_SLOT: u64 = (STACK_PAGES + 1) * PAGE_SIZE;
+
+#[derive(Debug)]
+pub enum SpawnError {
+    TooManyProcesses,
+    OutOfPids,
+}
 
 impl Process {
     // TODO: upgrade executable from function to ELF
@@ -51,16 +112,31 @@ impl Process {
         let mut new_proc = Process {
             frame: MaybeUninit::zeroed(),
             stack: MaybeUninit::zeroed(),
+            stack_base: STACK_ADDR,
             pc: PROC_STARTING_ADDR,
-            pid: unsafe { NEXT_PID },
-            root: kalloc() as *mut PageTable,
+            pid: 0,    // set by spawn
+            tgid: 0,   // set by spawn
+            leader: 0, // set by spawn
+            parent: 0, // set by spawn
+            root: mmu::create_user_table(),
             state: ProcessState::Waiting,
+            exit_status: 0,
+            clear_child_tid: 0,
+            sched: Entity::default(), // set by spawn
+            signals: Signals::new(0),
+            actions: Spinlock::new(Actions::new()),
+            files: Spinlock::new(FdTable::new()), // set by spawn
+            live_threads: 1,
+            exiting: false,
+            misaligned_loads: 0,
+            misaligned_stores: 0,
         };
         unsafe {
-            NEXT_PID += 1;
-        }
-        unsafe {
-            new_proc.frame.assume_init_mut().regs[2] = STACK_ADDR + PAGE_SIZE * 1;
+            let frame = new_proc.frame.assume_init_mut();
+            frame.regs[2] = STACK_ADDR + PAGE_SIZE * 1;
+            frame.epc = PROC_STARTING_ADDR;
+            frame.fp = FpState::new();
+            frame.vector = VectorState::new();
         }
 
         // set up memory mappings
@@ -70,22 +146,605 @@ impl Process {
         }
         for i in 0..STACK_PAGES {
             let vaddr = STACK_ADDR + i * PAGE_SIZE;
-            let paddr = kalloc() as u64;
-            assert!(paddr != 0); // TODO: handle alloc failure
-            pt.map(vaddr, paddr, PTE_USER | PTE_R | PTE_W, 0);
+            let page = kalloc();
+            assert!(!page.is_null()); // TODO: handle alloc failure
+            unsafe {
+                new_proc.stack.assume_init_mut()[i as usize] = page;
+            }
+            pt.map(
+                vaddr,
+                virt_to_phys(page as u64),
+                PTE_USER | PTE_R | PTE_W,
+                0,
+            );
         }
-        pt.map(PROC_STARTING_ADDR, func as u64, PTE_USER | PTE_R | PTE_X, 0);
+        pt.map(
+            PROC_STARTING_ADDR,
+            virt_to_phys(func as u64),
+            PTE_USER | PTE_R | PTE_X,
+            0,
+        );
+        // where signal handlers return to
+        pt.map(
+            SIGRETURN_ADDR,
+            signal::trampoline_page(),
+            PTE_USER | PTE_R | PTE_X,
+            0,
+        );
 
         new_proc
     }
 }
 
+/// Read the process limit from the kernel command line
+pub fn init() {
+    let limit = fdt::get()
+        .and_then(|fdt| fdt.find_node("chosen"))
+        .and_then(|chosen| chosen.property_str("bootargs"))
+        .and_then(|args| {
+            args.split_whitespace()
+                .find_map(|arg| arg.strip_prefix("maxprocs="))
+        })
+        .map(|value| value.parse::<usize>());
+    match limit {
+        Some(Ok(limit)) if (1..=MAX_PROCS).contains(&limit) => unsafe { LIMIT = limit },
+        Some(_) => debug!("maxprocs must be between 1 and {}", MAX_PROCS),
+        None => {}
+    }
+    debug!("Allowing up to {} processes", self::limit());
+}
+
+/// How many processes can exist at once
+pub fn limit() -> usize {
+    unsafe { LIMIT }
+}
+
+/// A free slot in PROCS and a pid for it. Must be called with RUN_LOCK held.
+fn alloc_slot(procs: &[Process; MAX_PROCS]) -> Result<(usize, Pid), SpawnError> {
+    let count = procs.iter().filter(|proc| proc.pid != 0).count();
+    let index = match procs.iter().position(|proc| proc.pid == 0) {
+        Some(index) if count < limit() => index,
+        _ => return Err(SpawnError::TooManyProcesses),
+    };
+    let pid = pid::alloc(index).ok_or(SpawnError::OutOfPids)?;
+    Ok((index, pid))
+}
+
+/// Create a process that runs `func`, which may start running on any hart as soon as this
+/// returns. Returns its pid. Called by a process, it makes a child with a copy of the caller's
+/// descriptor table, as `fork` does.
+pub fn spawn(func: fn()) -> Result<Pid, SpawnError> {
+    let process = Process::new(func);
+    // before taking RUN_LOCK, since closing a file can take it (see file.rs)
+    let (parent, files) = match sched::current() {
+        Some(Task::Process(parent)) => {
+            let leader = leader(parent);
+            (leader.pid, leader.files.lock().copy())
+        }
+        _ => (0, FdTable::console()),
+    };
+    *process.files.lock() = files;
+    let guard = RUN_LOCK.lock();
+    let procs = unsafe { PROCS.assume_init_mut() };
+    let (index, pid) = match alloc_slot(procs) {
+        Ok(slot) => slot,
+        Err(err) => {
+            drop(guard);
+            drop(process);
+            return Err(err);
+        }
+    };
+
+    // the slot is either zeroed or was moved out of by reap, so there's nothing to drop
+    let proc = &mut procs[index];
+    unsafe {
+        core::ptr::write(proc, process);
+    }
+    proc.pid = pid;
+    proc.tgid = pid;
+    proc.leader = index;
+    proc.parent = parent;
+    proc.sched = Entity::new();
+    proc.sched.group = Some(index);
+    sched::enqueue(Task::Process(index));
+    Ok(pid)
+}
+
+/// Index in PROCS of the process with pid `pid`
+pub fn find(pid: Pid) -> Option<usize> {
+    let index = pid::lookup(pid)?;
+    let procs = unsafe { PROCS.assume_init_ref() };
+    (procs[index].pid == pid).then_some(index)
+}
+
+/// The leader of the process that thread `index` belongs to
+pub fn leader(index: usize) -> &'static mut Process {
+    let procs = unsafe { PROCS.assume_init_mut() };
+    &mut procs[procs[index].leader]
+}
+
+/// Indices in PROCS of the threads of the process with pid `pid`, including the leader and
+/// threads that have exited but haven't been reaped. None if `pid` isn't the pid of a process.
+pub fn threads(pid: Pid) -> impl Iterator<Item = usize> {
+    let procs = unsafe { PROCS.assume_init_ref() };
+    let leader = find(pid).filter(|&index| procs[index].leader == index);
+    (0..MAX_PROCS).filter(move |&index| {
+        let proc = &procs[index];
+        leader.is_some_and(|leader| proc.pid != 0 && proc.leader == leader)
+    })
+}
+
+/// Free the slot and the pid of dead process `index`, returning the process. Dropping it frees
+/// the rest, which unmaps stacks and so calls other harts to flush their TLBs, so it must not
+/// be dropped with EXIT_LOCK held: another hart may be spinning on it with interrupts off.
+#[must_use]
+pub fn reap(index: usize) -> Process {
+    let guard = RUN_LOCK.lock();
+    let proc = unsafe { &mut PROCS.assume_init_mut()[index] };
+    assert!(
+        matches!(proc.state, ProcessState::Dead),
+        "reaping a live process"
+    );
+    let pid = proc.pid;
+    let process = unsafe { core::ptr::read(proc) };
+    proc.pid = 0;
+    drop(guard);
+    pid::free(pid);
+    process
+}
+
+/// Wait status of a process that exited with `code`
+pub fn exited_status(code: u64) -> u32 {
+    ((code & 0xff) << 8) as u32
+}
+
+/// Wait status of a process terminated by `signal`
+pub fn killed_status(signal: usize) -> u32 {
+    signal as u32 & 0x7f
+}
+
+impl Process {
+    /// Whether the process has ended and only its exit status is left for the parent
+    fn is_zombie(&self) -> bool {
+        matches!(self.state, ProcessState::Dead) && self.live_threads == 0
+    }
+}
+
+/// End the thread running on this hart with wait status `status`. If it was the last thread of
+/// its process, the process ends with it: its parent is sent SIGCHLD and collects the status
+/// with wait4, unless there's no parent or it ignores SIGCHLD, in which case the process is
+/// reaped straight away. Its children are orphaned.
+pub fn exit(status: u32) -> ! {
+    let index = unsafe { cpu!().current_proc };
+    let procs = unsafe { PROCS.assume_init_mut() };
+    let leader = procs[index].leader;
+    let exit_guard = EXIT_LOCK.lock();
+
+    // let a joiner see that the thread is gone, as with CLONE_CHILD_CLEARTID in Linux
+    let clear_child_tid = procs[index].clear_child_tid;
+    if clear_child_tid != 0 {
+        let pt = unsafe { &*procs[index].root };
+        let _ = copy_to_user(pt, clear_child_tid, &0u32.to_ne_bytes());
+    }
+    {
+        let _guard = RUN_LOCK.lock();
+        let proc = &mut procs[index];
+        proc.state = ProcessState::Dead;
+        if index != leader {
+            proc.exit_status = status;
+        }
+        sched::switched_out(Task::Process(index));
+    }
+    // The last thread stays counted until the other threads' slots are dropped below: they
+    // unmap their stacks from the leader's page table, which goes away once the leader has been
+    // waited for, and it can't be while it has live threads.
+    let group = &mut procs[leader];
+    let last = group.live_threads == 1;
+    if last {
+        if !group.exiting {
+            group.exit_status = status;
+        }
+    } else {
+        group.live_threads -= 1;
+    }
+
+    // the page table goes away with the last thread, and this hart may not get to run another
+    // process before then
+    unsafe {
+        let kernel_table = mmu::kernel_table() as *mut PageTable;
+        csr_write_field!(
+            satp,
+            SATP_PPN,
+            page_number!(virt_to_phys(kernel_table as u64))
+        );
+        asm!("sfence.vma");
+    }
+    if !last {
+        THREAD_EXIT.wake_all();
+        drop(exit_guard);
+        sched::idle()
+    }
+
+    // threads that nobody joined, which nobody can join now that none is left running
+    let pid = procs[leader].pid;
+    let mut threads_left = [false; MAX_PROCS];
+    for thread in threads(pid).filter(|&thread| thread != leader) {
+        threads_left[thread] = true;
+    }
+    drop(exit_guard);
+    for thread in (0..MAX_PROCS).filter(|&thread| threads_left[thread]) {
+        drop(reap(thread));
+    }
+
+    let exit_guard = EXIT_LOCK.lock();
+    procs[leader].live_threads -= 1;
+    let reaped = end_process(leader);
+    drop(exit_guard);
+    for index in (0..MAX_PROCS).filter(|&index| reaped[index]) {
+        drop(reap(index));
+    }
+    sched::idle()
+}
+
+/// Clean up after the last thread of process `leader` exited and its other threads were reaped.
+/// Must be called with EXIT_LOCK held. Returns the slots in PROCS to reap once it is released,
+/// which nothing else will reap in the meantime.
+fn end_process(leader: usize) -> [bool; MAX_PROCS] {
+    let procs = unsafe { PROCS.assume_init_mut() };
+    let pid = procs[leader].pid;
+    debug!(
+        "Process {} exited with status 0x{:x}",
+        pid, procs[leader].exit_status
+    );
+    let files = procs[leader].files.lock().take();
+    files.close_all();
+    let mut reaped = [false; MAX_PROCS];
+    for child in 0..MAX_PROCS {
+        if procs[child].pid != 0 && procs[child].parent == pid {
+            procs[child].parent = 0;
+            reaped[child] = procs[child].is_zombie();
+        }
+    }
+
+    // the parent's first thread may have exited, so it goes to any of them
+    let parent = procs[leader].parent;
+    match find(parent) {
+        Some(index) if procs[index].actions.lock().get(SIGCHLD).handler != SIG_IGN => {
+            signal::kill(parent, SIGCHLD);
+            CHILD_EXIT.wake_all();
+        }
+        _ => {
+            // so that the parent can't wait for it
+            procs[leader].parent = 0;
+            reaped[leader] = true;
+        }
+    }
+    reaped
+}
+
+/// End every thread of the process that the thread running on this hart belongs to, with wait
+/// status `status`
+pub fn exit_group(status: u32) -> ! {
+    let index = unsafe { cpu!().current_proc };
+    let procs = unsafe { PROCS.assume_init_mut() };
+    let guard = EXIT_LOCK.lock();
+    let leader = &mut procs[procs[index].leader];
+    // the first thread to end the process decides how it ended
+    if !leader.exiting {
+        leader.exiting = true;
+        leader.exit_status = status;
+    }
+    // the others die on their way back to user mode
+    for thread in threads(leader.pid).filter(|&thread| thread != index) {
+        if !matches!(procs[thread].state, ProcessState::Dead) {
+            signal::send(thread, SIGKILL);
+        }
+    }
+    drop(guard);
+    exit(status)
+}
+
+/// wait4() for the process running on this hart: reap a dead child, or any one for `pid`
+/// None, and return its pid and wait status. Returns None if no child has exited yet and
+/// `nohang` is set, or sleeps until one does.
+pub fn wait(pid: Option<Pid>, nohang: bool) -> Result<Option<(Pid, u32)>, i64> {
+    let procs = unsafe { PROCS.assume_init_ref() };
+    let parent = unsafe { crate::proc!().tgid };
+    let is_child = |proc: &Process| {
+        proc.pid != 0 && proc.parent == parent && pid.map_or(true, |pid| proc.pid == pid)
+    };
+    let guard = EXIT_LOCK.lock();
+    if !procs.iter().any(is_child) {
+        return Err(ECHILD);
+    }
+    let dead = procs
+        .iter()
+        .position(|proc| is_child(proc) && proc.is_zombie());
+    match dead {
+        Some(child) => {
+            let reaped = (procs[child].pid, procs[child].exit_status);
+            let process = reap(child);
+            drop(guard);
+            drop(process);
+            Ok(Some(reaped))
+        }
+        None if nohang => Ok(None),
+        None => CHILD_EXIT.wait_syscall(guard).map(|_| None),
+    }
+}
+
+/// Start a thread in the process running on this hart. It returns from the current syscall
+/// like the calling thread, with the same registers except for a0, which is 0, sp, which is
+/// `sp` or the top of a stack made for it if `sp` is 0, and tp, which is `tls` if there is one.
+/// It starts with floating point and vectors off, as if it hadn't used them yet. Returns the
+/// new thread's tid.
+pub fn clone_thread(sp: u64, tls: Option<u64>, clear_child_tid: u64) -> Result<Pid, i64> {
+    let current = unsafe { cpu!().current_proc };
+    let procs = unsafe { PROCS.assume_init_mut() };
+    let leader = procs[current].leader;
+
+    let mut pages = [core::ptr::null_mut(); STACK_PAGES as usize];
+    if sp == 0 {
+        for i in 0..pages.len() {
+            pages[i] = kalloc();
+            if pages[i].is_null() {
+                pages[..i].iter().for_each(|&page| kfree(page));
+                return Err(ENOMEM);
+            }
+        }
+    }
+
+    // a thread started while the process is being killed would be missed
+    let _exit_guard = EXIT_LOCK.lock();
+    let guard = RUN_LOCK.lock();
+    let slot = match alloc_slot(procs) {
+        Ok(slot) if !procs[leader].exiting => Ok(slot),
+        Ok((_, pid)) => {
+            pid::free(pid);
+            Err(EAGAIN)
+        }
+        Err(_) => Err(EAGAIN),
+    };
+    let (index, pid) = match slot {
+        Ok(slot) => slot,
+        Err(errno) => {
+            drop(guard);
+            pages
+                .iter()
+                .filter(|page| !page.is_null())
+                .for_each(|&page| kfree(page));
+            return Err(errno);
+        }
+    };
+
+    let parent = &procs[current];
+    let root = parent.root;
+    let mut thread = Process {
+        frame: MaybeUninit::zeroed(),
+        stack: MaybeUninit::new(pages),
+        stack_base: 0,
+        pc: parent.pc,
+        pid,
+        tgid: parent.tgid,
+        leader,
+        parent: 0,
+        root,
+        state: ProcessState::Waiting,
+        exit_status: 0,
+        clear_child_tid,
+        sched: Entity::new(),
+        signals: Signals::new(parent.signals.blocked()),
+        actions: Spinlock::new(Actions::new()),
+        files: Spinlock::new(FdTable::new()),
+        live_threads: 0,
+        exiting: false,
+        misaligned_loads: 0,
+        misaligned_stores: 0,
+    };
+    thread.sched.group = Some(leader);
+    let frame = unsafe { thread.frame.assume_init_mut() };
+    let parent_frame = unsafe { parent.frame.assume_init_ref() };
+    frame.regs = parent_frame.regs;
+    frame.epc = parent_frame.epc;
+    frame.fp = FpState::new();
+    frame.vector = VectorState::new();
+    frame.regs[10] = 0; // a0
+    frame.regs[2] = sp;
+    if let Some(tls) = tls {
+        frame.regs[4] = tls; // tp
+    }
+    if sp == 0 {
+        let pt = unsafe { &mut *root };
+        thread.stack_base = THREAD_STACKS + index as u64 * THREAD_STACK_SLOT + PAGE_SIZE;
+        for (i, &page) in pages.iter().enumerate() {
+            let vaddr = thread.stack_base + i as u64 * PAGE_SIZE;
+            pt.map(
+                vaddr,
+                virt_to_phys(page as u64),
+                PTE_USER | PTE_R | PTE_W,
+                0,
+            );
+        }
+        frame.regs[2] = thread.stack_base + STACK_PAGES * PAGE_SIZE;
+    }
+
+    // the slot is either zeroed or was moved out of by reap, so there's nothing to drop
+    unsafe {
+        core::ptr::write(&mut procs[index], thread);
+    }
+    procs[leader].live_threads += 1;
+    sched::enqueue(Task::Process(index));
+    Ok(pid)
+}
+
+/// Make a child of the process running on this hart, with a copy of its stack and descriptor
+/// table, sharing its open files, and the same code and signal actions. The child has a single
+/// thread, which returns from the current syscall like the calling thread with a0 set to 0,
+/// and with floating point and vectors off as in `clone_thread`. The calling thread must be
+/// running on the process's stack, since the stacks of other threads aren't copied. Returns
+/// the child's pid.
+pub fn fork() -> Result<Pid, i64> {
+    let current = unsafe { cpu!().current_proc };
+    let procs = unsafe { PROCS.assume_init_mut() };
+    let parent = &procs[current];
+    let parent_frame = unsafe { parent.frame.assume_init_ref() };
+    let sp = parent_frame.regs[2];
+    if !(STACK_ADDR..=STACK_ADDR + STACK_PAGES * PAGE_SIZE).contains(&sp) {
+        return Err(EINVAL);
+    }
+    let leader = parent.leader;
+    let parent_pt = unsafe { &*parent.root };
+    let code = parent_pt.lookup(PROC_STARTING_ADDR).ok_or(EINVAL)?;
+
+    let mut child = Process {
+        frame: MaybeUninit::zeroed(),
+        stack: MaybeUninit::new([core::ptr::null_mut(); STACK_PAGES as usize]),
+        stack_base: STACK_ADDR,
+        pc: parent.pc,
+        pid: 0,    // set below
+        tgid: 0,   // set below
+        leader: 0, // set below
+        parent: procs[leader].pid,
+        root: mmu::create_user_table(),
+        state: ProcessState::Waiting,
+        exit_status: 0,
+        clear_child_tid: 0,
+        sched: Entity::new(),
+        signals: Signals::new(parent.signals.blocked()),
+        actions: Spinlock::new(procs[leader].actions.lock().clone()),
+        // before taking RUN_LOCK, since closing a file can take it (see file.rs)
+        files: Spinlock::new(procs[leader].files.lock().copy()),
+        live_threads: 1,
+        exiting: false,
+        misaligned_loads: 0,
+        misaligned_stores: 0,
+    };
+    let frame = unsafe { child.frame.assume_init_mut() };
+    frame.regs = parent_frame.regs;
+    frame.epc = parent_frame.epc;
+    frame.fp = FpState::new();
+    frame.vector = VectorState::new();
+    frame.regs[10] = 0; // a0
+
+    let pt = unsafe { &mut *child.root };
+    let parent_stack = unsafe { procs[leader].stack.assume_init() };
+    let stack = unsafe { child.stack.assume_init_mut() };
+    for i in 0..STACK_PAGES as usize {
+        let page = kalloc();
+        if page.is_null() {
+            // dropping the child frees what it got so far
+            return Err(ENOMEM);
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(parent_stack[i], page, PAGE_SIZE as usize);
+        }
+        stack[i] = page;
+        pt.map(
+            STACK_ADDR + i as u64 * PAGE_SIZE,
+            virt_to_phys(page as u64),
+            PTE_USER | PTE_R | PTE_W,
+            0,
+        );
+    }
+    pt.map(PROC_STARTING_ADDR, code, PTE_USER | PTE_R | PTE_X, 0);
+    pt.map(
+        SIGRETURN_ADDR,
+        signal::trampoline_page(),
+        PTE_USER | PTE_R | PTE_X,
+        0,
+    );
+
+    // a child made while the process is being killed would outlive it unnoticed
+    let exit_guard = EXIT_LOCK.lock();
+    let guard = RUN_LOCK.lock();
+    let slot = match alloc_slot(procs) {
+        Ok(slot) if !procs[leader].exiting => Ok(slot),
+        Ok((_, pid)) => {
+            pid::free(pid);
+            Err(EAGAIN)
+        }
+        Err(_) => Err(EAGAIN),
+    };
+    let (index, pid) = match slot {
+        Ok(slot) => slot,
+        Err(errno) => {
+            drop(guard);
+            drop(exit_guard);
+            drop(child);
+            return Err(errno);
+        }
+    };
+
+    // the slot is either zeroed or was moved out of by reap, so there's nothing to drop
+    let proc = &mut procs[index];
+    unsafe {
+        core::ptr::write(proc, child);
+    }
+    proc.pid = pid;
+    proc.tgid = pid;
+    proc.leader = index;
+    proc.sched.group = Some(index);
+    sched::enqueue(Task::Process(index));
+    Ok(pid)
+}
+
+/// Wait for thread `tid` of the process running on this hart to exit and reap it, returning
+/// its wait status. Sleeps until it has exited.
+pub fn join(tid: Pid) -> Result<u32, i64> {
+    let current = unsafe { cpu!().current_proc };
+    let procs = unsafe { PROCS.assume_init_ref() };
+    let leader = procs[current].leader;
+    let guard = EXIT_LOCK.lock();
+    // the leader is reaped along with the process
+    let thread = find(tid)
+        .filter(|&thread| procs[thread].leader == leader && thread != leader)
+        .ok_or(ESRCH)?;
+    if thread == current {
+        return Err(EDEADLK);
+    }
+    if matches!(procs[thread].state, ProcessState::Dead) {
+        let status = procs[thread].exit_status;
+        let process = reap(thread);
+        drop(guard);
+        // this thread keeps the process, and so the page table the stack is in, alive
+        drop(process);
+        return Ok(status);
+    }
+    THREAD_EXIT.wait_syscall(guard).map(|_| 0)
+}
+
+/// Switch to the address space of process `index` in PROCS and run it in user mode
+pub fn run(index: usize) -> ! {
+    unsafe {
+        cpu!().current_proc = index;
+        let proc = &mut PROCS.assume_init_mut()[index];
+        proc.state = ProcessState::Running;
+        csr_write_field!(satp, SATP_MODE, SATP_MODE_SV39);
+        csr_write_field!(satp, SATP_PPN, page_number!(virt_to_phys(proc.root as u64)));
+        core::arch::asm!("sfence.vma");
+        trap::return_to_user(proc.frame.assume_init_mut())
+    }
+}
+
 impl Drop for Process {
     fn drop(&mut self) {
+        let root = unsafe { &mut *self.root };
+        let leader = self.pid == self.tgid;
+        if !leader && self.stack_base != 0 {
+            root.unmap(self.stack_base, STACK_PAGES * PAGE_SIZE);
+        }
         // free stack
         for page in unsafe { self.stack.assume_init() } {
-            kfree(page)
+            if !page.is_null() {
+                kfree(page)
+            }
+        }
+        // the page table is shared by the threads, and the leader is reaped last
+        if leader {
+            root.free_user();
         }
-        unsafe { &mut *self.root }.free();
+        let files = self.files.lock().take();
+        files.close_all();
+        unsafe { self.frame.assume_init_mut() }.vector.free();
     }
 }
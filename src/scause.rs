@@ -0,0 +1,220 @@
+/// Decoding of the scause CSR
+///
+/// The cause of a trap is split into `Exception`s (synchronous) and `Interrupt`s
+/// (asynchronous), covering every code defined by the privileged spec, including those of
+/// extensions the kernel doesn't use. Codes the spec reserves for custom use decode to a
+/// `Custom`/`Platform` variant holding the code, while codes reserved for future standard use
+/// fail to decode.
+/// https://github.com/riscv/riscv-isa-manual/blob/main/src/supervisor.adoc (Table 22)
+use core::fmt;
+
+const INTERRUPT: u64 = 1 << 63;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trap {
+    Exception(Exception),
+    Interrupt(Interrupt),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    InstAddrMisaligned,     // 0
+    InstAccessFault,        // 1
+    InstIllegal,            // 2
+    Breakpoint,             // 3
+    LoadAddrMisaligned,     // 4
+    LoadAccessFault,        // 5
+    StoreAMOAddrMisaligned, // 6
+    StoreAMOAccessFault,    // 7
+    EnvCallFromUMode,       // 8
+    EnvCallFromSMode,       // 9
+    EnvCallFromVSMode,      // 10 (H extension)
+    EnvCallFromMMode,       // 11
+    InstPageFault,          // 12
+    LoadPageFault,          // 13
+    StoreAMOPageFault,      // 15
+    DoubleTrap,             // 16 (Ssdbltrp)
+    SoftwareCheck,          // 18 (Zicfiss/Zicfilp)
+    HardwareError,          // 19
+    InstGuestPageFault,     // 20 (H extension)
+    LoadGuestPageFault,     // 21 (H extension)
+    VirtualInst,            // 22 (H extension)
+    StoreAMOGuestPageFault, // 23 (H extension)
+    Custom(u64),            // 24-31 and 48-63, designated for custom use
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    SSoftware,       // 1
+    VSSoftware,      // 2 (H extension)
+    MSoftware,       // 3
+    STimer,          // 5
+    VSTimer,         // 6 (H extension)
+    MTimer,          // 7
+    SExternal,       // 9
+    VSExternal,      // 10 (H extension)
+    MExternal,       // 11
+    SGuestExternal,  // 12 (H extension)
+    CounterOverflow, // 13 (Sscofpmf)
+    Platform(u64),   // 16 and up, designated for platform or custom use
+}
+
+/// scause held a code that is reserved for future standard use
+#[derive(Clone, Copy, Debug)]
+pub struct UnknownCause {
+    pub scause: u64,
+}
+
+impl Trap {
+    pub fn decode(scause: u64) -> Result<Trap, UnknownCause> {
+        let code = scause & !INTERRUPT;
+        let unknown = Err(UnknownCause { scause });
+        if scause & INTERRUPT != 0 {
+            let interrupt = match code {
+                1 => Interrupt::SSoftware,
+                2 => Interrupt::VSSoftware,
+                3 => Interrupt::MSoftware,
+                5 => Interrupt::STimer,
+                6 => Interrupt::VSTimer,
+                7 => Interrupt::MTimer,
+                9 => Interrupt::SExternal,
+                10 => Interrupt::VSExternal,
+                11 => Interrupt::MExternal,
+                12 => Interrupt::SGuestExternal,
+                13 => Interrupt::CounterOverflow,
+                16.. => Interrupt::Platform(code),
+                _ => return unknown,
+            };
+            Ok(Trap::Interrupt(interrupt))
+        } else {
+            let exception = match code {
+                0 => Exception::InstAddrMisaligned,
+                1 => Exception::InstAccessFault,
+                2 => Exception::InstIllegal,
+                3 => Exception::Breakpoint,
+                4 => Exception::LoadAddrMisaligned,
+                5 => Exception::LoadAccessFault,
+                6 => Exception::StoreAMOAddrMisaligned,
+                7 => Exception::StoreAMOAccessFault,
+                8 => Exception::EnvCallFromUMode,
+                9 => Exception::EnvCallFromSMode,
+                10 => Exception::EnvCallFromVSMode,
+                11 => Exception::EnvCallFromMMode,
+                12 => Exception::InstPageFault,
+                13 => Exception::LoadPageFault,
+                15 => Exception::StoreAMOPageFault,
+                16 => Exception::DoubleTrap,
+                18 => Exception::SoftwareCheck,
+                19 => Exception::HardwareError,
+                20 => Exception::InstGuestPageFault,
+                21 => Exception::LoadGuestPageFault,
+                22 => Exception::VirtualInst,
+                23 => Exception::StoreAMOGuestPageFault,
+                24..=31 | 48..=63 => Exception::Custom(code),
+                _ => return unknown,
+            };
+            Ok(Trap::Exception(exception))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Trap::Exception(e) => e.name(),
+            Trap::Interrupt(i) => i.name(),
+        }
+    }
+}
+
+impl Exception {
+    /// Exceptions that the kernel cannot recover from when they happen in S-mode
+    pub fn should_panic(&self) -> bool {
+        match self {
+            Exception::Breakpoint | Exception::EnvCallFromUMode | Exception::EnvCallFromSMode => {
+                false
+            }
+            _ => true,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exception::InstAddrMisaligned => "instruction address misaligned",
+            Exception::InstAccessFault => "instruction access fault",
+            Exception::InstIllegal => "illegal instruction",
+            Exception::Breakpoint => "breakpoint",
+            Exception::LoadAddrMisaligned => "load address misaligned",
+            Exception::LoadAccessFault => "load access fault",
+            Exception::StoreAMOAddrMisaligned => "store/AMO address misaligned",
+            Exception::StoreAMOAccessFault => "store/AMO access fault",
+            Exception::EnvCallFromUMode => "environment call from U-mode",
+            Exception::EnvCallFromSMode => "environment call from S-mode",
+            Exception::EnvCallFromVSMode => "environment call from VS-mode",
+            Exception::EnvCallFromMMode => "environment call from M-mode",
+            Exception::InstPageFault => "instruction page fault",
+            Exception::LoadPageFault => "load page fault",
+            Exception::StoreAMOPageFault => "store/AMO page fault",
+            Exception::DoubleTrap => "double trap",
+            Exception::SoftwareCheck => "software check",
+            Exception::HardwareError => "hardware error",
+            Exception::InstGuestPageFault => "instruction guest-page fault",
+            Exception::LoadGuestPageFault => "load guest-page fault",
+            Exception::VirtualInst => "virtual instruction",
+            Exception::StoreAMOGuestPageFault => "store/AMO guest-page fault",
+            Exception::Custom(_) => "custom exception",
+        }
+    }
+}
+
+impl Interrupt {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interrupt::SSoftware => "supervisor software interrupt",
+            Interrupt::VSSoftware => "virtual supervisor software interrupt",
+            Interrupt::MSoftware => "machine software interrupt",
+            Interrupt::STimer => "supervisor timer interrupt",
+            Interrupt::VSTimer => "virtual supervisor timer interrupt",
+            Interrupt::MTimer => "machine timer interrupt",
+            Interrupt::SExternal => "supervisor external interrupt",
+            Interrupt::VSExternal => "virtual supervisor external interrupt",
+            Interrupt::MExternal => "machine external interrupt",
+            Interrupt::SGuestExternal => "supervisor guest external interrupt",
+            Interrupt::CounterOverflow => "local counter overflow interrupt",
+            Interrupt::Platform(_) => "platform interrupt",
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::Exception(Exception::Custom(code))
+            | Trap::Interrupt(Interrupt::Platform(code)) => {
+                write!(f, "{} {}", self.name(), code)
+            }
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+impl UnknownCause {
+    pub fn is_interrupt(&self) -> bool {
+        self.scause & INTERRUPT != 0
+    }
+}
+
+impl fmt::Display for UnknownCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.is_interrupt() {
+            "interrupt"
+        } else {
+            "exception"
+        };
+        write!(
+            f,
+            "reserved {} code {} (scause 0x{:016x})",
+            kind,
+            self.scause & !INTERRUPT,
+            self.scause
+        )
+    }
+}
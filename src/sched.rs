@@ -0,0 +1,696 @@
+/// Process scheduling
+///
+/// A hart that has nothing else to do ends up in `idle`, which runs the next runnable process or
+/// kernel thread if there is one and otherwise waits for an interrupt.
+///
+/// Each hart has a run queue of its own, and a task that becomes runnable is put on the least
+/// loaded hart its affinity mask allows, preferring the one it last ran on. A hart only picks
+/// from its own queue. When that is empty it steals a task from the busiest hart, and on
+/// scheduler ticks, at most BALANCE_HZ times a second, it pulls tasks over from the busiest
+/// hart if that has at least two more.
+/// The queues are still protected by RUN_LOCK along with the states of the tasks on them.
+///
+/// Tasks belong to one of two scheduling classes. Real-time tasks have a fixed priority from 1
+/// to MAX_RT_PRIORITY, and always run before fair tasks, highest priority first and in turns
+/// among equals. Fair tasks share what is left in proportion to the weight of their nice
+/// value: each one has a virtual runtime, which goes up by the time it runs scaled by its
+/// weight, and the one with the lowest goes next. A task waking up is given at least the
+/// lowest virtual runtime picked so far, so that sleeping doesn't bank time to hog the hart
+/// with later.
+///
+/// On every scheduler tick (see timer.rs) the running task is charged for the time it has run
+/// so far. A process is preempted on its way back to user mode if a task that should run
+/// before it is waiting on its hart, which makes fair tasks take turns a tick at a time. Kernel
+/// threads only stop running by themselves.
+///
+/// A task that stops running (a kernel thread yielding or going to sleep, or a process going to
+/// sleep in a syscall) takes RUN_LOCK and holds it until its hart is back in the idle loop, no
+/// longer using its stack or registers. Only then can another hart pick it up again, or can a
+/// wakeup make it runnable.
+use core::arch::asm;
+
+use crate::{
+    cpu,
+    cpu::{CPUS, MAX_HARTS},
+    cpustat::{self, Mode},
+    csr::SSTATUS_SIE,
+    csr_clear_bits, csr_set_bits, csr_write, ipi,
+    kthread::{self, ThreadState, MAX_THREADS},
+    lockdep,
+    proc::{self, ProcessState, MAX_PROCS, PROCS},
+    reg_read,
+    spinlock::Spinlock,
+};
+
+/// Protects the states of processes and kernel threads while harts pick what to run
+pub static RUN_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// Something for a hart to run: a process's index in PROCS, or a kernel thread's
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Task {
+    Process(usize),
+    Thread(usize),
+}
+
+/// Scheduling priority of a task. Higher priorities run first: 0 for fair tasks, and the
+/// real-time priority otherwise. `effective` is raised above `base` while the task holds a
+/// mutex that a higher priority task is waiting for (see sync.rs).
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Priority {
+    pub base: u8,
+    pub effective: u8,
+    boosts: [(usize, u8); MAX_BOOSTS], // address of a held lock and the priority waiting for it
+}
+
+/// Held locks a task can be boosted through at once. Past that, the weakest boost is dropped
+/// for a stronger one.
+const MAX_BOOSTS: usize = 8;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Policy {
+    #[default]
+    Fair,
+    RealTime,
+}
+
+pub const MAX_RT_PRIORITY: u8 = 99;
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+/// Weights of nice values from NICE_MIN to NICE_MAX, as in Linux. A task gets about 10% more
+/// CPU time than one with the next higher nice value.
+const NICE_WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+const NICE_0_WEIGHT: u64 = NICE_WEIGHTS[20];
+
+/// Scheduling state of a task. Times are in ticks of the time CSR.
+#[derive(Clone, Copy, Default)]
+pub struct Entity {
+    pub policy: Policy,
+    pub priority: Priority,
+    pub nice: i8,
+    affinity: u64, // bit n set if the task may run on hart n
+    hart: usize,   // hart whose queue the task is on, or that it last ran on
+    vruntime: u64,
+    since: u64, // when the task last started running, stopped running or woke up
+    runtime: u64,
+    wait_time: u64,           // runnable but not running
+    switches: u64,            // times the task stopped running
+    pub group: Option<usize>, // index in PROCS of the leader of a user thread's process
+    wait_queue: usize,        // address of the WaitQueue the task sleeps on, or 0
+}
+
+/// Tasks that can be on a run queue
+const MAX_TASKS: usize = MAX_PROCS + MAX_THREADS;
+
+/// How many times a second a hart looks for a busier hart to take tasks from
+const BALANCE_HZ: u64 = 100;
+
+/// Runnable tasks waiting for a hart, in the order they became runnable. Each hart has one in
+/// its CPU.
+pub struct RunQueue {
+    tasks: [Task; MAX_TASKS],
+    len: usize,
+    last_balance: u64, // time of the last periodic balancing
+    stats: SchedStats,
+}
+
+/// Scheduling statistics of a hart, as returned by the sched_stats syscall
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SchedStats {
+    pub runnable: u64, // tasks in the run queue now
+    pub picks: u64,    // tasks picked to run
+    pub steals: u64,   // tasks taken from another hart while idle
+    pub pulled: u64,   // tasks taken from another hart by periodic balancing
+}
+
+/// How long a task has run and waited, as returned by the proc_stats syscall
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct TaskStats {
+    pub runtime: u64,
+    pub wait_time: u64,
+    pub switches: u64,
+    pub timebase_hz: u64, // ticks per second
+}
+
+/// Value of `cpu!().current_proc` while a hart isn't running a process
+pub const NO_PROC: usize = usize::MAX;
+
+/// Lowest virtual runtime of a fair task when it was picked to run. Only ever goes up.
+static mut MIN_VRUNTIME: u64 = 0;
+
+impl Entity {
+    /// State of a new task. Must be called with RUN_LOCK held.
+    pub fn new() -> Self {
+        Self {
+            vruntime: unsafe { MIN_VRUNTIME },
+            since: cpustat::now(),
+            affinity: u64::MAX,
+            ..Default::default()
+        }
+    }
+
+    fn is_fair(&self) -> bool {
+        self.policy == Policy::Fair
+    }
+
+    /// Whether this task should run before `other`, given that `other` was seen first
+    fn runs_before(&self, other: &Entity) -> bool {
+        let (priority, other_priority) = (self.priority.effective, other.priority.effective);
+        if priority != other_priority {
+            return priority > other_priority;
+        }
+        priority == 0 && self.vruntime < other.vruntime
+    }
+
+    /// Don't let a task that wasn't runnable for a while catch up on the time it missed
+    fn place(&mut self) {
+        self.vruntime = self.vruntime.max(unsafe { MIN_VRUNTIME });
+    }
+
+    fn set_base_priority(&mut self, base: u8) {
+        self.priority.base = base;
+        self.priority.update();
+    }
+}
+
+impl Priority {
+    /// Raise the priority to at least `priority` until `lock` is released
+    fn boost(&mut self, lock: usize, priority: u8) {
+        let slot = self.boosts.iter().position(|&(held, _)| held == lock);
+        let slot = slot.or_else(|| self.boosts.iter().position(|&(held, _)| held == 0));
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                let weakest = self.boosts.iter().enumerate().min_by_key(|(_, b)| b.1);
+                let (slot, &(_, boost)) = weakest.unwrap();
+                // keep the boosts the task has if they are all at least as strong
+                if priority <= boost {
+                    return;
+                }
+                slot
+            }
+        };
+        let (held, boost) = self.boosts[slot];
+        let boost = if held == lock { boost } else { 0 };
+        self.boosts[slot] = (lock, boost.max(priority));
+        self.update();
+    }
+
+    /// Drop the boost that came with `lock`
+    fn unboost(&mut self, lock: usize) {
+        for boost in self.boosts.iter_mut().filter(|(held, _)| *held == lock) {
+            *boost = (0, 0);
+        }
+        self.update();
+    }
+
+    /// Set the effective priority from the base one and the boosts that are left
+    fn update(&mut self) {
+        let boosts = self.boosts.iter().map(|&(_, priority)| priority);
+        self.effective = boosts.fold(self.base, u8::max);
+    }
+}
+
+impl RunQueue {
+    fn queued(&self) -> &[Task] {
+        &self.tasks[..self.len]
+    }
+
+    fn push(&mut self, task: Task) {
+        self.tasks[self.len] = task;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, index: usize) -> Task {
+        let task = self.tasks[index];
+        self.tasks.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        task
+    }
+
+    /// Index of the task that should run next: the first of those that nothing runs before
+    fn best(&self) -> Option<usize> {
+        let mut best = None;
+        for (index, &task) in self.queued().iter().enumerate() {
+            let runs_before =
+                |best: usize| entity_mut(task).runs_before(entity_mut(self.tasks[best]));
+            if best.map_or(true, runs_before) {
+                best = Some(index);
+            }
+        }
+        best
+    }
+}
+
+/// Run queue of hart `hart`. Must be used with RUN_LOCK held.
+fn queue(hart: usize) -> &'static mut RunQueue {
+    unsafe { &mut CPUS.assume_init_mut()[hart].run_queue }
+}
+
+fn this_hart() -> usize {
+    unsafe { reg_read!(tp) as usize }
+}
+
+/// Online harts that `entity` may run on
+fn allowed_harts(entity: &Entity) -> impl Iterator<Item = usize> {
+    let allowed = entity.affinity & cpu::online();
+    (0..MAX_HARTS).filter(move |hart| allowed & 1 << hart != 0)
+}
+
+/// Tasks of process `group` that are queued or running on `hart`
+fn siblings(hart: usize, group: Option<usize>) -> usize {
+    let Some(group) = group else {
+        return 0;
+    };
+    let procs = unsafe { PROCS.assume_init_ref() };
+    let in_group = |index: usize| index != NO_PROC && procs[index].sched.group == Some(group);
+    let queued = queue(hart).queued().iter().filter(|task| match task {
+        Task::Process(index) => in_group(*index),
+        Task::Thread(_) => false,
+    });
+    let running = unsafe { CPUS.assume_init_ref()[hart].current_proc };
+    queued.count() + in_group(running) as usize
+}
+
+/// The least loaded hart that `entity` may run on, preferring harts where no other thread of
+/// its process is waiting or running, and then the one it ran on last
+fn select_hart(entity: &Entity) -> usize {
+    let load = |hart: usize| {
+        (
+            siblings(hart, entity.group),
+            queue(hart).len,
+            hart != entity.hart,
+        )
+    };
+    allowed_harts(entity)
+        .min_by_key(|&hart| load(hart))
+        .unwrap_or_else(this_hart) // harts aren't online yet while booting
+}
+
+/// Put `task`, which has just become runnable, on a run queue. Must be called with RUN_LOCK
+/// held.
+pub fn enqueue(task: Task) {
+    let entity = entity_mut(task);
+    entity.hart = select_hart(entity);
+    queue(entity.hart).push(task);
+    if entity.hart != this_hart() {
+        // it may be waiting for an interrupt in the idle loop
+        ipi::kick(entity.hart);
+    }
+}
+
+/// Take `task` off its run queue if it is on one. Must be called with RUN_LOCK held.
+pub fn dequeue(task: Task) -> bool {
+    let run_queue = queue(entity_mut(task).hart);
+    match run_queue.queued().iter().position(|&queued| queued == task) {
+        Some(index) => {
+            run_queue.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The other online hart with the most tasks waiting
+fn busiest(hart: usize) -> Option<usize> {
+    (0..MAX_HARTS)
+        .filter(|&other| other != hart && cpu::online() & 1 << other != 0)
+        .max_by_key(|&other| queue(other).len)
+}
+
+/// Move up to `count` tasks that may run on `to` from the back of the run queue of `from`,
+/// where they would have waited the longest. Returns how many were moved.
+fn pull(from: usize, to: usize, count: usize) -> usize {
+    let mut moved = 0;
+    let mut index = queue(from).len;
+    while index > 0 && moved < count {
+        index -= 1;
+        let task = queue(from).tasks[index];
+        let entity = entity_mut(task);
+        if entity.affinity & 1 << to != 0 {
+            queue(from).remove(index);
+            queue(to).push(task);
+            entity.hart = to;
+            moved += 1;
+        }
+    }
+    moved
+}
+
+/// Even out the run queues of `hart` and the busiest other hart, if it's time to
+fn balance(hart: usize, now: u64) {
+    let run_queue = queue(hart);
+    if now.wrapping_sub(run_queue.last_balance) < cpustat::timebase_hz() / BALANCE_HZ {
+        return;
+    }
+    run_queue.last_balance = now;
+    let Some(busiest) = busiest(hart) else {
+        return;
+    };
+    let (len, busiest_len) = (run_queue.len, queue(busiest).len);
+    if busiest_len > len + 1 {
+        run_queue.stats.pulled += pull(busiest, hart, (busiest_len - len) / 2) as u64;
+    }
+}
+
+/// Restrict `task` to the harts in `mask`, moving it to another run queue if it has to.
+/// Returns false if none of them are online.
+pub fn set_affinity(task: Task, mask: u64) -> bool {
+    let _guard = RUN_LOCK.lock();
+    if mask & cpu::online() == 0 {
+        return false;
+    }
+    let entity = entity_mut(task);
+    entity.affinity = mask;
+    if mask & 1 << entity.hart == 0 && dequeue(task) {
+        enqueue(task);
+    }
+    true
+}
+
+/// Scheduling statistics of hart `hart`
+pub fn hart_stats(hart: usize) -> SchedStats {
+    let _guard = RUN_LOCK.lock();
+    let run_queue = queue(hart);
+    SchedStats {
+        runnable: run_queue.len as u64,
+        ..run_queue.stats
+    }
+}
+
+/// The task running on this hart: a kernel thread, or the process whose trap is being handled.
+/// None in the idle loop.
+pub fn current() -> Option<Task> {
+    if let Some(thread) = kthread::current() {
+        return Some(Task::Thread(thread.id));
+    }
+    match unsafe { cpu!().current_proc } {
+        NO_PROC => None,
+        index => Some(Task::Process(index)),
+    }
+}
+
+/// Must be called with RUN_LOCK held
+fn entity_mut(task: Task) -> &'static mut Entity {
+    match task {
+        Task::Process(index) => unsafe { &mut PROCS.assume_init_mut()[index].sched },
+        Task::Thread(index) => &mut kthread::get(index).sched,
+    }
+}
+
+/// The effective priority of `task`
+pub fn priority(task: Task) -> u8 {
+    let _guard = RUN_LOCK.lock();
+    entity_mut(task).priority.effective
+}
+
+/// Raise the effective priority of `task`, which holds the lock at address `lock`, to at
+/// least `priority` until it releases it
+pub fn boost(task: Task, lock: usize, priority: u8) {
+    let _guard = RUN_LOCK.lock();
+    entity_mut(task).priority.boost(lock, priority);
+}
+
+/// Drop the boost `task` got through the lock at address `lock`, keeping any it got through
+/// other locks it still holds
+pub fn unboost(task: Task, lock: usize) {
+    let _guard = RUN_LOCK.lock();
+    entity_mut(task).priority.unboost(lock);
+}
+
+/// Move `task` to the fair class, or to the real-time class with priority `rt_priority`,
+/// keeping any boost above it
+pub fn set_scheduler(task: Task, policy: Policy, rt_priority: u8) {
+    let _guard = RUN_LOCK.lock();
+    let entity = entity_mut(task);
+    if policy == Policy::Fair && !entity.is_fair() {
+        entity.place();
+    }
+    entity.policy = policy;
+    entity.set_base_priority(match policy {
+        Policy::Fair => 0,
+        Policy::RealTime => rt_priority.clamp(1, MAX_RT_PRIORITY),
+    });
+}
+
+/// Set the nice value of `task`, which only matters while it is in the fair class
+pub fn set_nice(task: Task, nice: i8) {
+    let _guard = RUN_LOCK.lock();
+    entity_mut(task).nice = nice.clamp(NICE_MIN, NICE_MAX);
+}
+
+/// Time spent by `task` running and waiting to run so far
+pub fn stats(task: Task) -> TaskStats {
+    let _guard = RUN_LOCK.lock();
+    let entity = entity_mut(task);
+    TaskStats {
+        runtime: entity.runtime,
+        wait_time: entity.wait_time,
+        switches: entity.switches,
+        timebase_hz: cpustat::timebase_hz(),
+    }
+}
+
+/// Charge `entity` for the time it has run since it started running or was last charged
+fn charge(entity: &mut Entity, now: u64) {
+    let ran = now.wrapping_sub(entity.since);
+    entity.runtime += ran;
+    entity.since = now;
+    if entity.is_fair() {
+        let weight = NICE_WEIGHTS[(entity.nice - NICE_MIN) as usize];
+        entity.vruntime += ran * NICE_0_WEIGHT / weight;
+    }
+}
+
+/// Charge `task` for the time it has just spent running, now that its hart is done with it.
+/// Must be called with RUN_LOCK held.
+pub fn switched_out(task: Task) {
+    let entity = entity_mut(task);
+    charge(entity, cpustat::now());
+    entity.switches += 1;
+}
+
+/// Called on every scheduler tick. Balances the run queues, charges the task running on this
+/// hart, and has the process running on it preempted if something that should run before it is
+/// waiting.
+pub fn tick() {
+    let _guard = RUN_LOCK.lock();
+    balance(this_hart(), cpustat::now());
+    let Some(task) = current() else {
+        return;
+    };
+    let running = match task {
+        Task::Thread(index) => matches!(kthread::get(index).state, ThreadState::Running),
+        Task::Process(index) => unsafe {
+            matches!(PROCS.assume_init_ref()[index].state, ProcessState::Running)
+        },
+    };
+    // otherwise it has stopped running and was charged already
+    if !running {
+        return;
+    }
+    let entity = entity_mut(task);
+    charge(entity, cpustat::now());
+    let run_queue = queue(this_hart());
+    let waiting = run_queue
+        .best()
+        .map(|best| entity_mut(run_queue.tasks[best]));
+    if matches!(task, Task::Process(_)) && waiting.is_some_and(|next| next.runs_before(entity)) {
+        unsafe {
+            cpu!().need_resched = true;
+        }
+    }
+}
+
+/// Have the process running on this hart give up its hart on the way back to user mode. It
+/// stays runnable, but whatever else is waiting that should run first goes before it.
+pub fn yield_process() {
+    unsafe {
+        cpu!().need_resched = true;
+    }
+}
+
+/// Whether the process running on this hart should let another task run before returning to
+/// user mode
+pub fn should_preempt() -> bool {
+    unsafe { cpu!().need_resched }
+}
+
+/// Put the process running on this hart back on a run queue and go back to the idle loop, which
+/// picks what runs next
+pub fn preempt() -> ! {
+    let guard = RUN_LOCK.lock();
+    let index = unsafe { cpu!().current_proc };
+    unsafe {
+        PROCS.assume_init_mut()[index].state = ProcessState::Waiting;
+    }
+    let task = Task::Process(index);
+    switched_out(task);
+    enqueue(task);
+    // it only uses its TrapFrame from here on, so another hart may pick it up now
+    drop(guard);
+    idle()
+}
+
+/// Claim the task that should run next on this hart, taking one from another hart if there is
+/// nothing to run here
+fn pick_next() -> Option<Task> {
+    let _guard = RUN_LOCK.lock();
+    let hart = this_hart();
+    let now = cpustat::now();
+    let run_queue = queue(hart);
+    if run_queue.len == 0 {
+        let busiest = busiest(hart)?;
+        run_queue.stats.steals += pull(busiest, hart, 1) as u64;
+    }
+
+    let task = run_queue.remove(run_queue.best()?);
+    run_queue.stats.picks += 1;
+    let entity = entity_mut(task);
+    entity.wait_time += now.wrapping_sub(entity.since);
+    entity.since = now;
+    if entity.is_fair() {
+        unsafe {
+            MIN_VRUNTIME = MIN_VRUNTIME.max(entity.vruntime);
+        }
+    }
+    match task {
+        Task::Process(index) => unsafe {
+            PROCS.assume_init_mut()[index].state = ProcessState::Running
+        },
+        Task::Thread(index) => kthread::get(index).state = ThreadState::Running,
+    }
+    Some(task)
+}
+
+/// The idle loop of a hart
+pub fn idle() -> ! {
+    loop {
+        // With interrupts off, an interrupt arriving after the check below still wakes up
+        // wfi, and is taken once they are turned back on.
+        unsafe {
+            csr_clear_bits!(sstatus, SSTATUS_SIE);
+            cpu!().current_proc = NO_PROC;
+            cpu!().need_resched = false;
+        }
+        match pick_next() {
+            Some(Task::Process(index)) => proc::run(index),
+            Some(Task::Thread(index)) => {
+                kthread::run(index);
+                continue;
+            }
+            None => {}
+        }
+        let prev = cpustat::switch(Mode::Idle);
+        unsafe {
+            asm!("wfi");
+        }
+        cpustat::switch(prev);
+        unsafe {
+            csr_set_bits!(sstatus, SSTATUS_SIE);
+        }
+    }
+}
+
+/// Make `task` runnable if it is asleep, returning whether it was
+pub fn wake(task: Task) -> bool {
+    let _guard = RUN_LOCK.lock();
+    wake_locked(task)
+}
+
+/// Make `task` runnable if it is asleep on the wait queue at address `queue`, returning whether
+/// it was. A task woken by something else, such as a signal, is left on the queues it slept on
+/// before, and may be asleep on another one by now.
+pub fn wake_from(task: Task, queue: usize) -> bool {
+    let _guard = RUN_LOCK.lock();
+    entity_mut(task).wait_queue == queue && wake_locked(task)
+}
+
+/// Record that `task`, which is going to sleep, waits on the wait queue at address `queue`.
+/// Must be called with RUN_LOCK held.
+pub fn set_wait_queue(task: Task, queue: usize) {
+    entity_mut(task).wait_queue = queue;
+}
+
+/// Must be called with RUN_LOCK held
+fn wake_locked(task: Task) -> bool {
+    let woken = match task {
+        Task::Process(index) => {
+            let proc = unsafe { &mut PROCS.assume_init_mut()[index] };
+            let sleeping = matches!(proc.state, ProcessState::Sleeping);
+            if sleeping {
+                proc.state = ProcessState::Waiting;
+            }
+            sleeping
+        }
+        Task::Thread(index) => kthread::wake(index),
+    };
+    if woken {
+        let entity = entity_mut(task);
+        entity.wait_queue = 0;
+        entity.since = cpustat::now();
+        entity.place();
+        enqueue(task);
+    }
+    woken
+}
+
+/// Interrupt `task` if it is a process running on another hart, so that it goes through the
+/// trap path, and back to user mode through `trap::return_to_user`, soon
+pub fn kick(task: Task) {
+    let _guard = RUN_LOCK.lock();
+    let Task::Process(index) = task else {
+        return;
+    };
+    let proc = unsafe { &PROCS.assume_init_ref()[index] };
+    let hart = proc.sched.hart;
+    if matches!(proc.state, ProcessState::Running) && hart != this_hart() {
+        ipi::kick(hart);
+    }
+}
+
+/// Put the process running on this hart to sleep, calling `release` once nothing can wake it
+/// before its hart is done with it. `release` must leave interrupts off. The trap path then
+/// goes back to the idle loop instead of returning to user mode (see `idle_after_sleep`).
+pub fn sleep_process(release: impl FnOnce() -> bool) {
+    core::mem::forget(RUN_LOCK.lock());
+    unsafe {
+        crate::proc!().state = ProcessState::Sleeping;
+    }
+    release();
+    lockdep::process_sleep();
+}
+
+/// Whether the process running on this hart went to sleep while handling its trap
+pub fn process_sleeping() -> bool {
+    unsafe { matches!(crate::proc!().state, ProcessState::Sleeping) }
+}
+
+/// Have `func(arg)` called once the process that went to sleep on this hart is switched out,
+/// for cleanup that takes RUN_LOCK, which `sleep_process` holds until then
+pub fn after_sleep(func: fn(usize), arg: usize) {
+    unsafe {
+        assert!(cpu!().after_sleep.is_none(), "after_sleep called twice");
+        cpu!().after_sleep = Some((func, arg));
+    }
+}
+
+/// Go back to the idle loop after `sleep_process`
+pub fn idle_after_sleep() -> ! {
+    unsafe {
+        switched_out(Task::Process(cpu!().current_proc));
+        RUN_LOCK.force_unlock();
+        if let Some((func, arg)) = cpu!().after_sleep.take() {
+            func(arg);
+        }
+    }
+    idle()
+}
@@ -0,0 +1,358 @@
+/// POSIX-style signals
+///
+/// A signal sent to a process is marked pending, and the process is woken if it is asleep in a
+/// syscall. Pending signals that aren't blocked are delivered on the way back to user mode
+/// (see `trap::return_to_user`), one at a time. Syscalls that were sleeping start over once the
+/// handler returns, as if every handler had SA_RESTART.
+///
+/// To run a handler, the registers of the process are saved in a SignalFrame pushed onto its
+/// user stack, and it resumes at the handler with the signal number in a0 and the frame in a2.
+/// The handler returns to a trampoline that every process has mapped at SIGRETURN_ADDR, which
+/// makes the rt_sigreturn syscall to restore the registers from the frame. There's no siginfo,
+/// so a1 is 0.
+///
+/// Each thread has its own pending signals and mask, and signals are sent to a thread. What a
+/// signal does is up to its process, so the actions are kept by its leader (see proc.rs), and
+/// a signal that terminates one thread terminates all of them.
+///
+/// Signals whose default action would stop or continue a process are ignored, since there's no
+/// job control, and core dumps are plain terminations.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    fp::FpState,
+    kmem::{virt_to_phys, PAGE_SIZE},
+    pid::Pid,
+    proc::{self, ProcessState, TrapFrame, PROCS},
+    sched::{self, Task, RUN_LOCK},
+    syscall::{self, EFAULT},
+    uaccess::{copy_from_user, copy_to_user},
+};
+
+extern "C" {
+    fn sigreturn_trampoline(); // defined in signal.s
+}
+
+/// Signals are numbered from 1 to NSIG
+pub const NSIG: usize = 64;
+
+// Linux signal numbers
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+// sigaction flags
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// Where the trampoline is mapped in every process, just below its code
+pub const SIGRETURN_ADDR: u64 = proc::PROC_STARTING_ADDR - PAGE_SIZE;
+
+/// Signals that can't be caught, blocked or ignored
+const UNBLOCKABLE: u64 = bit(SIGKILL) | bit(SIGSTOP);
+
+/// struct sigaction as the Linux syscalls take it on RISC-V
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SigAction {
+    pub handler: u64, // SIG_DFL, SIG_IGN or the address of a function
+    pub flags: u64,
+    pub mask: u64, // signals blocked while the handler runs
+}
+
+/// Signal state of a thread
+pub struct Signals {
+    pending: AtomicU64, // set by whoever sends a signal, see `bit`
+    blocked: u64,
+}
+
+/// What each signal does in a process, shared by its threads
+#[derive(Clone)]
+pub struct Actions {
+    actions: [SigAction; NSIG + 1], // indexed by signal number
+}
+
+/// Registers of a process saved while it runs a signal handler
+#[repr(C)]
+struct SignalFrame {
+    regs: [u64; 32],
+    pc: u64,
+    blocked: u64,
+    fp_saved: u64, // whether fp_regs and fcsr are valid
+    fp_regs: [u64; 32],
+    fcsr: u64,
+}
+
+enum DefaultAction {
+    Terminate,
+    Ignore,
+}
+
+/// The bit for `signal` in a set of signals, which is laid out like a Linux sigset_t
+const fn bit(signal: usize) -> u64 {
+    1 << (signal - 1)
+}
+
+fn default_action(signal: usize) -> DefaultAction {
+    match signal {
+        SIGCHLD | SIGCONT | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        // no job control
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Ignore,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+impl SigAction {
+    const DEFAULT: SigAction = SigAction {
+        handler: SIG_DFL,
+        flags: 0,
+        mask: 0,
+    };
+}
+
+impl Signals {
+    /// No signals pending, and those in `blocked` blocked
+    pub const fn new(blocked: u64) -> Self {
+        Self {
+            pending: AtomicU64::new(0),
+            blocked,
+        }
+    }
+
+    /// The lowest pending signal that isn't blocked, which is no longer pending
+    fn take(&self) -> Option<usize> {
+        let deliverable = self.pending.load(Ordering::Acquire) & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+        let signal = deliverable.trailing_zeros() as usize + 1;
+        self.pending.fetch_and(!bit(signal), Ordering::AcqRel);
+        Some(signal)
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    pub fn set_blocked(&mut self, blocked: u64) {
+        self.blocked = blocked & !UNBLOCKABLE;
+    }
+}
+
+impl Actions {
+    pub const fn new() -> Self {
+        Self {
+            actions: [SigAction::DEFAULT; NSIG + 1],
+        }
+    }
+
+    pub fn get(&self, signal: usize) -> SigAction {
+        self.actions[signal]
+    }
+
+    /// Whether `signal` would be thrown away if delivered now
+    fn ignores(&self, signal: usize) -> bool {
+        match self.actions[signal].handler {
+            SIG_IGN => true,
+            SIG_DFL => matches!(default_action(signal), DefaultAction::Ignore),
+            _ => false,
+        }
+    }
+}
+
+pub fn is_valid(signal: usize) -> bool {
+    (1..=NSIG).contains(&signal)
+}
+
+/// Change what `signal` does in the process that thread `index` belongs to. Returns false for
+/// signals that can't be caught or ignored.
+pub fn set_action(index: usize, signal: usize, action: SigAction) -> bool {
+    if UNBLOCKABLE & bit(signal) != 0 {
+        return false;
+    }
+    let mut actions = proc::leader(index).actions.lock();
+    actions.actions[signal] = action;
+    // POSIX: setting an ignored signal to be ignored throws away any pending one
+    if actions.ignores(signal) {
+        let group = proc::leader(index).pid;
+        for thread in proc::threads(group) {
+            let pending = unsafe { &PROCS.assume_init_ref()[thread].signals.pending };
+            pending.fetch_and(!bit(signal), Ordering::AcqRel);
+        }
+    }
+    true
+}
+
+/// Send `signal` to the thread at `index` in PROCS
+pub fn send(index: usize, signal: usize) {
+    let proc = unsafe { &mut PROCS.assume_init_mut()[index] };
+    let signals = &proc.signals;
+    if signal != SIGKILL && proc::leader(index).actions.lock().ignores(signal) {
+        return;
+    }
+    signals.pending.fetch_or(bit(signal), Ordering::AcqRel);
+    if signals.blocked & bit(signal) == 0 {
+        // get it back to the return path, where the signal is delivered
+        let task = Task::Process(index);
+        sched::wake(task);
+        sched::kick(task);
+    }
+}
+
+/// Send `signal` to the thread running on this hart for a fault it caused. The fault would
+/// happen again if the signal were blocked or ignored, so in that case it is unblocked and
+/// its default action restored.
+pub fn force(signal: usize) {
+    let index = unsafe { crate::cpu!().current_proc };
+    let signals = unsafe { &mut crate::proc!().signals };
+    let mut actions = proc::leader(index).actions.lock();
+    if signals.blocked & bit(signal) != 0 || actions.ignores(signal) {
+        signals.blocked &= !bit(signal);
+        actions.actions[signal] = SigAction::DEFAULT;
+    }
+    drop(actions);
+    send(index, signal);
+}
+
+/// Deliver a pending signal to the thread about to return to user mode with `frame`, if
+/// there is one. Doesn't return if the signal terminates the process.
+pub fn deliver(frame: &mut TrapFrame) {
+    let index = unsafe { crate::cpu!().current_proc };
+    let proc = unsafe { &mut crate::proc!() };
+    let Some(signal) = proc.signals.take() else {
+        return;
+    };
+    let action = {
+        let mut actions = proc::leader(index).actions.lock();
+        let action = actions.actions[signal];
+        if action.flags & SA_RESETHAND != 0 && !matches!(action.handler, SIG_DFL | SIG_IGN) {
+            actions.actions[signal] = SigAction::DEFAULT;
+        }
+        action
+    };
+    match action.handler {
+        SIG_IGN => return,
+        SIG_DFL => match default_action(signal) {
+            DefaultAction::Ignore => return,
+            DefaultAction::Terminate => proc::exit_group(proc::killed_status(signal)),
+        },
+        _ => {}
+    }
+
+    let mut saved = SignalFrame {
+        regs: frame.regs,
+        pc: frame.epc,
+        blocked: proc.signals.blocked,
+        fp_saved: frame.fp.enabled() as u64,
+        fp_regs: frame.fp.regs,
+        fcsr: frame.fp.fcsr,
+    };
+    saved.regs[0] = 0;
+    let size = core::mem::size_of::<SignalFrame>() as u64;
+    let sp = (frame.regs[2].wrapping_sub(size)) & !0xf;
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&saved as *const SignalFrame as *const u8, size as usize)
+    };
+    let pt = unsafe { &*proc.root };
+    if copy_to_user(pt, sp, bytes).is_err() {
+        // the stack is unusable, so the handler couldn't run either
+        proc::exit_group(proc::killed_status(SIGSEGV));
+    }
+
+    frame.regs[1] = SIGRETURN_ADDR; // ra
+    frame.regs[2] = sp;
+    frame.regs[10] = signal as u64;
+    frame.regs[11] = 0;
+    frame.regs[12] = sp;
+    frame.epc = action.handler;
+    if action.flags & SA_NODEFER == 0 {
+        proc.signals.blocked |= bit(signal);
+    }
+    proc.signals.set_blocked(proc.signals.blocked | action.mask);
+}
+
+/// rt_sigreturn(): restore the registers saved by `deliver` from the frame at sp. Returns the
+/// restored a0, which the syscall return path puts back. A bad frame is a SIGSEGV.
+pub fn sigreturn(frame: &mut TrapFrame) -> syscall::Result {
+    let proc = unsafe { &mut crate::proc!() };
+    let mut saved = core::mem::MaybeUninit::<SignalFrame>::uninit();
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(
+            saved.as_mut_ptr() as *mut u8,
+            core::mem::size_of::<SignalFrame>(),
+        )
+    };
+    let pt = unsafe { &*proc.root };
+    if copy_from_user(pt, bytes, frame.regs[2]).is_err() {
+        // there's nowhere sensible to return to
+        force(SIGSEGV);
+        return Err(EFAULT);
+    }
+    let saved = unsafe { saved.assume_init() };
+
+    frame.regs[1..].copy_from_slice(&saved.regs[1..]);
+    frame.epc = saved.pc;
+    proc.signals.set_blocked(saved.blocked);
+    if saved.fp_saved != 0 && frame.fp.enabled() {
+        restore_fp(&mut frame.fp, &saved);
+    }
+    Ok(frame.regs[10])
+}
+
+fn restore_fp(fp: &mut FpState, saved: &SignalFrame) {
+    for (i, &value) in saved.fp_regs.iter().enumerate() {
+        fp.set_reg(i, value);
+    }
+    fp.fcsr = saved.fcsr;
+}
+
+/// Physical address of the page holding the sigreturn trampoline
+pub fn trampoline_page() -> u64 {
+    virt_to_phys(sigreturn_trampoline as u64)
+}
+
+/// Send `signal` to the thread with tid `pid`, or just check that it is alive for signal 0.
+/// A process whose first thread has exited gets it in another of its threads. Returns false
+/// if there's no such thread.
+pub fn kill(pid: Pid, signal: usize) -> bool {
+    let index = {
+        let _guard = RUN_LOCK.lock();
+        let procs = unsafe { PROCS.assume_init_ref() };
+        let alive = |&index: &usize| !matches!(procs[index].state, ProcessState::Dead);
+        let Some(index) = proc::find(pid) else {
+            return false;
+        };
+        match proc::threads(pid).find(alive) {
+            _ if alive(&index) => index,
+            Some(thread) => thread,
+            None => return false,
+        }
+    };
+    if signal != 0 {
+        send(index, signal);
+    }
+    true
+}
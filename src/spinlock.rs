@@ -0,0 +1,116 @@
+/// Spinlock for data shared between harts
+///
+/// Interrupts are disabled on the current hart while a lock is held, so an interrupt handler
+/// never spins on a lock that the code it interrupted is holding.
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    csr::SSTATUS_SIE,
+    csr_clear_bits, csr_read, csr_set_bits, csr_write,
+    lockdep::{self, Kind, Site},
+};
+
+pub struct Spinlock<T> {
+    locked: AtomicBool,
+    class: Site, // where the lock was created, see lockdep.rs
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Spinlock<T> {}
+
+pub struct SpinlockGuard<'a, T> {
+    lock: &'a Spinlock<T>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> Spinlock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            class: Location::caller(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        let interrupts_were_enabled = interrupts_off();
+        lockdep::acquire(self.addr(), self.class, Kind::Spin, Location::caller());
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        SpinlockGuard {
+            lock: self,
+            interrupts_were_enabled,
+        }
+    }
+
+    /// Release a lock whose guard was forgotten, such as RUN_LOCK when it is handed over from
+    /// a task going to sleep to the idle loop (see sched.rs). Interrupts are left as they are.
+    pub unsafe fn force_unlock(&self) {
+        lockdep::release(self.addr());
+        self.locked.store(false, Ordering::Release);
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+impl<T> SpinlockGuard<'_, T> {
+    /// Release the lock but leave interrupts off, returning whether they were enabled when it
+    /// was taken
+    pub fn unlock_interrupts_off(self) -> bool {
+        let interrupts_were_enabled = self.interrupts_were_enabled;
+        lockdep::release(self.lock.addr());
+        self.lock.locked.store(false, Ordering::Release);
+        core::mem::forget(self);
+        interrupts_were_enabled
+    }
+}
+
+impl<T> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.addr());
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_were_enabled {
+            unsafe {
+                csr_set_bits!(sstatus, SSTATUS_SIE);
+            }
+        }
+    }
+}
+
+/// Disable interrupts on the current hart, returning whether they were enabled
+pub fn interrupts_off() -> bool {
+    unsafe {
+        let enabled = csr_read!(sstatus) & SSTATUS_SIE != 0;
+        csr_clear_bits!(sstatus, SSTATUS_SIE);
+        enabled
+    }
+}
@@ -0,0 +1,410 @@
+/// Sleeping locks
+///
+/// Unlike a Spinlock, these put a task that can't take them to sleep on a WaitQueue until they
+/// are released, so they can be held for a long time and across sleeps. They can only be used
+/// by tasks: kernel threads, or a process in a syscall with the `_syscall` variants, which
+/// return ERESTART to make the syscall wait (see wait.rs). A process can't sleep while holding
+/// one, since a guard doesn't outlive the syscall that took it.
+///
+/// A Mutex, and a RwLock taken for writing, remember the task holding them. A task that has to
+/// wait for one raises the priority of its holder to its own, so that a task of middling
+/// priority can't keep the holder, and through it the waiter, from running. The holder loses
+/// that boost when it releases the lock, keeping those it got through other locks it still
+/// holds. Only a task that is about to sleep boosts anyone. This only goes one level deep: the
+/// holder of a lock that the boosted task is itself waiting for isn't boosted as well.
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    panic::Location,
+};
+
+use crate::{
+    lockdep::{self, Kind, Site},
+    sched::{self, Task},
+    spinlock::{Spinlock, SpinlockGuard},
+    wait::WaitQueue,
+};
+
+fn current_task() -> Task {
+    sched::current().expect("sleeping lock used outside of a task")
+}
+
+/// Take the lock at address `lock` with `acquire`, telling lockdep about it
+#[track_caller]
+fn tracked<G>(
+    lock: usize,
+    class: Site,
+    acquire: impl FnOnce() -> Result<G, i64>,
+) -> Result<G, i64> {
+    lockdep::acquire(lock, class, Kind::Sleeping, Location::caller());
+    let result = acquire();
+    if result.is_err() {
+        lockdep::release(lock);
+    }
+    result
+}
+
+/// Have the current task wait for the holder of the lock at address `lock`, if there is one,
+/// which inherits its priority
+fn boost_holder(holder: Option<Task>, lock: usize) {
+    if let Some(holder) = holder {
+        sched::boost(holder, lock, sched::priority(current_task()));
+    }
+}
+
+pub struct Mutex<T> {
+    class: Site, // where the mutex was created, see lockdep.rs
+    owner: Spinlock<Option<Task>>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            class: Location::caller(),
+            owner: Spinlock::new(None),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Take the lock, calling `wait` with the owner's spinlock held whenever it has to wait
+    fn acquire(
+        &self,
+        mut wait: impl FnMut(SpinlockGuard<'_, Option<Task>>) -> Result<(), i64>,
+    ) -> Result<MutexGuard<'_, T>, i64> {
+        let task = current_task();
+        loop {
+            let mut owner = self.owner.lock();
+            match *owner {
+                None => {
+                    *owner = Some(task);
+                    return Ok(MutexGuard { mutex: self });
+                }
+                Some(holder) if holder == task => panic!("{:?} locked a mutex twice", task),
+                Some(_) => wait(owner)?,
+            }
+        }
+    }
+
+    /// Take the lock, sleeping until it is free. Panics outside of a kernel thread.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let guard = tracked(self.addr(), self.class, || {
+            self.acquire(|owner| {
+                boost_holder(*owner, self.addr());
+                self.waiters.wait(owner);
+                Ok(())
+            })
+        });
+        guard.unwrap()
+    }
+
+    /// Take the lock in a syscall, or return ERESTART once the process is asleep waiting for it
+    #[track_caller]
+    pub fn lock_syscall(&self) -> Result<MutexGuard<'_, T>, i64> {
+        tracked(self.addr(), self.class, || {
+            self.acquire(|owner| {
+                boost_holder(*owner, self.addr());
+                self.waiters.wait_syscall(owner).map(|_| ())
+            })
+        })
+    }
+
+    /// Take the lock if it is free
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        // the error only stops acquire instead of waiting
+        let guard = self.acquire(|_| Err(0)).ok()?;
+        lockdep::acquired(self.addr(), self.class, Kind::Sleeping, Location::caller());
+        Some(guard)
+    }
+
+    /// The task holding the lock, if any
+    pub fn owner(&self) -> Option<Task> {
+        *self.owner.lock()
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.mutex.addr());
+        let holder = self.mutex.owner.lock().take();
+        if let Some(holder) = holder {
+            sched::unboost(holder, self.mutex.addr());
+        }
+        self.mutex.waiters.wake_one();
+    }
+}
+
+/// Counting semaphore. It has no owner, so waiting for it boosts nobody.
+pub struct Semaphore {
+    count: Spinlock<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: Spinlock::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    fn acquire(
+        &self,
+        mut wait: impl FnMut(SpinlockGuard<'_, usize>) -> Result<(), i64>,
+    ) -> Result<(), i64> {
+        loop {
+            let mut count = self.count.lock();
+            if *count > 0 {
+                *count -= 1;
+                return Ok(());
+            }
+            wait(count)?;
+        }
+    }
+
+    /// Decrement the count, sleeping until it is above zero. Panics outside of a kernel thread.
+    pub fn down(&self) {
+        let result = self.acquire(|count| {
+            self.waiters.wait(count);
+            Ok(())
+        });
+        result.unwrap()
+    }
+
+    /// Decrement the count in a syscall, or return ERESTART once the process is asleep waiting
+    /// for it
+    pub fn down_syscall(&self) -> Result<(), i64> {
+        self.acquire(|count| self.waiters.wait_syscall(count).map(|_| ()))
+    }
+
+    /// Decrement the count if it is above zero
+    pub fn try_down(&self) -> bool {
+        self.acquire(|_| Err(0)).is_ok()
+    }
+
+    pub fn up(&self) {
+        *self.count.lock() += 1;
+        self.waiters.wake_one();
+    }
+}
+
+struct RwState {
+    readers: usize,
+    writer: Option<Task>,
+}
+
+/// Reader-writer lock. Writers are preferred: no new reader gets in while a writer is waiting.
+/// Only the writer is tracked, so only waiting for a writer boosts anyone.
+pub struct RwLock<T> {
+    class: Site,
+    state: Spinlock<RwState>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            class: Location::caller(),
+            state: Spinlock::new(RwState {
+                readers: 0,
+                writer: None,
+            }),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn acquire_read(
+        &self,
+        mut wait: impl FnMut(SpinlockGuard<'_, RwState>) -> Result<(), i64>,
+    ) -> Result<RwLockReadGuard<'_, T>, i64> {
+        loop {
+            let mut state = self.state.lock();
+            match state.writer {
+                Some(holder) if holder == current_task() => {
+                    panic!("{:?} read a lock it is writing", holder)
+                }
+                Some(_) => {}
+                None if self.writers.is_empty() => {
+                    state.readers += 1;
+                    return Ok(RwLockReadGuard { lock: self });
+                }
+                None => {}
+            }
+            wait(state)?;
+        }
+    }
+
+    fn acquire_write(
+        &self,
+        mut wait: impl FnMut(SpinlockGuard<'_, RwState>) -> Result<(), i64>,
+    ) -> Result<RwLockWriteGuard<'_, T>, i64> {
+        let task = current_task();
+        loop {
+            let mut state = self.state.lock();
+            match state.writer {
+                Some(holder) if holder == task => panic!("{:?} write locked twice", task),
+                Some(_) => {}
+                None if state.readers == 0 => {
+                    state.writer = Some(task);
+                    return Ok(RwLockWriteGuard { lock: self });
+                }
+                None => {}
+            }
+            wait(state)?;
+        }
+    }
+
+    /// Take the lock for reading, sleeping while it is written or a writer is waiting. Panics
+    /// outside of a kernel thread.
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let guard = tracked(self.addr(), self.class, || {
+            self.acquire_read(|state| {
+                boost_holder(state.writer, self.addr());
+                self.readers.wait(state);
+                Ok(())
+            })
+        });
+        guard.unwrap()
+    }
+
+    /// Take the lock for reading in a syscall, or return ERESTART once the process is asleep
+    /// waiting for it
+    #[track_caller]
+    pub fn read_syscall(&self) -> Result<RwLockReadGuard<'_, T>, i64> {
+        tracked(self.addr(), self.class, || {
+            self.acquire_read(|state| {
+                boost_holder(state.writer, self.addr());
+                self.readers.wait_syscall(state).map(|_| ())
+            })
+        })
+    }
+
+    /// Take the lock for writing, sleeping until nobody holds it. Panics outside of a kernel
+    /// thread.
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let guard = tracked(self.addr(), self.class, || {
+            self.acquire_write(|state| {
+                boost_holder(state.writer, self.addr());
+                self.writers.wait(state);
+                Ok(())
+            })
+        });
+        guard.unwrap()
+    }
+
+    /// Take the lock for writing in a syscall, or return ERESTART once the process is asleep
+    /// waiting for it
+    #[track_caller]
+    pub fn write_syscall(&self) -> Result<RwLockWriteGuard<'_, T>, i64> {
+        tracked(self.addr(), self.class, || {
+            self.acquire_write(|state| {
+                boost_holder(state.writer, self.addr());
+                self.writers.wait_syscall(state).map(|_| ())
+            })
+        })
+    }
+
+    /// The task holding the lock for writing, if any
+    pub fn writer(&self) -> Option<Task> {
+        self.state.lock().writer
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.addr());
+        let mut state = self.lock.state.lock();
+        state.readers -= 1;
+        let last = state.readers == 0;
+        drop(state);
+        if last {
+            self.lock.writers.wake_one();
+        }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.addr());
+        let holder = self.lock.state.lock().writer.take();
+        if let Some(holder) = holder {
+            sched::unboost(holder, self.lock.addr());
+        }
+        // readers waiting for this writer get in after any other writer
+        if !self.lock.writers.wake_one() {
+            self.lock.readers.wake_all();
+        }
+    }
+}
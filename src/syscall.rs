@@ -0,0 +1,570 @@
+/// System calls
+///
+/// A process puts the syscall number in a7 and the arguments in a0-a5, and gets the result
+/// back in a0. Errors are returned as a negated errno. Standard calls use the Linux numbers
+/// for RISC-V, and those specific to this kernel start at SYS_CPU_STATS.
+use crate::{
+    cpu::MAX_HARTS,
+    cpustat::{self, CpuStats},
+    file::{self, FdTable},
+    pid::Pid,
+    pipe::PIPE_BUF,
+    proc::{self, TrapFrame},
+    sched::{self, Policy, SchedStats, Task, TaskStats, MAX_RT_PRIORITY, NICE_MAX, NICE_MIN},
+    signal::{self, SigAction, SIGCHLD, SIGKILL, SIGSTOP},
+    spinlock::Spinlock,
+    uaccess::{copy_from_user, copy_to_user, strncpy_from_user},
+};
+
+// Linux errno values
+pub const ENOENT: i64 = 2;
+pub const ESRCH: i64 = 3;
+pub const EBADF: i64 = 9;
+pub const ECHILD: i64 = 10;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EFAULT: i64 = 14;
+pub const EINVAL: i64 = 22;
+pub const ENFILE: i64 = 23;
+pub const EMFILE: i64 = 24;
+pub const ESPIPE: i64 = 29;
+pub const EPIPE: i64 = 32;
+pub const EDEADLK: i64 = 35;
+pub const ENAMETOOLONG: i64 = 36;
+pub const ENOSYS: i64 = 38;
+
+/// Returned by a syscall that put the process to sleep (see wait.rs). The process makes the
+/// same syscall again once woken, so this never reaches user space.
+pub const ERESTART: i64 = 512;
+
+/// dup(oldfd)
+pub const SYS_DUP: u64 = 23;
+
+/// dup3(oldfd, newfd, flags). There's no dup2 on RISC-V; libc builds it on this.
+pub const SYS_DUP3: u64 = 24;
+
+/// openat(dirfd, const char *path, flags, mode). There are no directories, so path must be
+/// absolute and dirfd and mode are ignored.
+pub const SYS_OPENAT: u64 = 56;
+
+/// close(fd)
+pub const SYS_CLOSE: u64 = 57;
+
+/// pipe2(int fds[2], flags). Only O_NONBLOCK and O_CLOEXEC are allowed in flags.
+pub const SYS_PIPE2: u64 = 59;
+
+/// lseek(fd, offset, whence)
+pub const SYS_LSEEK: u64 = 62;
+
+/// read(fd, buf, count)
+pub const SYS_READ: u64 = 63;
+
+/// write(fd, const void *buf, count)
+pub const SYS_WRITE: u64 = 64;
+
+/// exit(status). Ends the calling thread only; the process ends with its last thread.
+pub const SYS_EXIT: u64 = 93;
+
+/// exit_group(status)
+pub const SYS_EXIT_GROUP: u64 = 94;
+
+/// sched_setscheduler(pid, policy, const struct sched_param *param). The policy is SCHED_OTHER
+/// for the fair class, or SCHED_FIFO for the real-time class.
+pub const SYS_SCHED_SETSCHEDULER: u64 = 119;
+
+/// sched_setaffinity(pid, len, const unsigned long *mask). Bit n of the mask allows hart n.
+pub const SYS_SCHED_SETAFFINITY: u64 = 122;
+
+/// sched_yield()
+pub const SYS_SCHED_YIELD: u64 = 124;
+
+/// kill(pid, sig). Only a single process can be signalled, so pid must be positive.
+pub const SYS_KILL: u64 = 129;
+
+/// rt_sigaction(sig, const struct sigaction *act, struct sigaction *oldact, sigsetsize)
+pub const SYS_RT_SIGACTION: u64 = 134;
+
+/// rt_sigprocmask(how, const sigset_t *set, sigset_t *oldset, sigsetsize)
+pub const SYS_RT_SIGPROCMASK: u64 = 135;
+
+/// rt_sigreturn(), made by the trampoline that signal handlers return to (see signal.rs)
+pub const SYS_RT_SIGRETURN: u64 = 139;
+
+/// setpriority(which, who, nice). Only PRIO_PROCESS is supported.
+pub const SYS_SETPRIORITY: u64 = 140;
+
+/// getpid(), which is the tid of the first thread of the process
+pub const SYS_GETPID: u64 = 172;
+
+/// gettid()
+pub const SYS_GETTID: u64 = 178;
+
+/// clone(flags, newsp, int *parent_tid, tls, int *child_tid). Either makes a thread, with
+/// flags having CLONE_VM, CLONE_FILES, CLONE_SIGHAND and CLONE_THREAD, or forks, with flags
+/// being SIGCHLD and newsp 0 as for fork() in libc (see `proc::fork`). If newsp is 0 for a
+/// thread, the kernel makes a stack for it.
+pub const SYS_CLONE: u64 = 220;
+
+/// wait4(pid, int *wstatus, options, struct rusage *rusage). pid is -1 for any child, or the
+/// pid of one. Only WNOHANG is supported, and rusage is left alone.
+pub const SYS_WAIT4: u64 = 260;
+
+/// cpu_stats(hartid, struct cpu_stats *stats)
+pub const SYS_CPU_STATS: u64 = 1000;
+
+/// proc_stats(pid, struct proc_stats *stats)
+pub const SYS_PROC_STATS: u64 = 1001;
+
+/// sched_stats(hartid, struct sched_stats *stats)
+pub const SYS_SCHED_STATS: u64 = 1002;
+
+/// thread_join(tid, int *wstatus). Waits for a thread of the calling process to exit and
+/// reaps it.
+pub const SYS_THREAD_JOIN: u64 = 1003;
+
+const SCHED_OTHER: u64 = 0;
+const SCHED_FIFO: u64 = 1;
+
+const PRIO_PROCESS: u64 = 0;
+
+/// Longest path that openat takes, including the NUL
+const PATH_MAX: usize = 256;
+
+/// Bytes copied to or from user memory at a time by read and write, which is as much as
+/// has to go into a pipe at once
+const IO_CHUNK: usize = PIPE_BUF;
+
+const SIG_BLOCK: u64 = 0;
+const SIG_UNBLOCK: u64 = 1;
+const SIG_SETMASK: u64 = 2;
+
+const WNOHANG: u64 = 1;
+
+// clone flags, as in Linux
+const CLONE_VM: u64 = 0x100;
+const CLONE_FS: u64 = 0x200;
+const CLONE_FILES: u64 = 0x400;
+const CLONE_SIGHAND: u64 = 0x800;
+const CLONE_THREAD: u64 = 0x10000;
+const CLONE_SYSVSEM: u64 = 0x40000;
+const CLONE_SETTLS: u64 = 0x80000;
+const CLONE_PARENT_SETTID: u64 = 0x100000;
+const CLONE_CHILD_CLEARTID: u64 = 0x200000;
+const CLONE_CHILD_SETTID: u64 = 0x1000000;
+const CLONE_THREAD_FLAGS: u64 = CLONE_VM | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+
+pub type Result = core::result::Result<u64, i64>;
+
+/// Carry out the syscall requested by the process whose registers are in `frame`
+pub fn handle(frame: &mut TrapFrame) {
+    let args = [
+        frame.regs[10],
+        frame.regs[11],
+        frame.regs[12],
+        frame.regs[13],
+        frame.regs[14],
+        frame.regs[15],
+    ];
+    let result = match frame.regs[17] {
+        SYS_DUP => dup(args[0]),
+        SYS_DUP3 => dup3(args[0], args[1], args[2]),
+        SYS_OPENAT => openat(args[1], args[2]),
+        SYS_CLOSE => close(args[0]),
+        SYS_PIPE2 => pipe2(args[0], args[1]),
+        SYS_LSEEK => lseek(args[0], args[1], args[2]),
+        SYS_READ => read(args[0], args[1], args[2]),
+        SYS_WRITE => write(args[0], args[1], args[2]),
+        SYS_EXIT => proc::exit(proc::exited_status(args[0])),
+        SYS_EXIT_GROUP => proc::exit_group(proc::exited_status(args[0])),
+        SYS_SCHED_SETSCHEDULER => sched_setscheduler(args[0], args[1], args[2]),
+        SYS_SCHED_SETAFFINITY => sched_setaffinity(args[0], args[1], args[2]),
+        SYS_SCHED_YIELD => {
+            sched::yield_process();
+            Ok(0)
+        }
+        SYS_KILL => kill(args[0], args[1]),
+        SYS_RT_SIGACTION => rt_sigaction(args[0], args[1], args[2], args[3]),
+        SYS_RT_SIGPROCMASK => rt_sigprocmask(args[0], args[1], args[2], args[3]),
+        SYS_RT_SIGRETURN => signal::sigreturn(frame),
+        SYS_SETPRIORITY => setpriority(args[0], args[1], args[2]),
+        SYS_GETPID => Ok(unsafe { crate::proc!().tgid } as u64),
+        SYS_GETTID => Ok(unsafe { crate::proc!().pid } as u64),
+        SYS_CLONE => clone(args[0], args[1], args[2], args[3], args[4]),
+        SYS_WAIT4 => wait4(args[0], args[1], args[2]),
+        SYS_CPU_STATS => cpu_stats(args[0], args[1]),
+        SYS_PROC_STATS => proc_stats(args[0], args[1]),
+        SYS_SCHED_STATS => sched_stats(args[0], args[1]),
+        SYS_THREAD_JOIN => thread_join(args[0], args[1]),
+        number => {
+            debug!("Unknown syscall {}", number);
+            Err(ENOSYS)
+        }
+    };
+    frame.regs[10] = match result {
+        Ok(value) => value,
+        Err(ERESTART) => {
+            // leave the arguments alone and go back to the ecall
+            frame.epc -= 4;
+            return;
+        }
+        Err(errno) => -errno as u64,
+    };
+}
+
+/// The descriptors of the calling process, which its threads share
+fn files() -> &'static Spinlock<FdTable> {
+    &proc::leader(unsafe { crate::cpu!().current_proc }).files
+}
+
+fn dup(oldfd: u64) -> Result {
+    files().lock().dup(oldfd)
+}
+
+fn dup3(oldfd: u64, newfd: u64, flags: u64) -> Result {
+    // O_CLOEXEC is the only flag, and there's no exec for it to matter to
+    if oldfd == newfd || flags & !(file::O_CLOEXEC as u64) != 0 {
+        return Err(EINVAL);
+    }
+    let replaced = files().lock().dup_to(oldfd, newfd)?;
+    if let Some(file) = replaced {
+        file::put(file);
+    }
+    Ok(newfd)
+}
+
+fn openat(path: u64, flags: u64) -> Result {
+    let mut buf = [0u8; PATH_MAX];
+    let pt = unsafe { &*crate::proc!().root };
+    let len = strncpy_from_user(pt, &mut buf, path).map_err(|_| EFAULT)?;
+    if len == buf.len() {
+        return Err(ENAMETOOLONG);
+    }
+    let file = file::open(&buf[..len], flags as u32)?;
+    install(file)
+}
+
+/// Give `file` the lowest free descriptor of the calling process, or close it if there is
+/// none. It isn't closed with the table locked (see file.rs).
+fn install(file: usize) -> Result {
+    let fd = files().lock().install(file);
+    if fd.is_err() {
+        file::put(file);
+    }
+    fd
+}
+
+fn close(fd: u64) -> Result {
+    let file = files().lock().close(fd)?;
+    file::put(file);
+    Ok(0)
+}
+
+fn pipe2(fds: u64, flags: u64) -> Result {
+    if flags & !((file::O_NONBLOCK | file::O_CLOEXEC) as u64) != 0 {
+        return Err(EINVAL);
+    }
+    let (read_end, write_end) = file::pipe(flags as u32 & file::O_NONBLOCK)?;
+    let installed = files().lock().install_pair(read_end, write_end);
+    let Ok((read_fd, write_fd)) = installed else {
+        file::put(read_end);
+        file::put(write_end);
+        return installed.map(|_| 0);
+    };
+    let mut bytes = [0u8; 8]; // int fds[2]
+    bytes[..4].copy_from_slice(&(read_fd as i32).to_ne_bytes());
+    bytes[4..].copy_from_slice(&(write_fd as i32).to_ne_bytes());
+    let pt = unsafe { &*crate::proc!().root };
+    if copy_to_user(pt, fds, &bytes).is_err() {
+        let mut files = files().lock();
+        let closed = [files.close(read_fd), files.close(write_fd)];
+        drop(files);
+        closed.into_iter().flatten().for_each(file::put);
+        return Err(EFAULT);
+    }
+    Ok(0)
+}
+
+fn lseek(fd: u64, offset: u64, whence: u64) -> Result {
+    let file = files().lock().file(fd)?;
+    file::lseek(file, offset as i64, whence)
+}
+
+fn read(fd: u64, buf: u64, count: u64) -> Result {
+    // another thread may close fd while this one sleeps
+    let file = files().lock().hold(fd)?;
+    // a read that comes up short may as well stop at a chunk
+    let mut bytes = [0u8; IO_CHUNK];
+    let len = count.min(bytes.len() as u64) as usize;
+    let result = file::read(file, &mut bytes[..len]);
+    release(file);
+    let n = result? as usize;
+    let pt = unsafe { &*crate::proc!().root };
+    copy_to_user(pt, buf, &bytes[..n]).map_err(|_| EFAULT)?;
+    Ok(n as u64)
+}
+
+fn write(fd: u64, buf: u64, count: u64) -> Result {
+    let file = files().lock().hold(fd)?;
+    let result = write_file(file, buf, count);
+    release(file);
+    result
+}
+
+/// Drop a reference taken with `hold`. Dropping the last one may take RUN_LOCK, which is held
+/// for the rest of the syscall if the process went to sleep, so then it waits until the
+/// process is switched out.
+fn release(file: usize) {
+    if sched::process_sleeping() {
+        sched::after_sleep(file::put, file);
+    } else {
+        file::put(file);
+    }
+}
+
+fn write_file(file: usize, buf: u64, count: u64) -> Result {
+    let pt = unsafe { &*crate::proc!().root };
+    let mut written = 0;
+    while written < count {
+        let mut bytes = [0u8; IO_CHUNK];
+        let len = (count - written).min(bytes.len() as u64) as usize;
+        let copied = copy_from_user(pt, &mut bytes[..len], buf + written);
+        // Report what was written before an error as a short write. Once something has been
+        // written, a chunk that would block doesn't sleep but ends the write with it.
+        let result = copied
+            .map_err(|_| EFAULT)
+            .and_then(|_| file::write(file, &bytes[..len], written > 0));
+        match result {
+            Ok(n) => written += n,
+            Err(_) if written > 0 => break,
+            Err(errno) => return Err(errno),
+        }
+    }
+    Ok(written)
+}
+
+/// The process with pid `pid`, or the calling process for pid 0
+fn process(pid: u64) -> core::result::Result<Task, i64> {
+    if pid == 0 {
+        return Ok(Task::Process(unsafe { crate::cpu!().current_proc }));
+    }
+    let pid = Pid::try_from(pid).map_err(|_| ESRCH)?;
+    proc::find(pid).map(Task::Process).ok_or(ESRCH)
+}
+
+fn sched_setscheduler(pid: u64, policy: u64, param: u64) -> Result {
+    let task = process(pid)?;
+    let mut priority = [0u8; 4]; // struct sched_param { int sched_priority; }
+    let pt = unsafe { &*crate::proc!().root };
+    copy_from_user(pt, &mut priority, param).map_err(|_| EFAULT)?;
+    let priority = i32::from_ne_bytes(priority);
+    match policy {
+        SCHED_OTHER if priority == 0 => sched::set_scheduler(task, Policy::Fair, 0),
+        SCHED_FIFO if (1..=MAX_RT_PRIORITY as i32).contains(&priority) => {
+            sched::set_scheduler(task, Policy::RealTime, priority as u8)
+        }
+        _ => return Err(EINVAL),
+    }
+    Ok(0)
+}
+
+fn sched_setaffinity(pid: u64, len: u64, mask: u64) -> Result {
+    let task = process(pid)?;
+    // harts past the first 64 don't exist, so only the first word matters
+    let mut bytes = [0u8; 8];
+    let len = len.min(bytes.len() as u64) as usize;
+    let pt = unsafe { &*crate::proc!().root };
+    copy_from_user(pt, &mut bytes[..len], mask).map_err(|_| EFAULT)?;
+    if !sched::set_affinity(task, u64::from_le_bytes(bytes)) {
+        return Err(EINVAL);
+    }
+    Ok(0)
+}
+
+fn setpriority(which: u64, who: u64, nice: u64) -> Result {
+    if which != PRIO_PROCESS {
+        return Err(EINVAL);
+    }
+    let task = process(who)?;
+    let nice = (nice as i32).clamp(NICE_MIN as i32, NICE_MAX as i32);
+    sched::set_nice(task, nice as i8);
+    Ok(0)
+}
+
+fn kill(pid: u64, sig: u64) -> Result {
+    let sig = sig as usize;
+    if sig != 0 && !signal::is_valid(sig) {
+        return Err(EINVAL);
+    }
+    if pid as i64 <= 0 {
+        return Err(EINVAL);
+    }
+    let pid = Pid::try_from(pid).map_err(|_| ESRCH)?;
+    if !signal::kill(pid, sig) {
+        return Err(ESRCH);
+    }
+    Ok(0)
+}
+
+fn rt_sigaction(sig: u64, act: u64, oldact: u64, sigsetsize: u64) -> Result {
+    let sig = sig as usize;
+    if sigsetsize != 8 || !signal::is_valid(sig) {
+        return Err(EINVAL);
+    }
+    let index = unsafe { crate::cpu!().current_proc };
+    let pt = unsafe { &*crate::proc!().root };
+    let size = core::mem::size_of::<SigAction>();
+    let old = proc::leader(index).actions.lock().get(sig);
+    if act != 0 {
+        if sig == SIGKILL || sig == SIGSTOP {
+            return Err(EINVAL);
+        }
+        let mut new = old;
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(&mut new as *mut SigAction as *mut u8, size) };
+        copy_from_user(pt, bytes, act).map_err(|_| EFAULT)?;
+        signal::set_action(index, sig, new);
+    }
+    if oldact != 0 {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(&old as *const SigAction as *const u8, size) };
+        copy_to_user(pt, oldact, bytes).map_err(|_| EFAULT)?;
+    }
+    Ok(0)
+}
+
+fn rt_sigprocmask(how: u64, set: u64, oldset: u64, sigsetsize: u64) -> Result {
+    if sigsetsize != 8 {
+        return Err(EINVAL);
+    }
+    let signals = unsafe { &mut crate::proc!().signals };
+    let pt = unsafe { &*crate::proc!().root };
+    let old = signals.blocked();
+    if set != 0 {
+        let mut bytes = [0u8; 8];
+        copy_from_user(pt, &mut bytes, set).map_err(|_| EFAULT)?;
+        let set = u64::from_ne_bytes(bytes);
+        let blocked = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(EINVAL),
+        };
+        // signals that were just unblocked are delivered on the way back to user mode
+        signals.set_blocked(blocked);
+    }
+    if oldset != 0 {
+        copy_to_user(pt, oldset, &old.to_ne_bytes()).map_err(|_| EFAULT)?;
+    }
+    Ok(0)
+}
+
+fn wait4(pid: u64, wstatus: u64, options: u64) -> Result {
+    if options & !WNOHANG != 0 {
+        return Err(EINVAL);
+    }
+    let pid = match pid as i64 {
+        -1 => None,
+        1.. => Some(Pid::try_from(pid).map_err(|_| ECHILD)?),
+        _ => return Err(EINVAL),
+    };
+    let Some((pid, status)) = proc::wait(pid, options & WNOHANG != 0)? else {
+        return Ok(0);
+    };
+    if wstatus != 0 {
+        let pt = unsafe { &*crate::proc!().root };
+        copy_to_user(pt, wstatus, &status.to_ne_bytes()).map_err(|_| EFAULT)?;
+    }
+    Ok(pid as u64)
+}
+
+fn clone(flags: u64, newsp: u64, parent_tid: u64, tls: u64, child_tid: u64) -> Result {
+    let optional = CLONE_FS
+        | CLONE_SYSVSEM
+        | CLONE_SETTLS
+        | CLONE_PARENT_SETTID
+        | CLONE_CHILD_CLEARTID
+        | CLONE_CHILD_SETTID;
+    if flags == SIGCHLD as u64 && newsp == 0 {
+        return proc::fork().map(|pid| pid as u64);
+    }
+    // the low byte is the signal sent to the parent on exit, which threads don't have
+    if flags & CLONE_THREAD_FLAGS != CLONE_THREAD_FLAGS
+        || flags & !(CLONE_THREAD_FLAGS | optional) != 0
+    {
+        return Err(EINVAL);
+    }
+    let tls = (flags & CLONE_SETTLS != 0).then_some(tls);
+    let clear_child_tid = if flags & CLONE_CHILD_CLEARTID != 0 {
+        child_tid
+    } else {
+        0
+    };
+    let tid = proc::clone_thread(newsp, tls, clear_child_tid)?;
+    // the new thread shares the address space, so it sees these too
+    let pt = unsafe { &*crate::proc!().root };
+    let bytes = (tid as i32).to_ne_bytes();
+    if flags & CLONE_PARENT_SETTID != 0 {
+        copy_to_user(pt, parent_tid, &bytes).map_err(|_| EFAULT)?;
+    }
+    if flags & CLONE_CHILD_SETTID != 0 {
+        copy_to_user(pt, child_tid, &bytes).map_err(|_| EFAULT)?;
+    }
+    Ok(tid as u64)
+}
+
+fn thread_join(tid: u64, wstatus: u64) -> Result {
+    let tid = Pid::try_from(tid).map_err(|_| ESRCH)?;
+    let status = proc::join(tid)?;
+    if wstatus != 0 {
+        let pt = unsafe { &*crate::proc!().root };
+        copy_to_user(pt, wstatus, &status.to_ne_bytes()).map_err(|_| EFAULT)?;
+    }
+    Ok(0)
+}
+
+fn proc_stats(pid: u64, buf: u64) -> Result {
+    let stats = sched::stats(process(pid)?);
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &stats as *const TaskStats as *const u8,
+            core::mem::size_of::<TaskStats>(),
+        )
+    };
+    let pt = unsafe { &*crate::proc!().root };
+    copy_to_user(pt, buf, bytes).map_err(|_| EFAULT)?;
+    Ok(0)
+}
+
+fn check_hart(hartid: u64) -> core::result::Result<usize, i64> {
+    if hartid >= MAX_HARTS as u64 || crate::cpu::online() & (1 << hartid) == 0 {
+        return Err(EINVAL);
+    }
+    Ok(hartid as usize)
+}
+
+fn sched_stats(hartid: u64, buf: u64) -> Result {
+    let stats = sched::hart_stats(check_hart(hartid)?);
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &stats as *const SchedStats as *const u8,
+            core::mem::size_of::<SchedStats>(),
+        )
+    };
+    let pt = unsafe { &*crate::proc!().root };
+    copy_to_user(pt, buf, bytes).map_err(|_| EFAULT)?;
+    Ok(0)
+}
+
+fn cpu_stats(hartid: u64, buf: u64) -> Result {
+    let hartid = check_hart(hartid)?;
+    let stats = cpustat::get(hartid);
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &stats as *const CpuStats as *const u8,
+            core::mem::size_of::<CpuStats>(),
+        )
+    };
+    let pt = unsafe { &*crate::proc!().root };
+    copy_to_user(pt, buf, bytes).map_err(|_| EFAULT)?;
+    Ok(0)
+}
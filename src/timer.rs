@@ -0,0 +1,70 @@
+/// The scheduler tick
+///
+/// Machine timer interrupts can't be delegated to supervisor mode, so machine_vec (see trap.s)
+/// takes them. It sets the hart's mtimecmp for the next tick, flags the tick in the hart's
+/// MachineScratch and raises a supervisor software interrupt, the same one that IPIs arrive
+/// as (see ipi.rs). `handle_intr` then passes the tick on to the scheduler.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    cpu::MAX_HARTS,
+    cpustat,
+    csr::MIE_MTIE,
+    csr_read, csr_set_bits, csr_write,
+    kmem::{phys_to_virt, CLINT_BASE},
+    reg_read, sched,
+};
+
+/// Scheduler ticks per second
+pub const TICK_HZ: u64 = 100;
+
+const MTIMECMP_BASE: u64 = CLINT_BASE + 0x4000;
+
+/// What machine_vec needs on a hart, which it finds through mscratch. The offsets must match
+/// trap.s.
+#[repr(C)]
+pub struct MachineScratch {
+    saved: [u64; 2], // room to save registers
+    mtimecmp: u64,   // physical address of the hart's mtimecmp
+    interval: u64,   // ticks of mtime from one timer interrupt to the next
+    tick: AtomicU64, // set with every timer interrupt
+}
+
+const EMPTY_SCRATCH: MachineScratch = MachineScratch {
+    saved: [0; 2],
+    mtimecmp: 0,
+    interval: 0,
+    tick: AtomicU64::new(0),
+};
+static mut MACHINE_SCRATCH: [MachineScratch; MAX_HARTS] = [EMPTY_SCRATCH; MAX_HARTS];
+
+/// Set up machine_vec on this hart. Called by kinit in machine mode, where addresses are
+/// physical. Timer interrupts don't happen until `init` sets the interval.
+pub unsafe fn machine_init(hartid: usize) {
+    let scratch = &mut MACHINE_SCRATCH[hartid];
+    scratch.mtimecmp = MTIMECMP_BASE + 8 * hartid as u64;
+    (scratch.mtimecmp as *mut u64).write_volatile(u64::MAX);
+    csr_write!(mscratch, scratch as *mut MachineScratch as u64);
+    csr_set_bits!(mie, MIE_MTIE);
+}
+
+/// Start the tick on this hart
+pub fn init() {
+    let hartid = unsafe { reg_read!(tp) } as usize;
+    let interval = cpustat::timebase_hz() / TICK_HZ;
+    unsafe {
+        let scratch = &mut MACHINE_SCRATCH[hartid];
+        scratch.interval = interval;
+        let mtimecmp = phys_to_virt(scratch.mtimecmp) as *mut u64;
+        mtimecmp.write_volatile(csr_read!(time) + interval);
+    }
+}
+
+/// Handle a supervisor software interrupt, which may have been a tick
+pub fn handle_intr() {
+    let hartid = unsafe { reg_read!(tp) } as usize;
+    let tick = unsafe { &MACHINE_SCRATCH[hartid].tick };
+    if tick.swap(0, Ordering::AcqRel) != 0 {
+        sched::tick();
+    }
+}
@@ -0,0 +1,34 @@
+/// TLB maintenance
+///
+/// Every hart caches translations on its own, so when a mapping is removed or loses
+/// permissions, all of them have to flush it before the old translation is known to be gone.
+use core::{arch::asm, ops::Range};
+
+use crate::{ipi, kmem::PAGE_SIZE, page_floor};
+
+/// Ranges of more pages than this flush the whole TLB instead
+const MAX_FLUSH_PAGES: u64 = 64;
+
+/// Flush translations of `range` on the current hart
+pub fn flush_local(range: &Range<u64>) {
+    unsafe {
+        if range.end - range.start > MAX_FLUSH_PAGES * PAGE_SIZE {
+            asm!("sfence.vma");
+            return;
+        }
+        let mut page = page_floor!(range.start);
+        while page < range.end {
+            asm!("sfence.vma {}, zero", in(reg) page);
+            page += PAGE_SIZE;
+        }
+    }
+}
+
+/// Flush translations of `range` on every online hart, and wait until all of them have
+pub fn shootdown(range: Range<u64>) {
+    ipi::call_all(flush_ipi, &range as *const Range<u64> as usize);
+}
+
+fn flush_ipi(range: usize) {
+    flush_local(unsafe { &*(range as *const Range<u64>) });
+}
@@ -1,100 +1,218 @@
+use core::ptr::addr_of;
+
 use crate::{
     cpu,
-    csr::SSTATUS_SPP,
-    csr_read, csr_read_field,
-    plic::{self, PlicPrivilege},
-    proc, uart,
+    cpu::MAX_HARTS,
+    cpustat::{self, Mode},
+    csr::{SSTATUS_SIE, SSTATUS_SPIE, SSTATUS_SPP},
+    csr_clear_bits, csr_read, csr_read_field, csr_set_bits, csr_write, fp, gdb, ipi, irq,
+    kmem::hart_stack,
+    ksyms::Symbolized,
+    misaligned,
+    proc::TrapFrame,
+    reg_read,
+    scause::{Exception, Interrupt, Trap},
+    sched,
+    signal::{self, SIGBUS, SIGILL, SIGSEGV},
+    syscall, timer, vector,
 };
 
-const INTERRUPT: u64 = 1 << 63;
-#[repr(u64)]
-#[derive(Debug)]
-pub enum SCause {
-    InstAddrMisaligned = 0,
-    InstAccessFault = 1,
-    InstIllegal = 2,
-    Breakpoint = 3,
-    LoadAddrMisaligned = 4,
-    LoadAccessFault = 5,
-    StoreAMOAddrMisaligned = 6,
-    StoreAMOAccessFault = 7,
-    EnvCallFromUMode = 8,
-    EnvCallFromSMode = 9,
-    InstPageFault = 12,
-    LoadPageFault = 13,
-    StoreAMOPageFault = 15,
-
-    SSoftwareInterrupt = INTERRUPT | 1,
-    STimerInterrupt = INTERRUPT | 5,
-    SExternalInterrupt = INTERRUPT | 9,
+extern "C" {
+    fn user_ret(frame: &TrapFrame) -> !; // defined in trap.s
 }
 
-impl SCause {
-    pub fn should_panic(&self) -> bool {
-        match self {
-            SCause::InstAddrMisaligned
-            | SCause::InstAccessFault
-            | SCause::InstIllegal
-            | SCause::LoadAddrMisaligned
-            | SCause::LoadAccessFault
-            | SCause::StoreAMOAddrMisaligned
-            | SCause::StoreAMOAccessFault
-            | SCause::InstPageFault
-            | SCause::LoadPageFault
-            | SCause::StoreAMOPageFault => true,
-            _ => false,
-        }
+/// Size of the stack that kernel_vec moves each hart to when a kernel thread overflows its
+/// own. Must match trap.s.
+const EMERGENCY_STACK_SIZE: usize = 16384;
+
+#[repr(C, align(16))]
+struct EmergencyStack([u8; EMERGENCY_STACK_SIZE]);
+
+const EMPTY_STACK: EmergencyStack = EmergencyStack([0; EMERGENCY_STACK_SIZE]);
+#[no_mangle]
+static mut EMERGENCY_STACKS: [EmergencyStack; MAX_HARTS] = [EMPTY_STACK; MAX_HARTS];
+
+/// Set up kernel traps on this hart: kernel_vec expects the top of the hart's emergency stack
+/// in sscratch. user_vec puts it back there after it held a process' TrapFrame.
+pub fn init() {
+    unsafe {
+        let hartid = reg_read!(tp) as usize;
+        let top = addr_of!(EMERGENCY_STACKS[hartid]) as u64 + EMERGENCY_STACK_SIZE as u64;
+        csr_write!(sscratch, top);
     }
 }
 
-pub const UART_IRQ: u32 = 0x0a;
+/// Registers pushed onto the stack by kernel_vec, indexed by register number
+#[repr(C)]
+pub struct KernelFrame {
+    pub regs: [u64; 32],
+}
+
+impl KernelFrame {
+    /// Value of sp before kernel_vec made room for the frame
+    pub fn sp(&self) -> u64 {
+        self.regs[2] + core::mem::size_of::<KernelFrame>() as u64
+    }
+}
 
 #[no_mangle]
-extern "C" fn kernel_trap() {
+extern "C" fn kernel_trap(frame: &mut KernelFrame) {
+    // let the panic handler find the interrupted registers
+    let prev_frame = unsafe { cpu!().kernel_frame };
+    unsafe {
+        cpu!().kernel_frame = frame;
+    }
+    handle_kernel_trap(frame);
+    unsafe {
+        cpu!().kernel_frame = prev_frame;
+    }
+}
+
+fn handle_kernel_trap(frame: &mut KernelFrame) {
     unsafe {
         let epc: u64 = csr_read!(sepc);
         let status: u64 = csr_read!(sstatus);
-        let cause: SCause = core::mem::transmute(csr_read!(scause));
+        let scause: u64 = csr_read!(scause);
 
         if csr_read_field!(sstatus, SSTATUS_SPP) == 0 {
             panic!("trap originated from user mode");
         }
-        if cause.should_panic() {
-            panic!("Kernel trap 0x{:08x} {:064b} {:?}", epc, status, cause);
-        }
-        match cause {
-            SCause::EnvCallFromUMode => {
-                debug!("EnvCall from User mode!");
-                handle_syscall();
-            }
-            SCause::SExternalInterrupt => {
-                // handle external interrupts until there are none left
-                loop {
-                    let irq: u32 = plic::claim(PlicPrivilege::Supervisor);
-                    match irq {
-                        0 => {
-                            // no pending interrupts
-                            return;
-                        }
-                        1..=8 => {}
-                        UART_IRQ => {
-                            // either received a byte or transmit buffer is empty
-                            uart::handle_intr();
-                        }
-                        _ => {
-                            debug!("Unexpected PLIC IRQ {}", irq)
-                        }
-                    }
-                    plic::complete(PlicPrivilege::Supervisor, irq);
+        let trap = match Trap::decode(scause) {
+            Ok(trap) => trap,
+            Err(unknown) => {
+                debug!("Unknown trap cause: {}", unknown);
+                if unknown.is_interrupt() {
+                    return;
                 }
+                // returning would just retry the faulting instruction
+                panic!("Unknown exception at {}", Symbolized(epc));
+            }
+        };
+        match trap {
+            Trap::Exception(exception) if exception.should_panic() => {
+                panic!(
+                    "Kernel trap at {} {:064b} {}",
+                    Symbolized(epc),
+                    status,
+                    trap
+                );
             }
+            Trap::Exception(Exception::Breakpoint) => gdb::enter_kernel(frame, gdb::SIGTRAP),
+            Trap::Interrupt(interrupt) => handle_interrupt(interrupt),
             _ => {}
         }
     }
 }
 
-fn handle_syscall() {
+/// Interrupt time is accounted separately from whatever was interrupted
+fn handle_interrupt(interrupt: Interrupt) {
+    let prev = cpustat::switch(Mode::Interrupt);
+    match interrupt {
+        Interrupt::SExternal => irq::handle_external(),
+        Interrupt::SSoftware => {
+            ipi::handle_intr();
+            timer::handle_intr();
+        }
+        _ => {}
+    }
+    cpustat::switch(prev);
+}
+
+/// Called by user_vec with the registers of the process that trapped
+#[no_mangle]
+extern "C" fn user_trap(frame: &mut TrapFrame) -> ! {
+    cpustat::switch(Mode::System);
+    fp::save_user(&mut frame.fp);
+    vector::save_user(&mut frame.vector);
+    handle_user_trap(frame);
+    if sched::process_sleeping() {
+        sched::idle_after_sleep();
+    }
+    if sched::should_preempt() {
+        sched::preempt();
+    }
+    return_to_user(frame)
+}
+
+fn handle_user_trap(frame: &mut TrapFrame) {
+    let scause = unsafe { csr_read!(scause) };
+    match Trap::decode(scause) {
+        Ok(Trap::Exception(Exception::EnvCallFromUMode)) => {
+            // return to the instruction after ecall
+            frame.epc += 4;
+            syscall::handle(frame);
+        }
+        Ok(Trap::Exception(Exception::Breakpoint)) => gdb::enter_user(frame, gdb::SIGTRAP),
+        Ok(Trap::Exception(Exception::InstIllegal)) if handle_first_use(frame) => {}
+        Ok(Trap::Exception(Exception::InstIllegal)) => signal::force(SIGILL),
+        Ok(Trap::Exception(Exception::InstAddrMisaligned)) => signal::force(SIGBUS),
+        Ok(Trap::Exception(Exception::LoadAddrMisaligned)) => {
+            handle_misaligned(frame, misaligned::Kind::Load)
+        }
+        Ok(Trap::Exception(Exception::StoreAMOAddrMisaligned)) => {
+            handle_misaligned(frame, misaligned::Kind::Store)
+        }
+        Ok(Trap::Exception(
+            Exception::InstAccessFault
+            | Exception::LoadAccessFault
+            | Exception::StoreAMOAccessFault
+            | Exception::InstPageFault
+            | Exception::LoadPageFault
+            | Exception::StoreAMOPageFault,
+        )) => signal::force(SIGSEGV),
+        Ok(Trap::Interrupt(interrupt)) => handle_interrupt(interrupt),
+        Ok(trap) => panic!("User trap at 0x{:x}: {}", frame.epc, trap),
+        Err(unknown) => panic!("User trap at 0x{:x}: {}", frame.epc, unknown),
+    }
+}
+
+/// Turn on vectors or floating point for a process that tried to use them for the first time
+fn handle_first_use(frame: &mut TrapFrame) -> bool {
+    let inst = unsafe { csr_read!(stval) };
+    vector::handle_first_use(&mut frame.vector, inst) || fp::handle_first_use(&mut frame.fp, inst)
+}
+
+fn handle_misaligned(frame: &mut TrapFrame, kind: misaligned::Kind) {
+    let process = unsafe { &mut crate::proc!() };
+    let pt = unsafe { &*process.root };
+    match misaligned::emulate(pt, frame, kind) {
+        Ok(()) => {}
+        Err(misaligned::Error::Fault(_)) => return signal::force(SIGSEGV),
+        Err(err) => {
+            debug!(
+                "Process {} misaligned access at 0x{:x}: {:?}",
+                process.pid, frame.epc, err
+            );
+            return signal::force(SIGBUS);
+        }
+    }
+    match kind {
+        misaligned::Kind::Load => process.misaligned_loads += 1,
+        misaligned::Kind::Store => process.misaligned_stores += 1,
+    }
+}
+
+/// Resume the process whose registers are in `frame`, whose page table must be in satp, in a
+/// signal handler if it has a signal to take
+pub fn return_to_user(frame: &mut TrapFrame) -> ! {
+    signal::deliver(frame);
     unsafe {
-        debug!("Syscall {}", proc!().frame.assume_init_ref().regs[17]);
+        // stvec points to user_vec from here on, so nothing may trap
+        csr_clear_bits!(sstatus, SSTATUS_SIE);
+        fp::restore_user(&mut frame.fp);
+        vector::restore_user(&mut frame.vector);
+
+        // the next trap from user mode starts over at the top of this hart's stack
+        let hartid = reg_read!(tp);
+        frame.kernel_sp = hart_stack(hartid).end;
+        frame.kernel_hartid = hartid;
+        frame.kernel_satp = csr_read!(satp);
+        cpu!().kernel_frame = core::ptr::null_mut();
+        cpustat::switch(Mode::User);
+
+        // sret to user mode with interrupts enabled
+        csr_clear_bits!(sstatus, SSTATUS_SPP);
+        csr_set_bits!(sstatus, SSTATUS_SPIE);
+        user_ret(frame)
     }
 }
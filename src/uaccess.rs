@@ -0,0 +1,173 @@
+/// Copying data across the user/kernel boundary
+///
+/// Every user page touched is first checked against the process' page table: it must be
+/// mapped, accessible from user mode, and readable or writeable as appropriate. A bad pointer
+/// from user space therefore results in an error rather than a kernel page fault.
+///
+/// Once validated, data is copied one of two ways. If the process' page table is the one
+/// currently in satp, user memory is accessed directly with sstatus.SUM set. Otherwise each
+/// user page is translated and accessed through the direct map.
+use crate::{
+    csr::{SATP_PPN, SSTATUS_SUM},
+    csr_clear_bits, csr_read, csr_read_field, csr_set_bits, csr_write,
+    kmem::{phys_to_virt, virt_to_phys, PAGE_SIZE},
+    mmu::{PageTable, PTE_R, PTE_USER, PTE_VALID, PTE_W},
+    page_floor, page_number,
+};
+
+/// End of the lower half of the Sv39 address space, which is all that user space can map
+pub const USER_END: u64 = 1 << 38;
+
+/// A user address that the kernel was asked to access is not mapped with the required
+/// permissions
+#[derive(Debug)]
+pub struct Fault {
+    pub vaddr: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy)]
+enum Method {
+    Sum,      // access user addresses directly with sstatus.SUM set
+    PageWalk, // translate user addresses and access them through the direct map
+}
+
+/// Copy `dst.len()` bytes starting at user address `src` into `dst`
+pub fn copy_from_user(pt: &PageTable, dst: &mut [u8], src: u64) -> Result<(), Fault> {
+    validate(pt, src, dst.len() as u64, Access::Read)?;
+    let method = method_for(pt);
+    for_each_chunk(
+        pt,
+        src,
+        dst.len() as u64,
+        method,
+        |ptr, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(ptr, dst.as_mut_ptr().add(offset), len);
+        },
+    );
+    Ok(())
+}
+
+/// Copy all of `src` to user memory starting at `dst`
+pub fn copy_to_user(pt: &PageTable, dst: u64, src: &[u8]) -> Result<(), Fault> {
+    validate(pt, dst, src.len() as u64, Access::Write)?;
+    let method = method_for(pt);
+    for_each_chunk(
+        pt,
+        dst,
+        src.len() as u64,
+        method,
+        |ptr, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(src.as_ptr().add(offset), ptr, len);
+        },
+    );
+    Ok(())
+}
+
+/// Copy a NUL-terminated string starting at user address `src` into `dst`, including the NUL.
+///
+/// Returns the length of the string, not including the NUL. If `dst` fills up before a NUL
+/// is found, `dst.len()` is returned and `dst` is not NUL-terminated.
+pub fn strncpy_from_user(pt: &PageTable, dst: &mut [u8], src: u64) -> Result<usize, Fault> {
+    let method = method_for(pt);
+    let mut copied = 0;
+    while copied < dst.len() {
+        // the length isn't known up front, so validate one page at a time
+        let vaddr = src.checked_add(copied as u64).ok_or(Fault { vaddr: src })?;
+        validate(pt, vaddr, 1, Access::Read)?;
+        let page_left = (page_floor!(vaddr) + PAGE_SIZE - vaddr) as usize;
+        let len = page_left.min(dst.len() - copied);
+        let mut found_nul = false;
+        for_each_chunk(pt, vaddr, len as u64, method, |ptr, _, len| {
+            for i in 0..len {
+                let byte = unsafe { ptr.add(i).read() };
+                dst[copied] = byte;
+                if byte == 0 {
+                    found_nul = true;
+                    return;
+                }
+                copied += 1;
+            }
+        });
+        if found_nul {
+            return Ok(copied);
+        }
+    }
+    Ok(copied)
+}
+
+/// Check that every page overlapping `[vaddr, vaddr + len)` allows `access` from user mode
+fn validate(pt: &PageTable, vaddr: u64, len: u64, access: Access) -> Result<(), Fault> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = match vaddr.checked_add(len) {
+        Some(end) if end <= USER_END => end,
+        _ => return Err(Fault { vaddr }),
+    };
+    let flags = match access {
+        Access::Read => PTE_VALID | PTE_USER | PTE_R,
+        Access::Write => PTE_VALID | PTE_USER | PTE_W,
+    };
+    let mut page = page_floor!(vaddr);
+    while page < end {
+        match pt.lookup_pte(page) {
+            Some((pte, _)) if pte.has_flags(flags) => {}
+            _ => {
+                return Err(Fault {
+                    vaddr: page.max(vaddr),
+                })
+            }
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Call `f(ptr, offset, len)` for each piece of `[vaddr, vaddr + len)` that lies in a single
+/// page, where `ptr` is a kernel-accessible pointer to user address `vaddr + offset`. The range
+/// must already have been validated.
+fn for_each_chunk(
+    pt: &PageTable,
+    vaddr: u64,
+    len: u64,
+    method: Method,
+    mut f: impl FnMut(*mut u8, usize, usize),
+) {
+    if let Method::Sum = method {
+        unsafe {
+            csr_set_bits!(sstatus, SSTATUS_SUM);
+        }
+    }
+    let mut offset = 0;
+    while offset < len {
+        let addr = vaddr + offset;
+        let chunk = (page_floor!(addr) + PAGE_SIZE - addr).min(len - offset);
+        let ptr = match method {
+            Method::Sum => addr,
+            Method::PageWalk => phys_to_virt(pt.lookup(addr).unwrap()),
+        };
+        f(ptr as *mut u8, offset as usize, chunk as usize);
+        offset += chunk;
+    }
+    if let Method::Sum = method {
+        unsafe {
+            csr_clear_bits!(sstatus, SSTATUS_SUM);
+        }
+    }
+}
+
+/// User memory can only be accessed directly if `pt` is the active page table
+fn method_for(pt: &PageTable) -> Method {
+    let active = unsafe { csr_read_field!(satp, SATP_PPN) };
+    if active == page_number!(virt_to_phys(pt as *const _ as u64)) {
+        Method::Sum
+    } else {
+        Method::PageWalk
+    }
+}
@@ -1,17 +1,24 @@
-use crate::kmem::UART_BASE;
+use crate::gdb;
+use crate::irq;
+use crate::kmem::{phys_to_virt, UART_BASE};
 use crate::mmio::MMIODevice;
 use crate::mmio::MMIORegister;
 use crate::mmio::RPerm;
 use crate::mmio::WPerm;
-use crate::plic;
+use crate::pid::Pid;
+use crate::reg_read;
+use crate::signal::{self, SIGINT};
+use crate::spinlock::Spinlock;
+use crate::syscall;
 use crate::term;
-use crate::trap::UART_IRQ;
 use crate::util::CircularBuffer;
+use crate::wait::WaitQueue;
 
 /// UART routines and driver
 /// Reference: http://byterunner.com/16550.html
 use core::fmt::Error;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 #[macro_export]
 macro_rules! print {
@@ -43,9 +50,10 @@ macro_rules! println_sync {
 }
 
 const BAUD_RATE: usize = 2_400;
+const UART_IRQ: u32 = 0x0a;
 
 // UART registers
-const UART_MMIO: MMIODevice<u8> = MMIODevice::new(UART_BASE);
+const UART_MMIO: MMIODevice<u8> = MMIODevice::new(phys_to_virt(UART_BASE));
 const RHR: MMIORegister<u8, RPerm> = UART_MMIO.reg(0); // receive holding register (for input bytes)
 const THR: MMIORegister<u8, WPerm> = UART_MMIO.reg(0); // transmit holding register (for output bytes)
 const DLL: MMIORegister<u8, WPerm> = UART_MMIO.reg(0); // divisor latch LSB
@@ -66,12 +74,35 @@ const LSR_TX_IDLE: u8 = 1 << 5; // THR can accept another character to send
 // UART transmit queue
 static mut TX_QUEUE: CircularBuffer<u8, 32> = CircularBuffer::new();
 
-pub fn handle_intr() {
+// Input that hasn't been read yet, and the tasks waiting for some. Bytes that arrive while the
+// buffer is full are dropped.
+const RX_BUFFER_SIZE: usize = 256;
+static RX_BUFFER: Spinlock<CircularBuffer<u8, RX_BUFFER_SIZE>> =
+    Spinlock::new(CircularBuffer::new());
+static RX_WAIT: WaitQueue = WaitQueue::new();
+
+/// Pid of the process that last read from the console, which BREAK_KEY interrupts unless gdb
+/// wants it. 0 for none.
+static FOREGROUND: AtomicU32 = AtomicU32::new(0);
+
+pub fn handle_intr(_irq: u32) {
     unsafe {
         // receive as many bytes as possible
+        let mut received = false;
         while LSR.read() & LSR_RX_READY != 0 {
             let byte: u8 = RHR.read();
+            if byte == gdb::BREAK_KEY {
+                break_key();
+                continue;
+            }
             term::handle_byte(byte);
+            // readers see lines ending in \n, like the echo
+            let byte = if byte == 0x0D { 0x0A } else { byte };
+            RX_BUFFER.lock().write(byte);
+            received = true;
+        }
+        if received {
+            RX_WAIT.wake_all();
         }
         // transmit as many bytes as possible
         try_flush();
@@ -106,6 +137,54 @@ impl Write for Uart {
     }
 }
 
+/// Move buffered input into `buf`, returning the number of bytes moved
+fn take_input(rx: &mut CircularBuffer<u8, RX_BUFFER_SIZE>, buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    while n < buf.len() {
+        match rx.read() {
+            Some(byte) => buf[n] = byte,
+            None => break,
+        }
+        n += 1;
+    }
+    n
+}
+
+/// Read at least one byte of input into `buf` (unless it is empty), sleeping until there is
+/// some. Only kernel threads can sleep.
+pub fn read(buf: &mut [u8]) -> usize {
+    loop {
+        let mut rx = RX_BUFFER.lock();
+        let n = take_input(&mut rx, buf);
+        if n > 0 || buf.is_empty() {
+            return n;
+        }
+        RX_WAIT.wait(rx);
+    }
+}
+
+/// Send SIGINT to the foreground process, like Ctrl-C in a terminal, or stop in gdb if it wants
+/// the key (see gdb.rs) or there's no such process
+fn break_key() {
+    let foreground: Pid = FOREGROUND.load(Ordering::Relaxed);
+    if gdb::wants_break_key() || foreground == 0 || !signal::kill(foreground, SIGINT) {
+        gdb::break_in();
+    }
+}
+
+/// Like `read`, from a syscall
+pub fn read_syscall(buf: &mut [u8]) -> syscall::Result {
+    let pid = unsafe { crate::proc!().tgid };
+    FOREGROUND.store(pid, Ordering::Relaxed);
+    let mut rx = RX_BUFFER.lock();
+    let n = take_input(&mut rx, buf);
+    if n == 0 && !buf.is_empty() {
+        return RX_WAIT.wait_syscall(rx);
+    }
+    Ok(n as u64)
+}
+
+/// Poll for a byte of input, bypassing the buffer
 pub fn get() -> Option<u8> {
     unsafe {
         if LSR.read() & LSR_RX_READY == 0 {
@@ -133,6 +212,14 @@ pub fn put_sync(byte: u8) {
     }
 }
 
+/// Turn interrupts for received bytes on or off, such as while gdb owns the input (see gdb.rs)
+pub fn set_rx_interrupts(enabled: bool) {
+    let rx = if enabled { IER_RX_ENABLE } else { 0 };
+    unsafe {
+        IER.write(IER_TX_ENABLE | rx);
+    }
+}
+
 /// Configure MMIO registers to enable UART communication and interrupts, though
 /// QEMU's virtual UART device doesn't necessarily need to be properly set up
 pub fn init() {
@@ -169,8 +256,7 @@ pub fn init() {
         // Enable transmitter empty and receiver ready interrupts
         IER.write(IER_TX_ENABLE | IER_RX_ENABLE);
 
-        plic::set_priority(UART_IRQ, 1);
-        plic::enable(plic::PlicPrivilege::Supervisor, UART_IRQ);
-        plic::set_threshold(plic::PlicPrivilege::Supervisor, 0);
+        // only the boot hart takes UART interrupts
+        irq::register(UART_IRQ, handle_intr, 1, 1 << reg_read!(tp)).unwrap();
     }
 }
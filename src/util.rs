@@ -1,6 +1,9 @@
 use core::mem::MaybeUninit;
 use core::option::Option;
 
+/// Fixed-size FIFO. The indices only ever go up, and are reduced mod N to index the array.
+/// Reading and writing both take `&mut self`, so a buffer shared between harts has to be behind
+/// a lock, like the Spinlock each pipe's buffer is in (see pipe.rs).
 pub struct CircularBuffer<T, const N: usize> {
     arr: [MaybeUninit<T>; N],
     r_index: usize,
@@ -19,14 +22,21 @@ impl<T, const N: usize> CircularBuffer<T, N> {
         self.r_index == self.w_index
     }
     pub fn is_full(&self) -> bool {
-        self.r_index + N == self.w_index
+        self.len() == N
+    }
+    pub fn len(&self) -> usize {
+        self.w_index.wrapping_sub(self.r_index)
+    }
+    /// Room left for writing
+    pub fn free(&self) -> usize {
+        N - self.len()
     }
     pub fn read(&mut self) -> Option<T> {
         if self.is_empty() {
             None
         } else {
             let value = unsafe { self.arr[self.r_index % N].assume_init_read() };
-            self.r_index += 1;
+            self.r_index = self.r_index.wrapping_add(1);
             Some(value)
         }
     }
@@ -35,8 +45,29 @@ impl<T, const N: usize> CircularBuffer<T, N> {
             None
         } else {
             self.arr[self.w_index % N].write(value);
-            self.w_index += 1;
+            self.w_index = self.w_index.wrapping_add(1);
             Some(())
         }
     }
 }
+
+impl<T: Copy, const N: usize> CircularBuffer<T, N> {
+    /// Move as many values as there are, up to `dst.len()`, into `dst`. Returns how many.
+    pub fn read_into(&mut self, dst: &mut [T]) -> usize {
+        let n = dst.len().min(self.len());
+        for value in &mut dst[..n] {
+            *value = unsafe { self.arr[self.r_index % N].assume_init() };
+            self.r_index = self.r_index.wrapping_add(1);
+        }
+        n
+    }
+    /// Write as much of `src` as fits. Returns how many values were written.
+    pub fn write_from(&mut self, src: &[T]) -> usize {
+        let n = src.len().min(self.free());
+        for &value in &src[..n] {
+            self.arr[self.w_index % N].write(value);
+            self.w_index = self.w_index.wrapping_add(1);
+        }
+        n
+    }
+}
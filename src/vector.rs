@@ -0,0 +1,180 @@
+/// Lazy vector (V extension) context switching
+///
+/// Works like fp.rs, with sstatus.VS in place of sstatus.FS. The size of the vector registers
+/// (VLEN) is only known at boot, so a process gets a page to save them in when it first uses
+/// a vector instruction. Processes that never do have VS Off and no save area.
+use core::arch::asm;
+
+use crate::{
+    cpu,
+    cpu::{has_extension, Extension},
+    csr::{SSTATUS_VS, SSTATUS_VS_CLEAN, SSTATUS_VS_DIRTY, SSTATUS_VS_INITIAL, SSTATUS_VS_OFF},
+    csr_read, csr_read_field, csr_write, csr_write_field,
+    kmem::{kalloc, kfree, PAGE_SIZE},
+    reg_read,
+};
+
+extern "C" {
+    fn vector_save(area: *mut u8, vlenb: u64); // defined in vector.s
+    fn vector_restore(area: *const u8, vlenb: u64);
+    fn vector_setvl(vl: u64, vtype: u64);
+}
+
+const NO_HART: u64 = u64::MAX;
+
+/// Size of one vector register in bytes, or 0 if vectors can't be used
+static mut VLENB: u64 = 0;
+
+/// Vector state of a process
+pub struct VectorState {
+    area: *mut u8, // v0-v31, allocated on first use
+    vl: u64,
+    vtype: u64,
+    vstart: u64,
+    vcsr: u64,
+    status: u64, // value of sstatus.VS to return to user mode with
+    hart: u64,   // hart that the registers were last loaded on, or NO_HART
+}
+
+/// Find out the size of the vector registers, if the hart has any
+pub fn init() {
+    if !has_extension(Extension::V) {
+        return;
+    }
+    let vlenb: u64;
+    unsafe {
+        // vlenb can only be read with VS on
+        csr_write_field!(sstatus, SSTATUS_VS, SSTATUS_VS_INITIAL);
+        asm!("csrr {}, 0xc22", out(reg) vlenb);
+        csr_write_field!(sstatus, SSTATUS_VS, SSTATUS_VS_OFF);
+    }
+    if 32 * vlenb > PAGE_SIZE {
+        debug!("VLEN of {} bits is too large, not using vectors", vlenb * 8);
+        return;
+    }
+    debug!("Vector registers are {} bits", vlenb * 8);
+    unsafe {
+        VLENB = vlenb;
+    }
+}
+
+pub fn supported() -> bool {
+    unsafe { VLENB != 0 }
+}
+
+impl VectorState {
+    pub const fn new() -> Self {
+        Self {
+            area: core::ptr::null_mut(),
+            vl: 0,
+            vtype: 0,
+            vstart: 0,
+            vcsr: 0,
+            status: SSTATUS_VS_OFF,
+            hart: NO_HART,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.status != SSTATUS_VS_OFF
+    }
+
+    /// Free the save area when the process exits
+    pub fn free(&mut self) {
+        if !self.area.is_null() {
+            kfree(self.area);
+            self.area = core::ptr::null_mut();
+        }
+        self.status = SSTATUS_VS_OFF;
+    }
+}
+
+/// Save the vector registers of the process that just trapped if it modified them, and turn
+/// vectors off for the kernel
+pub fn save_user(state: &mut VectorState) {
+    if !supported() {
+        return;
+    }
+    unsafe {
+        match csr_read_field!(sstatus, SSTATUS_VS) {
+            SSTATUS_VS_DIRTY => {
+                // read the CSRs first, since vector instructions reset vstart
+                asm!("csrr {}, 0x008", out(reg) state.vstart);
+                asm!("csrr {}, 0x00f", out(reg) state.vcsr);
+                asm!("csrr {}, 0xc20", out(reg) state.vl);
+                asm!("csrr {}, 0xc21", out(reg) state.vtype);
+                vector_save(state.area, VLENB);
+                state.status = SSTATUS_VS_CLEAN;
+            }
+            SSTATUS_VS_OFF => {}
+            vs => state.status = vs,
+        }
+        csr_write_field!(sstatus, SSTATUS_VS, SSTATUS_VS_OFF);
+    }
+}
+
+/// Make sure the hart holds the vector registers of the process about to run, and set
+/// sstatus.VS for it
+pub fn restore_user(state: &mut VectorState) {
+    if !supported() {
+        return;
+    }
+    if !state.enabled() {
+        unsafe {
+            csr_write_field!(sstatus, SSTATUS_VS, SSTATUS_VS_OFF);
+        }
+        return;
+    }
+    unsafe {
+        let hartid = reg_read!(tp);
+        let loaded = cpu!().vector_owner == state as *const VectorState && state.hart == hartid;
+        if !loaded {
+            csr_write_field!(sstatus, SSTATUS_VS, SSTATUS_VS_CLEAN);
+            vector_restore(state.area, VLENB);
+            vector_setvl(state.vl, state.vtype);
+            asm!("csrw 0x00f, {}", in(reg) state.vcsr);
+            asm!("csrw 0x008, {}", in(reg) state.vstart);
+            cpu!().vector_owner = state;
+            state.hart = hartid;
+        }
+        csr_write_field!(sstatus, SSTATUS_VS, state.status);
+    }
+}
+
+/// Handle an illegal instruction exception from user mode, where `inst` is the instruction
+/// (from stval). Returns true if it was caused by the process using vectors for the first
+/// time, in which case it can be retried.
+pub fn handle_first_use(state: &mut VectorState, inst: u64) -> bool {
+    if !supported() || state.enabled() || !is_vector_instruction(inst as u32) {
+        return false;
+    }
+    let area = kalloc();
+    if area.is_null() {
+        return false;
+    }
+    unsafe {
+        area.write_bytes(0, (32 * VLENB) as usize);
+    }
+    *state = VectorState {
+        area,
+        status: SSTATUS_VS_INITIAL,
+        ..VectorState::new()
+    };
+    true
+}
+
+/// Whether `inst` needs sstatus.VS to be on
+fn is_vector_instruction(inst: u32) -> bool {
+    let opcode = inst & 0x7f;
+    let width = (inst >> 12) & 0x7;
+    let csr = inst >> 20;
+    match opcode {
+        0x57 => true, // OP-V, including vset{i}vl{i}
+        // LOAD-FP and STORE-FP are vector loads and stores unless the width is that of a
+        // scalar floating point type
+        0x07 | 0x27 => !matches!(width, 1..=4),
+        // csr instructions (but not ecall, ebreak, etc.) accessing vector CSRs
+        0x73 => width != 0 && width != 4 && matches!(csr, 0x008..=0x00a | 0x00f | 0xc20..=0xc22),
+        _ => false,
+    }
+}
@@ -1,12 +1,19 @@
 use core::{
     mem::{size_of, MaybeUninit},
-    ptr::null_mut,
+    ptr::{addr_of, addr_of_mut, null_mut},
+    sync::atomic::{fence, Ordering},
 };
 
 use crate::{
-    kmem::{kalloc, PAGE_SIZE, VIRTIO_BASES},
+    irq,
+    kmem::{kalloc, kfree, phys_to_virt, virt_to_phys, PAGE_SIZE, VIRTIO_BASES},
+    kthread,
     mmio::MMIODevice,
+    reg_read,
+    spinlock::Spinlock,
     string::memset,
+    sync::Mutex,
+    wait::WaitQueue,
 };
 
 /// Driver for VirtIO over MMIO. Supports block devices.
@@ -54,6 +61,7 @@ enum Device {
 
 struct BlockDevice {
     pub queue: Queue,
+    pub request: *mut BlockRequest, // header and status of the request in flight
 }
 
 #[repr(u32)]
@@ -88,9 +96,30 @@ struct BlockRequest {
     pub status: u8,
 }
 
+#[derive(Debug)]
+pub enum BlockError {
+    NoDevice,
+    Unaligned,  // offset or size isn't a whole number of sectors
+    Failed(u8), // status the device returned
+}
+
 static mut VIRTIO_DEVICES: [MaybeUninit<Device>; 8] =
     unsafe { MaybeUninit::uninit().assume_init() };
 
+// Tasks waiting for a device to use buffers, and the lock they check the used ring under
+const NO_WAITERS: WaitQueue = WaitQueue::new();
+static COMPLETIONS: [WaitQueue; 8] = [NO_WAITERS; 8];
+const UNLOCKED: Spinlock<()> = Spinlock::new(());
+static COMPLETION_LOCKS: [Spinlock<()>; 8] = [UNLOCKED; 8];
+
+// Block devices that were set up, by index
+static mut BLOCK_DEVICES: u8 = 0;
+
+// Only one request is in flight per device, in descriptors 0 to 2
+const NO_REQUEST: Mutex<()> = Mutex::new(());
+static REQUEST_LOCKS: [Mutex<()>; 8] = [NO_REQUEST; 8];
+
+const VIRTIO_IRQ_BASE: u32 = 1; // device n raises IRQ VIRTIO_IRQ_BASE + n on the virt machine
 const VIRTIO_MAGIC: u32 = 0x74_72_69_76;
 const VIRTIO_VERSION: u32 = 2;
 const VIRTIO_QUEUE_LEN: usize = 8; // use a constant queue length for all devices for simplicity
@@ -108,12 +137,22 @@ const VIRTIO_BLK_F_RO: u32 = 1 << 5;
 const VIRTIO_BLK_F_CONFIG_WCE: u32 = 1 << 11;
 const VIRTIO_BLK_F_MQ: u32 = 1 << 12;
 
+// 2.7.5 The Virtqueue Descriptor Table
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2; // the device writes the buffer
+
+// 5.2.6 Device Operation (block device)
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const SECTOR_SIZE: u64 = 512;
+
 pub fn init() {
     assert!(size_of::<Descriptor>() == 16);
     assert!(size_of::<Available>() <= PAGE_SIZE as usize);
     assert!(size_of::<Used>() <= PAGE_SIZE as usize);
     for (i, addr) in VIRTIO_BASES.iter().enumerate() {
-        let mmio = MMIODevice::<u32>::new(addr.clone());
+        let mmio = MMIODevice::<u32>::new(phys_to_virt(*addr));
         let magic: u32;
         let version: u32;
         let device_id: u32;
@@ -129,6 +168,27 @@ pub fn init() {
             }
         }
     }
+    for i in 0..VIRTIO_BASES.len() {
+        if unsafe { BLOCK_DEVICES } & 1 << i != 0 {
+            if let Err(err) = kthread::spawn(probe_block_device, i) {
+                debug!("Couldn't start probing block device {}: {:?}", i, err);
+            }
+        }
+    }
+}
+
+/// Kernel thread that reads the first sector of block device `index`, to check that it works
+fn probe_block_device(index: usize) {
+    let buffer = kalloc();
+    if buffer.is_null() {
+        debug!("No memory to probe block device {}", index);
+        return;
+    }
+    match block_op(index, buffer, SECTOR_SIZE as u32, 0, false) {
+        Ok(()) => debug!("Read sector 0 of block device {}", index),
+        Err(err) => debug!("Couldn't read block device {}: {:?}", index, err),
+    }
+    kfree(buffer);
 }
 
 fn setup_block_device(mmio: MMIODevice<u32>, index: usize) {
@@ -192,36 +252,146 @@ fn setup_block_device(mmio: MMIODevice<u32>, index: usize) {
             avail: kalloc() as *mut Available,
             used: kalloc() as *mut Used,
         };
-        if queue.desc == null_mut() || queue.avail == null_mut() || queue.used == null_mut() {
+        let request = kalloc() as *mut BlockRequest;
+        if queue.desc == null_mut()
+            || queue.avail == null_mut()
+            || queue.used == null_mut()
+            || request.is_null()
+        {
             virtio_fail!("Queue length {} not supported", VIRTIO_QUEUE_LEN);
         }
         memset(queue.desc, 0, PAGE_SIZE as usize);
         memset(queue.avail, 0, PAGE_SIZE as usize);
         memset(queue.used, 0, PAGE_SIZE as usize);
 
-        queue_desc_l_reg.write((queue.desc as u64 & 0xFFFF_FFFF) as u32);
-        queue_desc_h_reg.write((queue.desc as u64 >> 32) as u32);
-        queue_driver_l_reg.write((queue.avail as u64 & 0xFFFF_FFFF) as u32);
-        queue_driver_h_reg.write((queue.avail as u64 >> 32) as u32);
-        queue_device_l_reg.write((queue.used as u64 & 0xFFFF_FFFF) as u32);
-        queue_device_h_reg.write((queue.used as u64 >> 32) as u32);
+        // the device needs physical addresses
+        let desc = virt_to_phys(queue.desc as u64);
+        let avail = virt_to_phys(queue.avail as u64);
+        let used = virt_to_phys(queue.used as u64);
+        queue_desc_l_reg.write((desc & 0xFFFF_FFFF) as u32);
+        queue_desc_h_reg.write((desc >> 32) as u32);
+        queue_driver_l_reg.write((avail & 0xFFFF_FFFF) as u32);
+        queue_driver_h_reg.write((avail >> 32) as u32);
+        queue_device_l_reg.write((used & 0xFFFF_FFFF) as u32);
+        queue_device_h_reg.write((used >> 32) as u32);
 
-        let device = BlockDevice { queue };
+        let device = BlockDevice { queue, request };
         VIRTIO_DEVICES[index].write(Device::Block(device));
 
         queue_num_reg.write(VIRTIO_QUEUE_LEN as u32);
 
         queue_ready_reg.write(0x1);
 
+        irq::register(
+            VIRTIO_IRQ_BASE + index as u32,
+            handle_intr,
+            1,
+            1 << reg_read!(tp),
+        )
+        .unwrap();
+
         status |= STATUS_DRIVER_OK;
         status_reg.write(status);
+        BLOCK_DEVICES |= 1 << index;
     }
 }
 
-pub fn block_op(index: usize, buffer: *mut u8, size: u32, offset: u64, write: bool) {
-    unsafe {
-        let dev = VIRTIO_DEVICES[index].assume_init_mut();
-        let sector = offset / 512;
-        todo!();
+/// Acknowledge the device's interrupt and wake everyone waiting for it to use buffers
+fn handle_intr(irq: u32) {
+    let index = (irq - VIRTIO_IRQ_BASE) as usize;
+    let mmio = MMIODevice::<u32>::new(phys_to_virt(VIRTIO_BASES[index]));
+    {
+        let _guard = COMPLETION_LOCKS[index].lock();
+        unsafe {
+            let status = mmio.reg_r(0x060).read(); // InterruptStatus
+            mmio.reg_w(0x064).write(status); // InterruptACK
+        }
+    }
+    COMPLETIONS[index].wake_all();
+}
+
+/// Sleep until the used ring index of device `index` has moved past `seen`, and return it.
+/// Only kernel threads can sleep.
+fn wait_for_used(index: usize, seen: u16) -> u16 {
+    loop {
+        let guard = COMPLETION_LOCKS[index].lock();
+        let Device::Block(dev) = unsafe { VIRTIO_DEVICES[index].assume_init_ref() };
+        let used = unsafe { core::ptr::addr_of!((*dev.queue.used).idx).read_volatile() };
+        if used != seen {
+            return used;
+        }
+        COMPLETIONS[index].wait(guard);
+    }
+}
+
+/// Read `size` bytes at byte `offset` of block device `index` into `buffer`, or write them
+/// from it if `write` is set, sleeping until the device is done. `buffer` must be in the direct
+/// map, like memory from kalloc. Only kernel threads can sleep, so only they can call this.
+pub fn block_op(
+    index: usize,
+    buffer: *mut u8,
+    size: u32,
+    offset: u64,
+    write: bool,
+) -> Result<(), BlockError> {
+    if index >= VIRTIO_BASES.len() || unsafe { BLOCK_DEVICES } & 1 << index == 0 {
+        return Err(BlockError::NoDevice);
+    }
+    if offset % SECTOR_SIZE != 0 || size as u64 % SECTOR_SIZE != 0 {
+        return Err(BlockError::Unaligned);
+    }
+    let _guard = REQUEST_LOCKS[index].lock();
+    let Device::Block(dev) = unsafe { VIRTIO_DEVICES[index].assume_init_mut() };
+    let queue = &dev.queue;
+    let mmio = MMIODevice::<u32>::new(phys_to_virt(VIRTIO_BASES[index]));
+    let seen = unsafe {
+        let request = &mut *dev.request;
+        request.typ = if write {
+            VIRTIO_BLK_T_OUT
+        } else {
+            VIRTIO_BLK_T_IN
+        };
+        request.reserved = 0;
+        request.sector = offset / SECTOR_SIZE;
+        request.data = buffer;
+        request.status = 0xff;
+
+        // 5.2.6: the header, the data and the status byte, chained
+        let desc = core::slice::from_raw_parts_mut(queue.desc, 3);
+        desc[0] = Descriptor {
+            addr: virt_to_phys(dev.request as u64),
+            len: 16, // typ, reserved and sector
+            flags: VIRTQ_DESC_F_NEXT,
+            next: 1,
+        };
+        desc[1] = Descriptor {
+            addr: virt_to_phys(buffer as u64),
+            len: size,
+            flags: VIRTQ_DESC_F_NEXT | if write { 0 } else { VIRTQ_DESC_F_WRITE },
+            next: 2,
+        };
+        desc[2] = Descriptor {
+            addr: virt_to_phys(addr_of!(request.status) as u64),
+            len: 1,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+
+        // nothing else is in flight, so the used index stays put until the device is done
+        let seen = addr_of!((*queue.used).idx).read_volatile();
+        let avail = &mut *queue.avail;
+        let idx = addr_of!(avail.idx).read_volatile();
+        avail.ring[idx as usize % queue.num] = 0;
+        fence(Ordering::SeqCst); // the device must see the descriptors before the index
+        addr_of_mut!(avail.idx).write_volatile(idx.wrapping_add(1));
+        fence(Ordering::SeqCst);
+        mmio.reg_w(0x050).write(0); // QueueNotify
+        seen
+    };
+    wait_for_used(index, seen);
+    fence(Ordering::SeqCst);
+    match unsafe { addr_of!((*dev.request).status).read_volatile() } {
+        VIRTIO_BLK_S_OK => Ok(()),
+        status => Err(BlockError::Failed(status)),
     }
 }
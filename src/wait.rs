@@ -0,0 +1,146 @@
+/// Wait queues
+///
+/// A task that has to wait for something, such as input or an I/O completion, checks for it
+/// under a spinlock and, if it has to wait, puts itself on a WaitQueue with `wait`. That
+/// releases the spinlock only once the task is on the queue and asleep, so a wakeup sent by
+/// whoever next takes the spinlock can't be lost.
+///
+/// Kernel threads sleep in `wait` and return from it once woken. Processes have no kernel stack
+/// to sleep on, so a syscall waits with `wait_syscall` instead, which makes the syscall start
+/// over from the ecall once the process is woken. Either way, the condition has to be checked
+/// again afterwards.
+///
+/// Waiters are woken highest priority first, and in the order they started waiting among
+/// equals. A waiter's priority is taken when it starts waiting.
+use crate::{
+    cpu,
+    kthread::{self, MAX_THREADS},
+    proc::MAX_PROCS,
+    sched::{self, Task},
+    spinlock::{Spinlock, SpinlockGuard},
+    syscall::{self, ERESTART},
+};
+
+/// Every task can be waiting on a queue at most once
+const MAX_WAITERS: usize = MAX_PROCS + MAX_THREADS;
+
+#[derive(Clone, Copy)]
+struct Waiter {
+    task: Task,
+    priority: u8,
+}
+
+/// Waiters in the order they started waiting
+struct Waiters {
+    entries: [Waiter; MAX_WAITERS],
+    len: usize,
+}
+
+pub struct WaitQueue {
+    waiters: Spinlock<Waiters>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        let empty = Waiter {
+            task: Task::Process(0),
+            priority: 0,
+        };
+        Self {
+            waiters: Spinlock::new(Waiters {
+                entries: [empty; MAX_WAITERS],
+                len: 0,
+            }),
+        }
+    }
+
+    /// Called with RUN_LOCK held by the task going to sleep, so `priority` has to be read
+    /// before then
+    fn add(&self, task: Task, priority: u8) {
+        sched::set_wait_queue(task, self.addr());
+        let mut waiters = self.waiters.lock();
+        let len = waiters.len;
+        // a task woken by something else, such as a signal, may still be on the queue
+        if waiters.entries[..len]
+            .iter()
+            .any(|waiter| waiter.task == task)
+        {
+            return;
+        }
+        assert!(len < MAX_WAITERS, "wait queue is full");
+        waiters.entries[len] = Waiter { task, priority };
+        waiters.len += 1;
+    }
+
+    /// Remove the waiter to wake next
+    fn take(&self) -> Option<Task> {
+        let mut waiters = self.waiters.lock();
+        let len = waiters.len;
+        let waiting = &mut waiters.entries[..len];
+        // max_by_key picks the last of equals, so search from the back to get the oldest
+        let (index, _) = waiting
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, waiter)| waiter.priority)?;
+        let task = waiting[index].task;
+        waiting.copy_within(index + 1.., index);
+        waiters.len -= 1;
+        Some(task)
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().len == 0
+    }
+
+    /// Sleep until woken, releasing `guard` once on the queue. Panics outside of a kernel
+    /// thread.
+    pub fn wait<T>(&self, guard: SpinlockGuard<'_, T>) {
+        let thread = kthread::current().expect("wait outside of a kernel thread");
+        let task = Task::Thread(thread.id);
+        let priority = sched::priority(task);
+        kthread::sleep(|| {
+            self.add(task, priority);
+            guard.unlock_interrupts_off()
+        });
+    }
+
+    /// Put the process making a syscall to sleep, releasing `guard` once on the queue. Returns
+    /// the error that the syscall handler has to return for the syscall to be made again once
+    /// the process is woken.
+    pub fn wait_syscall<T>(&self, guard: SpinlockGuard<'_, T>) -> syscall::Result {
+        let task = Task::Process(unsafe { cpu!().current_proc });
+        let priority = sched::priority(task);
+        sched::sleep_process(|| {
+            self.add(task, priority);
+            guard.unlock_interrupts_off()
+        });
+        Err(ERESTART)
+    }
+
+    /// Wake the task with the highest priority that has been waiting the longest. Returns false
+    /// if there was none. Waiters that were already woken by something else, which may be
+    /// asleep on another queue by now, are skipped.
+    pub fn wake_one(&self) -> bool {
+        while let Some(task) = self.take() {
+            if sched::wake_from(task, self.addr()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Wake every waiting task
+    pub fn wake_all(&self) {
+        // tasks that wait again straight away can't keep this going forever
+        for _ in 0..MAX_WAITERS {
+            if !self.wake_one() {
+                break;
+            }
+        }
+    }
+}